@@ -0,0 +1,78 @@
+use ::chrono::{SecondsFormat, Utc};
+use ::serde_json::{json, Value};
+use ::sha2::{Digest, Sha256};
+
+/// Which of the data paths were present, and so decoded, on a record
+#[derive(Clone,Copy,Debug,Default,PartialEq)]
+pub(crate) struct Decoders {
+    pub(crate) binary: bool,
+    pub(crate) text: bool,
+}
+
+/// Provenance of an output record, so it can be traced back to its input
+#[derive(Debug)]
+pub(crate) struct Annotate {
+    source: String,
+}
+
+impl Annotate {
+    pub(crate) fn new(source: &str) -> Annotate {
+        Annotate { source: source.to_owned() }
+    }
+
+    /// The `_meta` object for a raw input line
+    pub(crate) fn meta(&self, line_num: usize, offset: u64, raw: &str, decoders: Decoders) -> Value {
+        let decoders: Vec<&str> = [(decoders.binary, "binary"), (decoders.text, "text")].iter()
+            .filter(|(ran, _)| *ran)
+            .map(|(_, name)| *name)
+            .collect();
+        json!({
+            "line": line_num,
+            "source": self.source,
+            "offset": offset,
+            "processed_at": Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            "sha256": hex(&Sha256::digest(raw.as_bytes())),
+            "decoders": decoders,
+        })
+    }
+
+    /// Adds `_meta` to object records; anything else is left alone
+    pub(crate) fn annotate(&self, json: &mut Value, meta: &Value) {
+        if let Value::Object(ref mut map) = json {
+            let _ = map.insert("_meta".to_owned(), meta.clone());
+        }
+    }
+}
+
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_meta() {
+        let annotate = Annotate::new("segment-3");
+        let meta = annotate.meta(7, 1024, "{}", Decoders { binary: true, text: false });
+        assert_eq!(meta["line"], json!(7));
+        assert_eq!(meta["source"], json!("segment-3"));
+        assert_eq!(meta["offset"], json!(1024));
+        assert_eq!(meta["sha256"], json!("44136fa355b3678a1146ad16f7e8649e94fb4fc21fe77e8310c060f61caaff8a"));
+        assert_eq!(meta["decoders"], json!(["binary"]));
+        assert!(meta["processed_at"].as_str().is_some_and(|s| s.ends_with('Z')));
+    }
+
+    #[test]
+    fn test_annotate() {
+        let annotate = Annotate::new("stdin");
+        let meta = json!({"line": 1});
+        let mut record = json!({"a": 1});
+        annotate.annotate(&mut record, &meta);
+        assert_eq!(record, json!({"a": 1, "_meta": {"line": 1}}));
+        let mut record = json!([1]);
+        annotate.annotate(&mut record, &meta);
+        assert_eq!(record, json!([1]));
+    }
+}
@@ -0,0 +1,1107 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::SystemTime;
+
+use ::base64;
+use ::chrono::NaiveDate;
+use ::error_chain::bail;
+use ::flate2::write::DeflateEncoder;
+use ::serde_json::{self, json, Map, Value};
+use ::sha2::{Digest, Sha256};
+
+use crate::errors::*;
+use crate::json_queries::type_name;
+use crate::output::{RecordWriter, Rejected, Source};
+use crate::timestamps::parse_timestamp_str;
+
+const MAGIC: &[u8] = b"Obj\x01";
+
+/// Encoded records are written as a block once they reach this size
+const BLOCK_BYTES: usize = 1 << 20;
+
+/// DynamoDB attribute value types, as in {"N": "42"}
+const DYNAMODB_TYPES: &[&str] = &["S", "N", "B", "BOOL", "NULL", "M", "L", "SS", "NS", "BS"];
+
+/// Compression of the data blocks
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub(crate) enum AvroCodec {
+    Null,
+    Deflate,
+}
+
+impl FromStr for AvroCodec {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "null" => Ok(AvroCodec::Null),
+            "deflate" => Ok(AvroCodec::Deflate),
+            _ => Err(format!("unknown avro codec '{}', expected null or deflate", s))
+        }
+    }
+}
+
+/// The types values are coerced to
+#[derive(Clone,Debug,PartialEq)]
+enum Schema {
+    Null,
+    Boolean,
+    Int,
+    Date,
+    Long,
+    /// Timestamps, with their units per second
+    Timestamp(i64),
+    Float,
+    Double,
+    Bytes,
+    String,
+    Decimal { precision: u32, scale: u32, size: Option<usize> },
+    Fixed(usize),
+    Enum(Vec<String>),
+    Array(Box<Schema>),
+    Map(Box<Schema>),
+    Union(Vec<Schema>),
+    Record(Vec<Field>),
+}
+
+#[derive(Clone,Debug,PartialEq)]
+struct Field {
+    name: String,
+    /// Key of the field on the records, when it isn't a valid avro name
+    key: String,
+    schema: Schema,
+    default: Option<Value>,
+}
+
+/// Reads an avro schema (.avsc) file
+pub(crate) fn read_avro_schema(path: &Path) -> Result<Value> {
+    Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+}
+
+/// Parses schemas, keeping track of named types so they can be referenced
+#[derive(Debug, Default)]
+struct Parser {
+    named: HashMap<String, Schema>,
+}
+
+impl Parser {
+    fn parse(&mut self, json: &Value, namespace: Option<&str>) -> Result<Schema> {
+        match json {
+            Value::String(name) => match primitive(name) {
+                Some(schema) => Ok(schema),
+                None => self.lookup(name, namespace),
+            },
+            Value::Array(branches) => Ok(Schema::Union(branches.iter()
+                .map(|branch| self.parse(branch, namespace))
+                .collect::<Result<Vec<Schema>>>()?)),
+            Value::Object(map) => self.parse_complex(map, namespace),
+            other => bail!(ErrorKind::InvalidSchema(format!("type can't be {}", type_name(other)))),
+        }
+    }
+
+    fn parse_complex(&mut self, map: &Map<String, Value>, namespace: Option<&str>) -> Result<Schema> {
+        let kind = match map.get("type") {
+            Some(Value::String(kind)) => kind.as_str(),
+            Some(other) => return self.parse(other, namespace),
+            None => bail!(ErrorKind::InvalidSchema("type is missing".to_owned())),
+        };
+        let logical = map.get("logicalType").and_then(Value::as_str);
+        let schema = match (kind, logical) {
+            ("record", _) | ("error", _) => {
+                let (full_name, namespace) = full_name(map, namespace)?;
+                let fields = match map.get("fields") {
+                    Some(Value::Array(fields)) => fields.iter()
+                        .map(|field| self.parse_field(field, namespace.as_deref()))
+                        .collect::<Result<Vec<Field>>>()?,
+                    _ => bail!(ErrorKind::InvalidSchema(format!("record {} has no fields", full_name))),
+                };
+                let mut names = HashSet::new();
+                if let Some(field) = fields.iter().find(|field| !names.insert(&field.name)) {
+                    bail!(ErrorKind::InvalidSchema(format!("record {} has field {} twice", full_name, field.name)));
+                }
+                let record = Schema::Record(fields);
+                let _ = self.named.insert(full_name, record.clone());
+                record
+            },
+            ("enum", _) => {
+                let (full_name, _) = full_name(map, namespace)?;
+                let symbols = match map.get("symbols").and_then(Value::as_array) {
+                    Some(symbols) => symbols.iter().filter_map(Value::as_str).map(str::to_owned).collect(),
+                    None => bail!(ErrorKind::InvalidSchema(format!("enum {} has no symbols", full_name))),
+                };
+                let enumeration = Schema::Enum(symbols);
+                let _ = self.named.insert(full_name, enumeration.clone());
+                enumeration
+            },
+            ("fixed", logical) => {
+                let (full_name, _) = full_name(map, namespace)?;
+                let size = match map.get("size").and_then(Value::as_u64) {
+                    Some(size) => size as usize,
+                    None => bail!(ErrorKind::InvalidSchema(format!("fixed {} has no size", full_name))),
+                };
+                let fixed = match logical {
+                    Some("decimal") => decimal(map, Some(size))?,
+                    _ => Schema::Fixed(size),
+                };
+                let _ = self.named.insert(full_name, fixed.clone());
+                fixed
+            },
+            ("array", _) => match map.get("items") {
+                Some(items) => Schema::Array(Box::new(self.parse(items, namespace)?)),
+                None => bail!(ErrorKind::InvalidSchema("array has no items".to_owned())),
+            },
+            ("map", _) => match map.get("values") {
+                Some(values) => Schema::Map(Box::new(self.parse(values, namespace)?)),
+                None => bail!(ErrorKind::InvalidSchema("map has no values".to_owned())),
+            },
+            ("bytes", Some("decimal")) => decimal(map, None)?,
+            ("int", Some("date")) => Schema::Date,
+            ("long", Some("timestamp-millis")) | ("long", Some("local-timestamp-millis")) => Schema::Timestamp(1_000),
+            ("long", Some("timestamp-micros")) | ("long", Some("local-timestamp-micros")) => Schema::Timestamp(1_000_000),
+            // Unknown logical types fall back to their underlying type
+            (name, _) => match primitive(name) {
+                Some(schema) => schema,
+                None => self.lookup(name, namespace)?,
+            },
+        };
+        Ok(schema)
+    }
+
+    fn parse_field(&mut self, json: &Value, namespace: Option<&str>) -> Result<Field> {
+        let name = match json.get("name").and_then(Value::as_str) {
+            Some(name) => name.to_owned(),
+            None => bail!(ErrorKind::InvalidSchema("field has no name".to_owned())),
+        };
+        let schema = match json.get("type") {
+            Some(schema) => self.parse(schema, namespace)?,
+            None => bail!(ErrorKind::InvalidSchema(format!("field {} has no type", name))),
+        };
+        let key = json.get("json_key").and_then(Value::as_str).unwrap_or(&name).to_owned();
+        Ok(Field { name, key, schema, default: json.get("default").cloned() })
+    }
+
+    fn lookup(&self, name: &str, namespace: Option<&str>) -> Result<Schema> {
+        let qualified = namespace.map(|namespace| format!("{}.{}", namespace, name));
+        qualified.and_then(|qualified| self.named.get(&qualified))
+            .or_else(|| self.named.get(name))
+            .cloned()
+            .ok_or_else(|| ErrorKind::InvalidSchema(format!("unknown type {}", name)).into())
+    }
+}
+
+fn primitive(name: &str) -> Option<Schema> {
+    match name {
+        "null" => Some(Schema::Null),
+        "boolean" => Some(Schema::Boolean),
+        "int" => Some(Schema::Int),
+        "long" => Some(Schema::Long),
+        "float" => Some(Schema::Float),
+        "double" => Some(Schema::Double),
+        "bytes" => Some(Schema::Bytes),
+        "string" => Some(Schema::String),
+        _ => None,
+    }
+}
+
+/// Full name of a named type, and the namespace of the types inside it
+fn full_name(map: &Map<String, Value>, namespace: Option<&str>) -> Result<(String, Option<String>)> {
+    let name = match map.get("name").and_then(Value::as_str) {
+        Some(name) => name,
+        None => bail!(ErrorKind::InvalidSchema("named type has no name".to_owned())),
+    };
+    if let Some(dot) = name.rfind('.') {
+        return Ok((name.to_owned(), Some(name[..dot].to_owned())));
+    }
+    let namespace = map.get("namespace").and_then(Value::as_str).or(namespace).filter(|n| !n.is_empty());
+    match namespace {
+        Some(namespace) => Ok((format!("{}.{}", namespace, name), Some(namespace.to_owned()))),
+        None => Ok((name.to_owned(), None)),
+    }
+}
+
+fn decimal(map: &Map<String, Value>, size: Option<usize>) -> Result<Schema> {
+    let precision = map.get("precision").and_then(Value::as_u64).unwrap_or(0) as u32;
+    let scale = map.get("scale").and_then(Value::as_u64).unwrap_or(0) as u32;
+    if precision == 0 || precision > 38 || scale > precision {
+        bail!(ErrorKind::InvalidSchema(format!("decimal precision {} and scale {} not supported", precision, scale)));
+    }
+    Ok(Schema::Decimal { precision, scale, size })
+}
+
+impl Schema {
+    fn describe(&self) -> String {
+        match self {
+            Schema::Null => "null".to_owned(),
+            Schema::Boolean => "boolean".to_owned(),
+            Schema::Int => "int".to_owned(),
+            Schema::Date => "date".to_owned(),
+            Schema::Long => "long".to_owned(),
+            Schema::Timestamp(_) => "timestamp".to_owned(),
+            Schema::Float => "float".to_owned(),
+            Schema::Double => "double".to_owned(),
+            Schema::Bytes => "bytes".to_owned(),
+            Schema::String => "string".to_owned(),
+            Schema::Decimal { precision, scale, .. } => format!("decimal({}, {})", precision, scale),
+            Schema::Fixed(size) => format!("fixed({})", size),
+            Schema::Enum(symbols) => format!("one of the symbols {}", symbols.join(", ")),
+            Schema::Array(_) => "array".to_owned(),
+            Schema::Map(_) => "map".to_owned(),
+            Schema::Union(branches) => {
+                let names: Vec<String> = branches.iter().map(Schema::describe).collect();
+                format!("one of {}", names.join(", "))
+            },
+            Schema::Record(_) => "record".to_owned(),
+        }
+    }
+
+    /// Appends the binary encoding of a value, coercing it to this schema
+    fn encode(&self, value: &Value, path: &str, out: &mut Vec<u8>) -> Result<()> {
+        let (value, dynamodb_type) = match self {
+            // Each branch unwraps the value as its own type needs
+            Schema::Union(_) => unwrap_dynamodb(value, &["NULL"]),
+            Schema::Record(_) | Schema::Map(_) => unwrap_dynamodb(value, &["M"]),
+            _ => unwrap_dynamodb(value, DYNAMODB_TYPES),
+        };
+        let mismatch = || -> Error {
+            let path = if path.is_empty() { "." } else { path };
+            ErrorKind::SchemaMismatch(format!("field {} is {}, expected {}", path, type_name(value), self.describe())).into()
+        };
+        match self {
+            Schema::Null if value.is_null() => (),
+            Schema::Boolean => match value {
+                Value::Bool(b) => out.push(*b as u8),
+                _ => return Err(mismatch()),
+            },
+            Schema::Int => match integer(value) {
+                Some(n) if n >= i64::from(i32::MIN) && n <= i64::from(i32::MAX) => write_long(out, n),
+                _ => return Err(mismatch()),
+            },
+            Schema::Long => write_long(out, integer(value).ok_or_else(mismatch)?),
+            Schema::Date => {
+                let epoch = NaiveDate::from_ymd_opt(1970, 1, 1).unwrap_or_default();
+                let days = match value {
+                    Value::String(s) => NaiveDate::parse_from_str(s.trim(), "%Y-%m-%d").ok()
+                        .map(|date| (date - epoch).num_days()),
+                    _ => integer(value),
+                };
+                write_long(out, days.ok_or_else(mismatch)?);
+            },
+            Schema::Timestamp(units) => {
+                let timestamp = match value {
+                    Value::String(s) if s.parse::<i64>().is_err() => parse_timestamp_str(s.trim())
+                        .and_then(|timestamp| match units {
+                            1_000 => Some(timestamp.timestamp_millis()),
+                            _ => timestamp.timestamp_nanos_opt().map(|nanos| nanos / 1_000),
+                        }),
+                    _ => integer(value),
+                };
+                write_long(out, timestamp.ok_or_else(mismatch)?);
+            },
+            Schema::Float => out.extend_from_slice(&(number(value).ok_or_else(mismatch)? as f32).to_le_bytes()),
+            Schema::Double => out.extend_from_slice(&number(value).ok_or_else(mismatch)?.to_le_bytes()),
+            Schema::String => match value {
+                Value::String(s) => write_bytes(out, s.as_bytes()),
+                _ => return Err(mismatch()),
+            },
+            Schema::Bytes => write_bytes(out, &bytes(value, dynamodb_type).ok_or_else(mismatch)?),
+            Schema::Fixed(size) => match bytes(value, dynamodb_type) {
+                Some(ref bytes) if bytes.len() == *size => out.extend_from_slice(bytes),
+                _ => return Err(mismatch()),
+            },
+            Schema::Decimal { precision, scale, size } => {
+                let unscaled = decimal_text(value)
+                    .and_then(|text| parse_decimal(&text, *scale))
+                    .filter(|unscaled| unscaled.unsigned_abs() < 10u128.pow(*precision))
+                    .ok_or_else(mismatch)?;
+                let bytes = twos_complement(unscaled, *size).ok_or_else(mismatch)?;
+                match size {
+                    Some(_) => out.extend_from_slice(&bytes),
+                    None => write_bytes(out, &bytes),
+                }
+            },
+            Schema::Enum(symbols) => match value.as_str().and_then(|s| symbols.iter().position(|symbol| symbol == s)) {
+                Some(index) => write_long(out, index as i64),
+                None => return Err(mismatch()),
+            },
+            Schema::Array(items) => match value {
+                Value::Array(elements) => {
+                    if !elements.is_empty() {
+                        write_long(out, elements.len() as i64);
+                        for (index, element) in elements.iter().enumerate() {
+                            items.encode(element, &format!("{}[{}]", path, index), out)?;
+                        }
+                    }
+                    write_long(out, 0);
+                },
+                _ => return Err(mismatch()),
+            },
+            Schema::Map(values) => match value {
+                Value::Object(map) => {
+                    if !map.is_empty() {
+                        write_long(out, map.len() as i64);
+                        for (key, element) in map {
+                            write_bytes(out, key.as_bytes());
+                            values.encode(element, &format!("{}.{}", path, key), out)?;
+                        }
+                    }
+                    write_long(out, 0);
+                },
+                _ => return Err(mismatch()),
+            },
+            Schema::Union(branches) => {
+                let non_null: Vec<(usize, &Schema)> = branches.iter().enumerate()
+                    .filter(|(_, branch)| **branch != Schema::Null)
+                    .collect();
+                match branches.iter().position(|branch| *branch == Schema::Null) {
+                    Some(index) if value.is_null() => {
+                        write_long(out, index as i64);
+                        return Ok(());
+                    },
+                    _ => (),
+                }
+                // With a single choice, its own error is the most precise
+                if let [(index, branch)] = non_null[..] {
+                    let mut encoded = Vec::new();
+                    branch.encode(value, path, &mut encoded)?;
+                    write_long(out, index as i64);
+                    out.extend(encoded);
+                    return Ok(());
+                }
+                let encoded = non_null.iter().find_map(|(index, branch)| {
+                    let mut encoded = Vec::new();
+                    branch.encode(value, path, &mut encoded).ok().map(|_| (index, encoded))
+                });
+                match encoded {
+                    Some((index, encoded)) => {
+                        write_long(out, *index as i64);
+                        out.extend(encoded);
+                    },
+                    None => return Err(mismatch()),
+                }
+            },
+            Schema::Record(fields) => match value {
+                Value::Object(map) => for field in fields {
+                    let path = format!("{}.{}", path, field.key);
+                    match (map.get(&field.key), &field.default) {
+                        (Some(value), _) | (None, Some(value)) => field.schema.encode(value, &path, out)?,
+                        (None, None) => field.schema.encode(&Value::Null, &path, out)
+                            .chain_err(|| ErrorKind::SchemaMismatch(format!("field {} is missing", path)))?,
+                    }
+                },
+                _ => return Err(mismatch()),
+            },
+            Schema::Null => return Err(mismatch()),
+        }
+        Ok(())
+    }
+}
+
+/// The value inside a DynamoDB attribute value of one of the given types
+fn unwrap_dynamodb<'a>(value: &'a Value, types: &[&str]) -> (&'a Value, Option<&'a str>) {
+    match value {
+        Value::Object(map) if map.len() == 1 => match map.iter().next() {
+            Some((kind, _)) if kind == "NULL" && types.contains(&"NULL") => (&Value::Null, Some("NULL")),
+            Some((kind, inner)) if types.contains(&kind.as_str()) => (inner, Some(kind.as_str())),
+            _ => (value, None),
+        },
+        _ => (value, None),
+    }
+}
+
+fn integer(value: &Value) -> Option<i64> {
+    match value {
+        Value::Number(n) => n.as_i64().or_else(|| n.as_f64()
+            .filter(|f| f.fract() == 0.0 && f.abs() < 9.2e18)
+            .map(|f| f as i64)),
+        Value::String(s) => s.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+fn number(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(n) => n.as_f64(),
+        Value::String(s) => s.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+/// DynamoDB binary values are base64, other strings are taken as UTF-8
+fn bytes(value: &Value, dynamodb_type: Option<&str>) -> Option<Vec<u8>> {
+    match (value, dynamodb_type) {
+        (Value::String(s), Some("B")) => base64::decode(s).ok(),
+        (Value::String(s), _) => Some(s.as_bytes().to_vec()),
+        _ => None,
+    }
+}
+
+fn decimal_text(value: &Value) -> Option<String> {
+    match value {
+        Value::Number(n) => Some(n.to_string()),
+        Value::String(s) => Some(s.trim().to_owned()),
+        _ => None,
+    }
+}
+
+/// Parses a decimal number, such as -12.50 or 1.5e3, into its unscaled
+/// value. Numbers with more fractional digits than the scale are rejected
+/// rather than rounded.
+fn parse_decimal(text: &str, scale: u32) -> Option<i128> {
+    let (mantissa, exponent) = match text.find(['e', 'E']) {
+        Some(e) => (&text[..e], text[e + 1..].parse::<i32>().ok()?),
+        None => (text, 0),
+    };
+    let (negative, mantissa) = match mantissa.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, mantissa.strip_prefix('+').unwrap_or(mantissa)),
+    };
+    let (whole, fraction) = match mantissa.find('.') {
+        Some(dot) => (&mantissa[..dot], &mantissa[dot + 1..]),
+        None => (mantissa, ""),
+    };
+    let digits = format!("{}{}", whole, fraction);
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let shift = scale as i32 - fraction.len() as i32 + exponent;
+    let digits = digits.trim_start_matches('0');
+    let unscaled = if shift >= 0 {
+        let value: i128 = if digits.is_empty() { 0 } else { digits.parse().ok()? };
+        value.checked_mul(10i128.checked_pow(shift as u32)?)?
+    } else {
+        let cut = (-shift) as usize;
+        let kept = digits.len().saturating_sub(cut);
+        if digits[kept..].bytes().any(|b| b != b'0') {
+            return None;
+        }
+        if kept == 0 { 0 } else { digits[..kept].parse().ok()? }
+    };
+    Some(if negative { -unscaled } else { unscaled })
+}
+
+/// Big-endian two's complement bytes, as short as possible or sign
+/// extended to a fixed size
+fn twos_complement(value: i128, size: Option<usize>) -> Option<Vec<u8>> {
+    let bytes = value.to_be_bytes();
+    let sign = if value < 0 { 0xff } else { 0 };
+    let mut start = 0;
+    while start < bytes.len() - 1 && bytes[start] == sign && (bytes[start + 1] & 0x80) == (sign & 0x80) {
+        start += 1;
+    }
+    let minimal = &bytes[start..];
+    match size {
+        None => Some(minimal.to_vec()),
+        Some(size) if size >= minimal.len() => {
+            let mut fixed = vec![sign; size - minimal.len()];
+            fixed.extend_from_slice(minimal);
+            Some(fixed)
+        },
+        Some(_) => None,
+    }
+}
+
+/// Zigzag variable length encoding of ints and longs
+fn write_long(out: &mut Vec<u8>, n: i64) {
+    let mut z = ((n << 1) ^ (n >> 63)) as u64;
+    while z > 0x7f {
+        out.push((z & 0x7f) as u8 | 0x80);
+        z >>= 7;
+    }
+    out.push(z as u8);
+}
+
+fn write_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    write_long(out, bytes.len() as i64);
+    out.extend_from_slice(bytes);
+}
+
+/// Types seen on a field across the sampled records
+#[derive(Debug, Default)]
+struct Shape {
+    null: bool,
+    boolean: bool,
+    long: bool,
+    double: bool,
+    string: bool,
+    array: Option<Box<Shape>>,
+    record: Option<Vec<(String, Shape)>>,
+}
+
+impl Shape {
+    fn add(&mut self, value: &Value) {
+        match value {
+            Value::Null => self.null = true,
+            Value::Bool(_) => self.boolean = true,
+            Value::Number(n) if n.is_f64() => self.double = true,
+            Value::Number(_) => self.long = true,
+            Value::String(_) => self.string = true,
+            Value::Array(elements) => {
+                let items = self.array.get_or_insert_with(Box::default);
+                elements.iter().for_each(|element| items.add(element));
+            },
+            Value::Object(map) => {
+                let first = self.record.is_none();
+                let fields = self.record.get_or_insert_with(Vec::new);
+                for (key, field) in fields.iter_mut() {
+                    if !map.contains_key(key) {
+                        field.null = true;
+                    }
+                }
+                for (key, value) in map {
+                    match fields.iter_mut().find(|(name, _)| name == key) {
+                        Some((_, field)) => field.add(value),
+                        None => {
+                            let mut field = Shape { null: !first, ..Shape::default() };
+                            field.add(value);
+                            fields.push((key.clone(), field));
+                        },
+                    }
+                }
+            },
+        }
+    }
+
+    /// The schema as json. Records are named after their path, and fields
+    /// that can be missing or null default to null.
+    fn to_json(&self, name: &str, names: &mut HashSet<String>) -> Value {
+        let mut branches = Vec::new();
+        if self.null {
+            branches.push(json!("null"));
+        }
+        if self.boolean {
+            branches.push(json!("boolean"));
+        }
+        if self.double {
+            branches.push(json!("double"));
+        } else if self.long {
+            branches.push(json!("long"));
+        }
+        if self.string {
+            branches.push(json!("string"));
+        }
+        if let Some(ref items) = self.array {
+            branches.push(json!({"type": "array", "items": items.to_json(&format!("{}_item", name), names)}));
+        }
+        if let Some(ref fields) = self.record {
+            let mut record_name = name.to_owned();
+            let mut suffix = 1;
+            while !names.insert(record_name.clone()) {
+                suffix += 1;
+                record_name = format!("{}{}", name, suffix);
+            }
+            let mut field_names = HashSet::new();
+            let fields: Vec<Value> = fields.iter()
+                .map(|(key, shape)| {
+                    // Keys such as a-b and a_b sanitise to the same name
+                    let mut field_name = avro_name(key);
+                    let mut suffix = 1;
+                    while !field_names.insert(field_name.clone()) {
+                        suffix += 1;
+                        field_name = format!("{}_{}", avro_name(key), suffix);
+                    }
+                    let schema = shape.to_json(&format!("{}_{}", record_name, field_name), names);
+                    let mut field = json!({"name": field_name, "type": schema});
+                    if field_name != *key {
+                        field["json_key"] = json!(key);
+                    }
+                    if shape.null {
+                        field["default"] = Value::Null;
+                    }
+                    field
+                })
+                .collect();
+            branches.push(json!({"type": "record", "name": record_name, "fields": fields}));
+        }
+        match branches.len() {
+            0 => json!(["null", "string"]),
+            1 => branches.remove(0),
+            _ => Value::Array(branches),
+        }
+    }
+}
+
+/// Replaces what can't be in an avro name with underscores
+fn avro_name(key: &str) -> String {
+    let mut name: String = key.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '_' { c } else { '_' })
+        .collect();
+    if !name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+        name.insert(0, '_');
+    }
+    name
+}
+
+/// Infers a schema from sampled records
+fn infer_schema(records: &[&Value]) -> Value {
+    let mut shape = Shape::default();
+    records.iter().for_each(|record| shape.add(record));
+    shape.to_json("export", &mut HashSet::new())
+}
+
+/// Writes records to an avro object container file, with a schema that is
+/// either given or inferred from the first records.
+///
+/// Records that don't fit the schema are non-fatal errors naming the field.
+/// Sampled records are rejected with the line they came from instead.
+#[derive(Debug)]
+pub(crate) struct Avro<W: Write> {
+    output: W,
+    codec: AvroCodec,
+    schema: Option<(Schema, String)>,
+    sample_size: usize,
+    sample: Vec<(Value, Source)>,
+    source: Source,
+    rejected: Vec<Rejected>,
+    started: bool,
+    sync: [u8; 16],
+    block: Vec<u8>,
+    block_records: usize,
+}
+
+impl<W: Write> Avro<W> {
+    /// Without a schema, it's inferred from the first `sample_size` records
+    pub(crate) fn new(output: W, codec: AvroCodec, schema: Option<Value>, sample_size: usize) -> Result<Avro<W>> {
+        let schema = match schema {
+            Some(json) => Some((Parser::default().parse(&json, None)?, json.to_string())),
+            None => None,
+        };
+        Ok(Avro {
+            output,
+            codec,
+            schema,
+            sample_size: sample_size.max(1),
+            sample: Vec::new(),
+            source: Source::default(),
+            rejected: Vec::new(),
+            started: false,
+            sync: [0; 16],
+            block: Vec::new(),
+            block_records: 0,
+        })
+    }
+
+    /// Writes the header and the sampled records, once the schema is known
+    fn start(&mut self) -> Result<()> {
+        self.started = true;
+        let text = match self.schema {
+            Some((_, ref text)) => text.clone(),
+            None => {
+                let records: Vec<&Value> = self.sample.iter().map(|(record, _)| record).collect();
+                let json = infer_schema(&records);
+                let schema = Parser::default().parse(&json, None)?;
+                let text = json.to_string();
+                self.schema = Some((schema, text.clone()));
+                text
+            },
+        };
+        let nanos = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0);
+        let digest = Sha256::new().chain_update(text.as_bytes()).chain_update(nanos.to_be_bytes()).finalize();
+        self.sync.copy_from_slice(&digest[..16]);
+
+        let codec = match self.codec {
+            AvroCodec::Null => "null",
+            AvroCodec::Deflate => "deflate",
+        };
+        let mut header = MAGIC.to_vec();
+        write_long(&mut header, 2);
+        write_bytes(&mut header, b"avro.schema");
+        write_bytes(&mut header, text.as_bytes());
+        write_bytes(&mut header, b"avro.codec");
+        write_bytes(&mut header, codec.as_bytes());
+        write_long(&mut header, 0);
+        header.extend_from_slice(&self.sync);
+        self.output.write_all(&header)?;
+
+        for (record, source) in std::mem::take(&mut self.sample) {
+            match self.append(&record) {
+                Err(error) if !error.is_fatal() => self.rejected.push(Rejected { error, source }),
+                result => result?,
+            }
+        }
+        Ok(())
+    }
+
+    fn append(&mut self, record: &Value) -> Result<()> {
+        let schema = match self.schema {
+            Some((ref schema, _)) => schema,
+            None => return Ok(()),
+        };
+        let mut encoded = Vec::new();
+        schema.encode(record, "", &mut encoded)?;
+        self.block.extend(encoded);
+        self.block_records += 1;
+        if self.block.len() >= BLOCK_BYTES {
+            self.write_block()?;
+        }
+        Ok(())
+    }
+
+    fn write_block(&mut self) -> Result<()> {
+        if self.block_records == 0 {
+            return Ok(());
+        }
+        let data = match self.codec {
+            AvroCodec::Null => std::mem::take(&mut self.block),
+            AvroCodec::Deflate => {
+                let mut encoder = DeflateEncoder::new(Vec::new(), ::flate2::Compression::default());
+                encoder.write_all(&self.block)?;
+                self.block.clear();
+                encoder.finish()?
+            },
+        };
+        let mut prefix = Vec::new();
+        write_long(&mut prefix, self.block_records as i64);
+        write_long(&mut prefix, data.len() as i64);
+        self.output.write_all(&prefix)?;
+        self.output.write_all(&data)?;
+        self.output.write_all(&self.sync)?;
+        self.block_records = 0;
+        Ok(())
+    }
+}
+
+impl<W: Write> RecordWriter for Avro<W> {
+    fn set_source(&mut self, source: &Source) {
+        if !self.started {
+            self.source.clone_from(source);
+        }
+    }
+
+    fn write_record(&mut self, record: &str) -> Result<()> {
+        let record: Value = serde_json::from_str(record)?;
+        if !self.started && self.schema.is_some() {
+            self.start()?;
+        }
+        if self.started {
+            return self.append(&record);
+        }
+        self.sample.push((record, self.source.clone()));
+        if self.sample.len() >= self.sample_size {
+            self.start()?;
+        }
+        Ok(())
+    }
+
+    fn take_rejected(&mut self) -> Vec<Rejected> {
+        std::mem::take(&mut self.rejected)
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        if !self.started {
+            self.start()?;
+        }
+        self.write_block()?;
+        self.output.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::assert_matches::assert_matches;
+    use ::flate2::read::DeflateDecoder;
+    use std::convert::TryInto;
+    use std::io::Read;
+
+    /// Avro binary data, read as the specification lays it out
+    struct Decoder<'a> {
+        data: &'a [u8],
+    }
+
+    impl<'a> Decoder<'a> {
+        fn take(&mut self, n: usize) -> &'a [u8] {
+            let (taken, rest) = self.data.split_at(n);
+            self.data = rest;
+            taken
+        }
+
+        /// Zigzag variable-length integer
+        fn long(&mut self) -> i64 {
+            let (mut n, mut shift) = (0u64, 0);
+            loop {
+                let byte = self.take(1)[0];
+                n |= u64::from(byte & 0x7f) << shift;
+                shift += 7;
+                if byte & 0x80 == 0 {
+                    return (n >> 1) as i64 ^ -((n & 1) as i64);
+                }
+            }
+        }
+
+        fn bytes(&mut self) -> &'a [u8] {
+            let len = self.long() as usize;
+            self.take(len)
+        }
+
+        /// Reads the blocks of an array or map, until the empty one
+        fn blocks(&mut self, mut f: impl FnMut(&mut Self)) {
+            loop {
+                let count = match self.long() {
+                    0 => return,
+                    count if count < 0 => {
+                        let _size = self.long();
+                        -count
+                    },
+                    count => count,
+                };
+                (0..count).for_each(|_| f(self));
+            }
+        }
+
+        /// Decimals come back as their unscaled value, and bytes as text
+        fn value(&mut self, schema: &Schema) -> Value {
+            match schema {
+                Schema::Null => Value::Null,
+                Schema::Boolean => json!(self.take(1)[0] != 0),
+                Schema::Int | Schema::Date | Schema::Long | Schema::Timestamp(_) => json!(self.long()),
+                Schema::Float => json!(f32::from_le_bytes(self.take(4).try_into().unwrap())),
+                Schema::Double => json!(f64::from_le_bytes(self.take(8).try_into().unwrap())),
+                Schema::Bytes | Schema::String => json!(String::from_utf8_lossy(self.bytes())),
+                Schema::Fixed(size) => json!(String::from_utf8_lossy(self.take(*size))),
+                Schema::Decimal { size, .. } => {
+                    let bytes = match size {
+                        Some(size) => self.take(*size),
+                        None => self.bytes(),
+                    };
+                    let sign = if bytes.first().is_some_and(|byte| byte & 0x80 != 0) { -1 } else { 0 };
+                    let unscaled = bytes.iter().fold(sign as i128, |n, byte| n << 8 | i128::from(*byte));
+                    json!(unscaled as i64)
+                },
+                Schema::Enum(symbols) => json!(symbols[self.long() as usize]),
+                Schema::Array(items) => {
+                    let mut elements = Vec::new();
+                    self.blocks(|decoder| elements.push(decoder.value(items)));
+                    Value::Array(elements)
+                },
+                Schema::Map(values) => {
+                    let mut map = Map::new();
+                    self.blocks(|decoder| {
+                        let key = String::from_utf8_lossy(decoder.bytes()).into_owned();
+                        let _ = map.insert(key, decoder.value(values));
+                    });
+                    Value::Object(map)
+                },
+                Schema::Union(branches) => {
+                    let index = self.long() as usize;
+                    self.value(&branches[index])
+                },
+                Schema::Record(fields) => Value::Object(fields.iter()
+                    .map(|field| (field.name.clone(), self.value(&field.schema)))
+                    .collect()),
+            }
+        }
+    }
+
+    /// Reads a container file back into its schema and records, checking
+    /// its framing on the way
+    fn read_back(file: &[u8]) -> (Value, Vec<Value>) {
+        let mut decoder = Decoder { data: file };
+        assert_eq!(decoder.take(4), MAGIC);
+        let mut metadata = HashMap::new();
+        decoder.blocks(|decoder| {
+            let key = String::from_utf8(decoder.bytes().to_vec()).unwrap();
+            let _ = metadata.insert(key, decoder.bytes());
+        });
+        let sync = decoder.take(16);
+        let json: Value = serde_json::from_slice(metadata["avro.schema"]).unwrap();
+        let schema = Parser::default().parse(&json, None).unwrap();
+        let mut records = Vec::new();
+        while !decoder.data.is_empty() {
+            let count = decoder.long();
+            let data = decoder.bytes();
+            let data = match metadata["avro.codec"] {
+                b"null" => data.to_vec(),
+                b"deflate" => {
+                    let mut inflated = Vec::new();
+                    let _ = DeflateDecoder::new(data).read_to_end(&mut inflated).unwrap();
+                    inflated
+                },
+                codec => panic!("unknown codec {:?}", codec),
+            };
+            let mut block = Decoder { data: &data };
+            records.extend((0..count).map(|_| block.value(&schema)));
+            assert!(block.data.is_empty());
+            assert_eq!(decoder.take(16), sync);
+        }
+        (json, records)
+    }
+
+    fn field_names(schema: &Value) -> Vec<&str> {
+        schema["fields"].as_array().unwrap().iter().map(|field| field["name"].as_str().unwrap()).collect()
+    }
+
+    fn encode(schema: Value, value: Value) -> Result<Vec<u8>> {
+        let schema = Parser::default().parse(&schema, None)?;
+        let mut out = Vec::new();
+        schema.encode(&value, "", &mut out)?;
+        Ok(out)
+    }
+
+    #[test]
+    fn test_encode_coercions() {
+        assert_eq!(encode(json!("long"), json!({"N": "-3"})).unwrap(), vec![5]);
+        assert_eq!(encode(json!("long"), json!(64)).unwrap(), vec![0x80, 0x01]);
+        assert_eq!(encode(json!("double"), json!({"N": "1.5"})).unwrap(), 1.5f64.to_le_bytes().to_vec());
+        assert_eq!(encode(json!("string"), json!({"S": "hi"})).unwrap(), vec![4, b'h', b'i']);
+        assert_eq!(encode(json!("bytes"), json!({"B": "aGk="})).unwrap(), vec![4, b'h', b'i']);
+        assert_eq!(encode(json!(["null", "string"]), json!({"NULL": true})).unwrap(), vec![0]);
+        assert_eq!(encode(json!(["null", "boolean"]), json!({"BOOL": true})).unwrap(), vec![2, 1]);
+        assert_eq!(encode(json!({"type": "int", "logicalType": "date"}), json!("1970-01-02")).unwrap(), vec![2]);
+        let timestamp = json!({"type": "long", "logicalType": "timestamp-millis"});
+        assert_eq!(encode(timestamp, json!("1970-01-01T00:00:00.001Z")).unwrap(), vec![2]);
+        let map = json!({"type": "map", "values": "long"});
+        assert_eq!(encode(map, json!({"M": {"a": {"N": "1"}}})).unwrap(), vec![2, 2, b'a', 2, 0]);
+    }
+
+    #[test]
+    fn test_encode_union_branches_unwrap() {
+        let optional = json!(["null", {"type": "record", "name": "n", "fields": [{"name": "N", "type": "string"}]}]);
+        assert_eq!(encode(optional.clone(), json!({"N": "1"})).unwrap(), vec![2, 2, b'1']);
+        assert_eq!(encode(optional, json!({"NULL": true})).unwrap(), vec![0]);
+        assert_eq!(encode(json!(["null", "long"]), json!({"N": "1"})).unwrap(), vec![2, 2]);
+    }
+
+    #[test]
+    fn test_encode_decimal() {
+        let decimal = json!({"type": "bytes", "logicalType": "decimal", "precision": 5, "scale": 2});
+        assert_eq!(encode(decimal.clone(), json!({"N": "1.5"})).unwrap(), vec![4, 0x00, 0x96]);
+        assert_eq!(encode(decimal.clone(), json!(-1)).unwrap(), vec![2, 0x9c]);
+        assert_eq!(encode(decimal.clone(), json!("1.5e1")).unwrap(), vec![4, 0x05, 0xdc]);
+        assert_matches!(encode(decimal.clone(), json!("1.555")), Err(Error(ErrorKind::SchemaMismatch(_), _)));
+        assert_matches!(encode(decimal, json!("1000")), Err(Error(ErrorKind::SchemaMismatch(_), _)));
+        let fixed = json!({"type": "fixed", "name": "d", "size": 4, "logicalType": "decimal", "precision": 5, "scale": 0});
+        assert_eq!(encode(fixed, json!(-2)).unwrap(), vec![0xff, 0xff, 0xff, 0xfe]);
+    }
+
+    #[test]
+    fn test_encode_mismatch_names_field() {
+        let schema = json!({"type": "record", "name": "r", "fields": [
+            {"name": "pk", "type": "string"},
+            {"name": "items", "type": {"type": "array", "items": {"type": "record", "name": "item", "fields": [
+                {"name": "count", "type": "int"},
+            ]}}},
+        ]});
+        let result = encode(schema.clone(), json!({"pk": "a", "items": [{"count": 1}, {"count": "many"}]}));
+        assert_matches!(result, Err(Error(ErrorKind::SchemaMismatch(ref reason), _))
+                        if reason == "field .items[1].count is a string, expected int");
+        assert_matches!(result, Err(ref error) if !error.is_fatal());
+        let result = encode(schema, json!({"items": []}));
+        assert_matches!(result, Err(ref error) if error.to_string().contains("field .pk is missing"));
+    }
+
+    #[test]
+    fn test_infer_schema() {
+        let records = [
+            &json!({"pk": "a", "n": 1, "data": {"tags": ["x"]}}),
+            &json!({"pk": "b", "n": 1.5, "my-key": true}),
+        ];
+        assert_eq!(infer_schema(&records), json!({"type": "record", "name": "export", "fields": [
+            {"name": "pk", "type": "string"},
+            {"name": "n", "type": "double"},
+            {"name": "data", "type": ["null", {"type": "record", "name": "export_data", "fields": [
+                {"name": "tags", "type": {"type": "array", "items": "string"}},
+            ]}], "default": null},
+            {"name": "my_key", "type": ["null", "boolean"], "json_key": "my-key", "default": null},
+        ]}));
+    }
+
+    #[test]
+    fn test_infer_schema_name_collisions() {
+        let record = json!({"a-b": 1, "a_b": "x", "a.b": true});
+        let schema = infer_schema(&[&record]);
+        assert_eq!(field_names(&schema), vec!["a_b", "a_b_2", "a_b_3"]);
+        let mut avro = Avro::new(Vec::new(), AvroCodec::Null, Some(schema), 10).unwrap();
+        avro.write_record(&record.to_string()).unwrap();
+        avro.finish().unwrap();
+        assert_eq!(read_back(&avro.output).1, vec![json!({"a_b": 1, "a_b_2": "x", "a_b_3": true})]);
+
+        let duplicated = json!({"type": "record", "name": "r", "fields": [
+            {"name": "a", "type": "long"},
+            {"name": "a", "type": "string"},
+        ]});
+        assert_matches!(Parser::default().parse(&duplicated, None), Err(Error(ErrorKind::InvalidSchema(_), _)));
+    }
+
+    #[test]
+    fn test_container_file() {
+        let schema = json!({"type": "record", "name": "r", "fields": [{"name": "n", "type": "long"}]});
+        let mut avro = Avro::new(Vec::new(), AvroCodec::Null, Some(schema.clone()), 10).unwrap();
+        avro.write_record(r#"{"n":1}"#).unwrap();
+        assert_matches!(avro.write_record(r#"{"n":"x"}"#), Err(Error(ErrorKind::SchemaMismatch(_), _)));
+        avro.write_record(r#"{"n":{"N":"2"}}"#).unwrap();
+        avro.finish().unwrap();
+        let sync = avro.sync;
+        let file = avro.output;
+        assert!(file.starts_with(MAGIC));
+        let schema_text = schema.to_string();
+        assert!(file.windows(schema_text.len()).any(|window| window == schema_text.as_bytes()));
+        let mut block = vec![4, 4, 2, 4];
+        block.extend_from_slice(&sync);
+        assert!(file.ends_with(&block));
+    }
+
+    #[test]
+    fn test_sample_rejected() {
+        let mut avro = Avro::new(Vec::new(), AvroCodec::Null, None, 2).unwrap();
+        avro.set_source(&Source { index: 0, line: None });
+        avro.write_record(r#"{"n":1}"#).unwrap();
+        // Inferred as long, which it's too big for
+        avro.set_source(&Source { index: 1, line: Some("big".to_owned()) });
+        avro.write_record(r#"{"n":18446744073709551615}"#).unwrap();
+        let rejected = avro.take_rejected();
+        assert_eq!(rejected.len(), 1);
+        assert_eq!(rejected[0].source, Source { index: 1, line: Some("big".to_owned()) });
+        assert_matches!(rejected[0].error, Error(ErrorKind::SchemaMismatch(_), _));
+        avro.finish().unwrap();
+        assert!(avro.take_rejected().is_empty());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let schema = json!({"type": "record", "name": "r", "fields": [
+            {"name": "pk", "type": "string"},
+            {"name": "n", "type": "long"},
+            {"name": "price", "type": {"type": "bytes", "logicalType": "decimal", "precision": 5, "scale": 2}},
+            {"name": "day", "type": {"type": "int", "logicalType": "date"}},
+            {"name": "tags", "type": {"type": "array", "items": "string"}},
+            {"name": "flag", "type": ["null", "boolean"], "default": null},
+        ]});
+        let records = [
+            r#"{"pk":{"S":"a"},"n":{"N":"-3"},"price":{"N":"1.5"},"day":"1970-01-02","tags":{"SS":["x","y"]},"flag":{"BOOL":true}}"#,
+            r#"{"pk":"b","n":64,"price":-1,"day":3,"tags":[]}"#,
+        ];
+        for codec in &[AvroCodec::Null, AvroCodec::Deflate] {
+            let mut avro = Avro::new(Vec::new(), *codec, Some(schema.clone()), 10).unwrap();
+            for record in &records {
+                avro.write_record(record).unwrap();
+            }
+            avro.finish().unwrap();
+
+            let (written, records) = read_back(&avro.output);
+            assert_eq!(written, schema);
+            assert_eq!(records, vec![
+                json!({"pk": "a", "n": -3, "price": 150, "day": 1, "tags": ["x", "y"], "flag": true}),
+                json!({"pk": "b", "n": 64, "price": -100, "day": 3, "tags": [], "flag": null}),
+            ]);
+        }
+    }
+
+    #[test]
+    fn test_round_trip_inferred() {
+        let mut avro = Avro::new(Vec::new(), AvroCodec::Null, None, 10).unwrap();
+        avro.write_record(r#"{"pk":{"S":"a"},"x":{"N":"1"},"my-key":1}"#).unwrap();
+        avro.write_record(r#"{"pk":{"S":"b"}}"#).unwrap();
+        avro.finish().unwrap();
+
+        let (schema, records) = read_back(&avro.output);
+        assert_eq!(field_names(&schema), vec!["pk", "x", "my_key"]);
+        assert_eq!(records, vec![
+            json!({"pk": {"S": "a"}, "x": {"N": "1"}, "my_key": 1}),
+            json!({"pk": {"S": "b"}, "x": null, "my_key": null}),
+        ]);
+    }
+}
@@ -0,0 +1,188 @@
+use std::io::Write;
+
+use ::error_chain::bail;
+use ::serde_json::{self, json, Value};
+
+use crate::errors::*;
+use crate::json_queries::JsonPath;
+use crate::output::RecordWriter;
+
+/// Part of an index name template
+#[derive(Debug)]
+enum IndexPart {
+    Text(String),
+    Field(JsonPath),
+}
+
+/// Writes records for the Elasticsearch and OpenSearch `_bulk` API, as an
+/// index action line followed by the record.
+///
+/// The index name can take record fields, as in `projects-{.kind.S}`.
+/// Their values are lowercased, and characters not allowed in index names
+/// replaced with `_`. Binary attributes, DynamoDB B and BS, over the size
+/// limit are dropped from the record.
+#[derive(Debug)]
+pub(crate) struct EsBulk<W: Write> {
+    output: W,
+    index: Vec<IndexPart>,
+    id_path: Option<JsonPath>,
+    max_binary_bytes: Option<u64>,
+}
+
+impl<W: Write> EsBulk<W> {
+    pub(crate) fn new(output: W, index: &str, id_path: Option<&str>,
+                      max_binary_bytes: Option<u64>) -> Result<EsBulk<W>> {
+        let id_path = match id_path {
+            Some(path) => Some(JsonPath::new(path)?),
+            None => None,
+        };
+        Ok(EsBulk { output, index: parse_index_template(index)?, id_path, max_binary_bytes })
+    }
+
+    fn index_name(&self, record: &Value) -> Result<String> {
+        let mut name = String::new();
+        for part in &self.index {
+            match part {
+                IndexPart::Text(text) => name.push_str(text),
+                IndexPart::Field(path) => match path.get(record).map(scalar) {
+                    Some(Some(value)) => name.push_str(&sanitise(&value)),
+                    _ => bail!(ErrorKind::MissingKey(path.to_string())),
+                },
+            }
+        }
+        Ok(name)
+    }
+}
+
+impl<W: Write> RecordWriter for EsBulk<W> {
+    fn write_record(&mut self, record: &str) -> Result<()> {
+        let mut record: Value = serde_json::from_str(record)?;
+        let mut action = json!({ "_index": self.index_name(&record)? });
+        if let Some(ref path) = self.id_path {
+            match path.get(&record).map(scalar) {
+                Some(Some(id)) => action["_id"] = Value::String(id),
+                _ => bail!(ErrorKind::MissingKey(path.to_string())),
+            }
+        }
+        if let Some(max_bytes) = self.max_binary_bytes {
+            drop_binary(&mut record, max_bytes);
+        }
+        writeln!(self.output, "{}", json!({ "index": action }))?;
+        writeln!(self.output, "{}", record)?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.output.flush()?;
+        Ok(())
+    }
+}
+
+/// Splits an index name template into text and `{path}` fields
+fn parse_index_template(template: &str) -> Result<Vec<IndexPart>> {
+    let mut parts = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => bail!(ErrorKind::InvalidPath(template.to_owned(), "unclosed { in index name".to_owned())),
+        };
+        if start > 0 {
+            parts.push(IndexPart::Text(rest[..start].to_owned()));
+        }
+        parts.push(IndexPart::Field(JsonPath::new(&rest[start + 1..end])?));
+        rest = &rest[end + 1..];
+    }
+    if !rest.is_empty() {
+        parts.push(IndexPart::Text(rest.to_owned()));
+    }
+    Ok(parts)
+}
+
+/// Text of a string or number, also inside a DynamoDB S or N
+fn scalar(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Object(map) if map.len() == 1 => map.get("S").or_else(|| map.get("N")).and_then(scalar),
+        _ => None,
+    }
+}
+
+/// Lowercases a value and replaces characters index names can't have
+fn sanitise(value: &str) -> String {
+    value.chars()
+        .flat_map(char::to_lowercase)
+        .map(|c| match c {
+            '\\' | '/' | '*' | '?' | '"' | '<' | '>' | '|' | ' ' | ',' | '#' | ':' => '_',
+            c => c,
+        })
+        .collect()
+}
+
+/// Removes DynamoDB binary attributes whose base64 is over the size
+fn drop_binary(value: &mut Value, max_bytes: u64) {
+    match value {
+        Value::Object(map) => {
+            map.retain(|_, value| binary_size(value).is_none_or(|size| size <= max_bytes));
+            map.values_mut().for_each(|value| drop_binary(value, max_bytes));
+        },
+        Value::Array(vec) => {
+            vec.retain(|value| binary_size(value).is_none_or(|size| size <= max_bytes));
+            vec.iter_mut().for_each(|value| drop_binary(value, max_bytes));
+        },
+        _ => (),
+    }
+}
+
+/// Size of a B or BS attribute, as it's written
+fn binary_size(value: &Value) -> Option<u64> {
+    let map = match value {
+        Value::Object(map) if map.len() == 1 => map,
+        _ => return None,
+    };
+    match (map.get("B"), map.get("BS")) {
+        (Some(Value::String(b)), _) => Some(b.len() as u64),
+        (_, Some(Value::Array(bs))) =>
+            Some(bs.iter().map(|b| b.as_str().map_or(0, str::len) as u64).sum()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::assert_matches::assert_matches;
+
+    fn write(bulk: &mut EsBulk<Vec<u8>>, record: &str) -> Result<String> {
+        bulk.output.clear();
+        bulk.write_record(record)?;
+        Ok(String::from_utf8(bulk.output.clone()).unwrap())
+    }
+
+    #[test]
+    fn test_bulk_lines() {
+        let mut bulk = EsBulk::new(Vec::new(), "projects", Some(".pk.S"), None).unwrap();
+        let lines = write(&mut bulk, r#"{"pk":{"S":"a/1"},"n":{"N":"1"}}"#).unwrap();
+        assert_eq!(lines, "{\"index\":{\"_index\":\"projects\",\"_id\":\"a/1\"}}\n{\"pk\":{\"S\":\"a/1\"},\"n\":{\"N\":\"1\"}}\n");
+        assert_matches!(write(&mut bulk, r#"{"n":1}"#), Err(Error(ErrorKind::MissingKey(_), _)));
+    }
+
+    #[test]
+    fn test_index_template() {
+        let mut bulk = EsBulk::new(Vec::new(), "projects-{.kind}-{.year.N}", None, None).unwrap();
+        let lines = write(&mut bulk, r#"{"kind":{"S":"My Kind/2"},"year":{"N":"2024"}}"#).unwrap();
+        assert!(lines.starts_with("{\"index\":{\"_index\":\"projects-my_kind_2-2024\"}}\n"));
+        assert_matches!(write(&mut bulk, r#"{"kind":"x"}"#), Err(Error(ErrorKind::MissingKey(_), _)));
+        assert_matches!(EsBulk::new(Vec::new(), "projects-{.kind", None, None),
+                        Err(Error(ErrorKind::InvalidPath(_, _), _)));
+    }
+
+    #[test]
+    fn test_drop_binary() {
+        let mut bulk = EsBulk::new(Vec::new(), "i", None, Some(4)).unwrap();
+        let record = r#"{"small":{"B":"AAA="},"large":{"B":"AAAAAA=="},"set":{"BS":["AAA=","AAA="]},"l":{"L":[{"B":"AAAAAA=="}]}}"#;
+        let lines = write(&mut bulk, record).unwrap();
+        assert_eq!(lines.lines().nth(1), Some(r#"{"small":{"B":"AAA="},"l":{"L":[]}}"#));
+    }
+}
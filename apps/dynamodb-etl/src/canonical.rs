@@ -0,0 +1,169 @@
+use std::fmt::Write;
+
+use ::serde_json::Value;
+
+/// Serializes json following RFC 8785, the JSON Canonicalization Scheme:
+/// no whitespace, object keys sorted by their UTF-16 code units, numbers
+/// as ECMAScript would print them and strings with minimal escaping.
+pub(crate) fn to_canonical_string(json: &Value) -> String {
+    let mut canonical = String::new();
+    write_canonical(&mut canonical, json);
+    canonical
+}
+
+fn write_canonical(out: &mut String, json: &Value) {
+    match json {
+        Value::Null => out.push_str("null"),
+        Value::Bool(b) => out.push_str(if *b { "true" } else { "false" }),
+        Value::Number(n) => write_number(out, n.as_f64().unwrap_or(0.0)),
+        Value::String(s) => write_string(out, s),
+        Value::Array(vec) => {
+            out.push('[');
+            for (i, value) in vec.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_canonical(out, value);
+            }
+            out.push(']');
+        },
+        Value::Object(map) => {
+            let mut entries: Vec<(Vec<u16>, &String, &Value)> = map.iter()
+                .map(|(key, value)| (key.encode_utf16().collect(), key, value))
+                .collect();
+            entries.sort_by(|a, b| a.0.cmp(&b.0));
+            out.push('{');
+            for (i, (_, key, value)) in entries.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_string(out, key);
+                out.push(':');
+                write_canonical(out, value);
+            }
+            out.push('}');
+        },
+    }
+}
+
+fn write_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\u{08}' => out.push_str("\\b"),
+            '\t' => out.push_str("\\t"),
+            '\n' => out.push_str("\\n"),
+            '\u{0c}' => out.push_str("\\f"),
+            '\r' => out.push_str("\\r"),
+            c if c < ' ' => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            },
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// ECMAScript's Number.prototype.toString, which is what RFC 8785 requires
+fn write_number(out: &mut String, number: f64) {
+    if number == 0.0 {
+        out.push('0');
+        return;
+    }
+    if number < 0.0 {
+        out.push('-');
+    }
+    // Shortest digits that round trip, as d.ddde±x
+    let scientific = format!("{:e}", number.abs());
+    let (mantissa, exponent) = scientific.split_at(scientific.find('e').unwrap_or(scientific.len()));
+    let digits: String = mantissa.chars().filter(|c| *c != '.').collect();
+    let exponent: i32 = exponent.trim_start_matches('e').parse().unwrap_or(0);
+    let k = digits.len() as i32;
+    let n = exponent + 1;
+    if k <= n && n <= 21 {
+        out.push_str(&digits);
+        out.extend(std::iter::repeat_n('0', (n - k) as usize));
+    } else if 0 < n && n <= 21 {
+        out.push_str(&digits[..n as usize]);
+        out.push('.');
+        out.push_str(&digits[n as usize..]);
+    } else if -6 < n && n <= 0 {
+        out.push_str("0.");
+        out.extend(std::iter::repeat_n('0', (-n) as usize));
+        out.push_str(&digits);
+    } else {
+        out.push_str(&digits[..1]);
+        if k > 1 {
+            out.push('.');
+            out.push_str(&digits[1..]);
+        }
+        let _ = write!(out, "e{}{}", if n - 1 < 0 { '-' } else { '+' }, (n - 1).abs());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::serde_json::json;
+
+    fn number(n: f64) -> String {
+        let mut out = String::new();
+        write_number(&mut out, n);
+        out
+    }
+
+    #[test]
+    fn test_numbers() {
+        assert_eq!(number(0.0), "0");
+        assert_eq!(number(-0.0), "0");
+        assert_eq!(number(1.0), "1");
+        assert_eq!(number(-1.5), "-1.5");
+        assert_eq!(number(4.50), "4.5");
+        assert_eq!(number(2e-3), "0.002");
+        assert_eq!(number(333_333_333.333_333_3), "333333333.3333333");
+        assert_eq!(number(1e30), "1e+30");
+        assert_eq!(number(1e21), "1e+21");
+        assert_eq!(number(1e20), "100000000000000000000");
+        assert_eq!(number(0.000_001), "0.000001");
+        assert_eq!(number(1e-7), "1e-7");
+        assert_eq!(number(1.5e-27), "1.5e-27");
+        assert_eq!(number(9_007_199_254_740_993.0), "9007199254740992");
+        assert_eq!(number(f64::MAX), "1.7976931348623157e+308");
+        assert_eq!(number(5e-324), "5e-324");
+    }
+
+    #[test]
+    fn test_strings() {
+        let json = json!("\u{20ac}$\u{000f}\nA'B\"\\\\\"/");
+        assert_eq!(to_canonical_string(&json), r#""€$\u000f\nA'B\"\\\\\"/""#);
+    }
+
+    #[test]
+    fn test_key_order() {
+        // Sorted by UTF-16 code units, not by code points or UTF-8 bytes
+        let json: Value = serde_json::from_str(r#"{
+            "€": "Euro Sign",
+            "\r": "Carriage Return",
+            "\ufb33": "Hebrew Letter Dalet With Dagesh",
+            "1": "One",
+            "😀": "Emoji: Grinning Face",
+            "\u0080": "Control",
+            "ö": "Latin Small Letter O With Diaeresis"
+        }"#).unwrap();
+        assert_eq!(to_canonical_string(&json), concat!(
+            r#"{"\r":"Carriage Return","1":"One","#,
+            "\"\u{80}\":\"Control\",",
+            r#""ö":"Latin Small Letter O With Diaeresis","€":"Euro Sign","#,
+            r#""😀":"Emoji: Grinning Face","#,
+            "\"\u{fb33}\":\"Hebrew Letter Dalet With Dagesh\"}"
+        ));
+    }
+
+    #[test]
+    fn test_nested() {
+        let json: Value = serde_json::from_str(r#"{ "b": [1.0, {"d": true, "c": null}], "a": 1e2 }"#).unwrap();
+        assert_eq!(to_canonical_string(&json), r#"{"a":100,"b":[1,{"c":null,"d":true}]}"#);
+    }
+}
@@ -0,0 +1,382 @@
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use ::arrow::datatypes::{Schema, SchemaRef};
+use ::arrow::error::ArrowError;
+use ::arrow::ipc::writer::{FileWriter, StreamWriter};
+use ::arrow::json::reader::{infer_json_schema_from_iterator, ReaderBuilder};
+use ::arrow::record_batch::RecordBatch;
+use ::parquet::arrow::{parquet_to_arrow_schema, ArrowWriter};
+use ::parquet::basic::Compression;
+use ::parquet::file::properties::WriterProperties;
+use ::parquet::schema::parser::parse_message_type;
+use ::parquet::schema::types::SchemaDescriptor;
+use ::serde_json::{self, Value};
+
+use crate::errors::*;
+use crate::output::{RecordWriter, Rejected, Source};
+
+/// Rows decoded before being written together to parquet
+const PARQUET_BATCH_SIZE: usize = 1024;
+
+/// Reads an explicit schema, written as a parquet message type such as
+/// `message export { required binary id (STRING); optional int64 count; }`
+pub(crate) fn read_parquet_schema(path: &Path) -> Result<SchemaRef> {
+    let message = fs::read_to_string(path)?;
+    let descriptor = SchemaDescriptor::new(Arc::new(parse_message_type(&message)?));
+    Ok(Arc::new(parquet_to_arrow_schema(&descriptor, None)?))
+}
+
+/// Parquet file settings
+#[derive(Clone,Copy,Debug)]
+pub(crate) struct ParquetOptions {
+    pub(crate) compression: Compression,
+    /// A row group is closed once its encoded data reaches this size
+    pub(crate) row_group_bytes: usize,
+}
+
+/// Arrow IPC format: a stream, or a file that can be memory-mapped
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub(crate) enum ArrowIpc {
+    Stream,
+    File,
+}
+
+impl FromStr for ArrowIpc {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "stream" => Ok(ArrowIpc::Stream),
+            "file" => Ok(ArrowIpc::File),
+            _ => Err(format!("unknown arrow ipc format '{}', expected stream or file", s))
+        }
+    }
+}
+
+/// Columnar file format and its settings
+#[derive(Clone,Copy,Debug)]
+pub(crate) enum ColumnarFormat {
+    Parquet(ParquetOptions),
+    Arrow { ipc: ArrowIpc, batch_size: usize },
+}
+
+/// Where record batches go once the schema is known
+enum Sink<W: Write + Send> {
+    Parquet(ArrowWriter<W>, usize),
+    ArrowStream(StreamWriter<W>),
+    ArrowFile(FileWriter<W>),
+}
+
+impl<W: Write + Send> Sink<W> {
+    fn write(&mut self, batch: &RecordBatch) -> Result<()> {
+        match self {
+            Sink::Parquet(writer, row_group_bytes) => {
+                writer.write(batch)?;
+                if writer.in_progress_size() >= *row_group_bytes {
+                    writer.flush()?;
+                }
+            },
+            Sink::ArrowStream(writer) => writer.write(batch)?,
+            Sink::ArrowFile(writer) => writer.write(batch)?,
+        }
+        Ok(())
+    }
+
+    fn close(self) -> Result<()> {
+        match self {
+            Sink::Parquet(writer, _) => {
+                let _ = writer.close()?;
+            },
+            Sink::ArrowStream(mut writer) => writer.finish()?,
+            Sink::ArrowFile(mut writer) => writer.finish()?,
+        }
+        Ok(())
+    }
+}
+
+/// Writes records to columnar files, decoding them into arrow record
+/// batches with a schema that is either given or inferred from the first
+/// records. Nested objects and arrays become struct and list columns.
+///
+/// Records are decoded a batch at a time, so those that don't fit the
+/// schema are only found with the rest of their batch. They're rejected
+/// then, with the line they came from. Fields missing from the schema are
+/// dropped.
+pub(crate) struct Columnar<W: Write + Send> {
+    output: Option<W>,
+    format: ColumnarFormat,
+    schema: Option<SchemaRef>,
+    sample_size: usize,
+    sample: Vec<(String, Source)>,
+    /// Records of the next batch
+    pending: Vec<(String, Source)>,
+    source: Source,
+    rejected: Vec<Rejected>,
+    sink: Option<Sink<W>>,
+}
+
+impl<W: Write + Send> std::fmt::Debug for Columnar<W> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Columnar")
+            .field("schema", &self.schema)
+            .field("sample_size", &self.sample_size)
+            .finish()
+    }
+}
+
+impl<W: Write + Send> Columnar<W> {
+    /// Without a schema, it's inferred from the first `sample_size` records
+    pub(crate) fn new(output: W, format: ColumnarFormat, schema: Option<SchemaRef>, sample_size: usize) -> Columnar<W> {
+        Columnar {
+            output: Some(output),
+            format,
+            schema,
+            sample_size: sample_size.max(1),
+            sample: Vec::new(),
+            pending: Vec::new(),
+            source: Source::default(),
+            rejected: Vec::new(),
+            sink: None,
+        }
+    }
+
+    /// Opens the sink and writes the sampled records, once the schema is known
+    fn start(&mut self) -> Result<()> {
+        let schema = match self.schema {
+            Some(ref schema) => schema.clone(),
+            None => {
+                let sample = self.sample.iter()
+                    .map(|(record, _)| serde_json::from_str::<Value>(record).map_err(|e| ArrowError::JsonError(e.to_string())));
+                let inferred = infer_json_schema_from_iterator(sample)?;
+                let schema = Arc::new(inferred);
+                self.schema = Some(schema.clone());
+                schema
+            },
+        };
+        let output = match self.output.take() {
+            Some(output) => output,
+            None => return Ok(()),
+        };
+        let sink = match self.format {
+            ColumnarFormat::Parquet(parquet) => {
+                let properties = WriterProperties::builder()
+                    .set_compression(parquet.compression)
+                    .set_max_row_group_size(usize::MAX)
+                    .build();
+                Sink::Parquet(ArrowWriter::try_new(output, schema, Some(properties))?, parquet.row_group_bytes)
+            },
+            ColumnarFormat::Arrow { ipc: ArrowIpc::Stream, .. } => Sink::ArrowStream(StreamWriter::try_new(output, &schema)?),
+            ColumnarFormat::Arrow { ipc: ArrowIpc::File, .. } => Sink::ArrowFile(FileWriter::try_new(output, &schema)?),
+        };
+        self.sink = Some(sink);
+        for (record, source) in std::mem::take(&mut self.sample) {
+            self.push(record, source)?;
+        }
+        Ok(())
+    }
+
+    fn push(&mut self, record: String, source: Source) -> Result<()> {
+        self.pending.push((record, source));
+        let batch_size = match self.format {
+            ColumnarFormat::Parquet(_) => PARQUET_BATCH_SIZE,
+            ColumnarFormat::Arrow { batch_size, .. } => batch_size.max(1),
+        };
+        if self.pending.len() >= batch_size {
+            self.write_pending()?;
+        }
+        Ok(())
+    }
+
+    /// Decodes the pending records into a batch and writes it. If they
+    /// don't fit the schema, they're decoded one at a time to find those
+    /// that don't, which are rejected and left out.
+    fn write_pending(&mut self) -> Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let schema = self.schema.clone().unwrap_or_else(|| Arc::new(Schema::empty()));
+        let (mut records, sources): (Vec<String>, Vec<Source>) =
+            std::mem::take(&mut self.pending).into_iter().unzip();
+        let batch = match decode(&schema, &records) {
+            Ok(batch) => batch,
+            Err(_) => {
+                let pending = std::mem::take(&mut records).into_iter().zip(sources);
+                for (record, source) in pending {
+                    match decode(&schema, std::slice::from_ref(&record)) {
+                        Ok(_) => records.push(record),
+                        Err(error) => self.rejected.push(Rejected { error, source }),
+                    }
+                }
+                decode(&schema, &records)?
+            },
+        };
+        match (batch, self.sink.as_mut()) {
+            (Some(batch), Some(sink)) => sink.write(&batch),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Decodes records into a single batch, if there are any
+fn decode(schema: &SchemaRef, records: &[String]) -> Result<Option<RecordBatch>> {
+    let mut decoder = ReaderBuilder::new(schema.clone())
+        .with_coerce_primitive(true)
+        .with_batch_size(records.len().max(1))
+        .build_decoder()?;
+    let mismatch = |error: ArrowError| Error::from(ErrorKind::SchemaMismatch(error.to_string()));
+    let lines = records.join("\n");
+    let mut buffer = lines.as_bytes();
+    while !buffer.is_empty() {
+        let read = decoder.decode(buffer).map_err(mismatch)?;
+        if read == 0 {
+            break;
+        }
+        buffer = &buffer[read..];
+    }
+    decoder.flush().map_err(mismatch)
+}
+
+impl<W: Write + Send> RecordWriter for Columnar<W> {
+    fn set_source(&mut self, source: &Source) {
+        self.source.clone_from(source);
+    }
+
+    fn write_record(&mut self, record: &str) -> Result<()> {
+        let source = self.source.clone();
+        if self.sink.is_some() {
+            return self.push(record.to_owned(), source);
+        }
+        if self.schema.is_some() {
+            self.start()?;
+            return self.push(record.to_owned(), source);
+        }
+        self.sample.push((record.to_owned(), source));
+        if self.sample.len() >= self.sample_size {
+            self.start()?;
+        }
+        Ok(())
+    }
+
+    fn take_rejected(&mut self) -> Vec<Rejected> {
+        std::mem::take(&mut self.rejected)
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        if self.sink.is_none() {
+            self.start()?;
+        }
+        self.write_pending()?;
+        match self.sink.take() {
+            Some(sink) => sink.close(),
+            None => Ok(()),
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::arrow::array::{Array, AsArray};
+    use ::arrow::datatypes::{DataType, Field, Int64Type};
+    use ::arrow::ipc::reader::{FileReader, StreamReader};
+    use ::assert_matches::assert_matches;
+    use ::parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    use std::fs::File;
+    use std::io::{Seek, SeekFrom};
+
+    const OPTIONS: ParquetOptions = ParquetOptions { compression: Compression::SNAPPY, row_group_bytes: 1 << 20 };
+
+    fn read(mut file: File) -> Vec<RecordBatch> {
+        let _ = file.seek(SeekFrom::Start(0)).unwrap();
+        ParquetRecordBatchReaderBuilder::try_new(file).unwrap().build().unwrap()
+            .collect::<std::result::Result<Vec<_>, _>>().unwrap()
+    }
+
+    #[test]
+    fn test_parquet_inferred_schema() {
+        let file = ::tempfile::tempfile().unwrap();
+        let mut columnar = Columnar::new(file.try_clone().unwrap(), ColumnarFormat::Parquet(OPTIONS), None, 2);
+        columnar.write_record(r#"{"id":"a","n":1,"data":{"tags":["x"]}}"#).unwrap();
+        columnar.write_record(r#"{"id":"b","n":2}"#).unwrap();
+        columnar.write_record(r#"{"id":"c","n":3,"extra":true}"#).unwrap();
+        // Left out of its batch when it's decoded, and rejected with its line
+        columnar.set_source(&Source { index: 3, line: Some("d".to_owned()) });
+        columnar.write_record(r#"{"id":"d","n":"many"}"#).unwrap();
+        columnar.set_source(&Source { index: 4, line: None });
+        columnar.write_record(r#"{"id":"e","n":5}"#).unwrap();
+        assert!(columnar.take_rejected().is_empty());
+        columnar.finish().unwrap();
+        let rejected = columnar.take_rejected();
+        assert_eq!(rejected.len(), 1);
+        assert_eq!(rejected[0].source, Source { index: 3, line: Some("d".to_owned()) });
+        assert_matches!(rejected[0].error, Error(ErrorKind::SchemaMismatch(_), _));
+
+        let batches = read(file);
+        let schema = batches[0].schema();
+        let names: Vec<&String> = schema.fields().iter().map(|field| field.name()).collect();
+        assert_eq!(names, vec!["id", "n", "data"]);
+        assert_matches!(schema.field(2).data_type(), DataType::Struct(_));
+        let n: Vec<i64> = batches.iter()
+            .flat_map(|batch| batch.column(1).as_primitive::<Int64Type>().values().to_vec())
+            .collect();
+        assert_eq!(n, vec![1, 2, 3, 5]);
+    }
+
+    #[test]
+    fn test_parquet_explicit_schema() {
+        let mut schema_file = ::tempfile::NamedTempFile::new().unwrap();
+        write!(schema_file, "message export {{ required binary id (STRING); optional int64 n; }}").unwrap();
+        let schema = read_parquet_schema(schema_file.path()).unwrap();
+        let file = ::tempfile::tempfile().unwrap();
+        let options = ParquetOptions { compression: Compression::UNCOMPRESSED, row_group_bytes: 1 };
+        let mut columnar = Columnar::new(file.try_clone().unwrap(), ColumnarFormat::Parquet(options), Some(schema), 100);
+        columnar.write_record(r#"{"id":"a","n":1,"dropped":[]}"#).unwrap();
+        columnar.write_record(r#"{"n":2}"#).unwrap();
+        columnar.write_record(r#"{"id":"c"}"#).unwrap();
+        columnar.finish().unwrap();
+
+        let batches = read(file);
+        assert_eq!(batches.iter().map(RecordBatch::num_rows).sum::<usize>(), 2);
+        assert_eq!(batches[0].num_columns(), 2);
+        assert_eq!(batches.last().unwrap().column(1).null_count(), 1);
+    }
+
+    #[test]
+    fn test_decode() {
+        let schema = Arc::new(Schema::new(vec![Field::new("n", DataType::Int64, false)]));
+        let records = vec![r#"{"n":1}"#.to_owned(), r#"{"n":2}"#.to_owned()];
+        let batch = decode(&schema, &records).unwrap().unwrap();
+        assert_eq!(batch.column(0).as_primitive::<Int64Type>().values().to_vec(), vec![1, 2]);
+        let result = decode(&schema, &[r#"{"n":"many"}"#.to_owned()]);
+        assert_matches!(result, Err(Error(ErrorKind::SchemaMismatch(_), _)));
+        assert_matches!(result, Err(ref error) if !error.is_fatal());
+        assert_matches!(decode(&schema, &[]), Ok(None));
+    }
+
+    #[test]
+    fn test_arrow_ipc() {
+        let records = [r#"{"id":"a","data":{"tags":["x","y"]}}"#, r#"{"id":"b","data":{"tags":[]}}"#, r#"{"id":"c"}"#];
+        for ipc in &[ArrowIpc::Stream, ArrowIpc::File] {
+            let mut file = ::tempfile::tempfile().unwrap();
+            let format = ColumnarFormat::Arrow { ipc: *ipc, batch_size: 2 };
+            let mut columnar = Columnar::new(file.try_clone().unwrap(), format, None, 10);
+            for record in &records {
+                columnar.write_record(record).unwrap();
+            }
+            columnar.finish().unwrap();
+
+            let _ = file.seek(SeekFrom::Start(0)).unwrap();
+            let batches: Vec<RecordBatch> = match ipc {
+                ArrowIpc::Stream => StreamReader::try_new(file, None).unwrap().collect::<std::result::Result<_, _>>(),
+                ArrowIpc::File => FileReader::try_new(file, None).unwrap().collect::<std::result::Result<_, _>>(),
+            }.unwrap();
+            assert_eq!(batches.iter().map(RecordBatch::num_rows).collect::<Vec<usize>>(), vec![2, 1]);
+            let data = batches[0].column(1).as_struct();
+            assert_matches!(data.column(0).data_type(), DataType::List(_));
+            assert_eq!(data.column(0).as_list::<i32>().value_length(0), 2);
+        }
+    }
+}
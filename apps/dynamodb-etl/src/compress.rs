@@ -0,0 +1,318 @@
+use std::io::{self, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread::{self, JoinHandle};
+
+use ::bzip2::write::BzEncoder;
+use ::error_chain::bail;
+use ::flate2::write::GzEncoder;
+use ::xz2::write::XzEncoder;
+
+use crate::errors::*;
+
+/// Bytes handed to the compression thread at a time
+const CHUNK_SIZE: usize = 256 * 1024;
+/// Chunks waiting for the compression thread before writes block
+const QUEUED_CHUNKS: usize = 16;
+
+/// Compression of the output stream
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub(crate) enum OutputCompression {
+    Gzip,
+    Zstd,
+    Bzip2,
+    Xz,
+}
+
+impl FromStr for OutputCompression {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "gzip" => Ok(OutputCompression::Gzip),
+            "zstd" => Ok(OutputCompression::Zstd),
+            "bzip2" => Ok(OutputCompression::Bzip2),
+            "xz" => Ok(OutputCompression::Xz),
+            _ => Err(format!("unknown output compression '{}', expected gzip, zstd, bzip2 or xz", s))
+        }
+    }
+}
+
+impl OutputCompression {
+    /// The compression a file name's extension stands for
+    pub(crate) fn from_path(path: &Path) -> Option<OutputCompression> {
+        match path.extension()?.to_str()? {
+            "gz" => Some(OutputCompression::Gzip),
+            "zst" => Some(OutputCompression::Zstd),
+            "bz2" => Some(OutputCompression::Bzip2),
+            "xz" => Some(OutputCompression::Xz),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn extension(self) -> &'static str {
+        match self {
+            OutputCompression::Gzip => "gz",
+            OutputCompression::Zstd => "zst",
+            OutputCompression::Bzip2 => "bz2",
+            OutputCompression::Xz => "xz",
+        }
+    }
+
+    /// Default and valid compression levels
+    fn levels(self) -> (u32, std::ops::RangeInclusive<u32>) {
+        match self {
+            OutputCompression::Gzip => (6, 0..=9),
+            OutputCompression::Zstd => (3, 1..=22),
+            OutputCompression::Bzip2 => (9, 1..=9),
+            OutputCompression::Xz => (6, 0..=9),
+        }
+    }
+}
+
+type Output = Box<dyn Write + Send>;
+
+enum Message {
+    Data(Vec<u8>),
+    /// Completes the compressed stream written so far
+    Finish(SyncSender<io::Result<()>>),
+}
+
+/// A compressed stream being written
+enum Encoder {
+    Gzip(GzEncoder<Output>),
+    Zstd(::zstd::stream::write::Encoder<'static, Output>),
+    Bzip2(BzEncoder<Output>),
+    Xz(XzEncoder<Output>),
+}
+
+impl Encoder {
+    fn new(compression: OutputCompression, level: u32, output: Output) -> io::Result<Encoder> {
+        Ok(match compression {
+            OutputCompression::Gzip => Encoder::Gzip(GzEncoder::new(output, ::flate2::Compression::new(level))),
+            OutputCompression::Zstd => Encoder::Zstd(::zstd::stream::write::Encoder::new(output, level as i32)?),
+            OutputCompression::Bzip2 => Encoder::Bzip2(BzEncoder::new(output, ::bzip2::Compression::new(level))),
+            OutputCompression::Xz => Encoder::Xz(XzEncoder::new(output, level)),
+        })
+    }
+
+    fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.write_all(data),
+            Encoder::Zstd(encoder) => encoder.write_all(data),
+            Encoder::Bzip2(encoder) => encoder.write_all(data),
+            Encoder::Xz(encoder) => encoder.write_all(data),
+        }
+    }
+
+    fn finish(self) -> io::Result<Output> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.finish(),
+            Encoder::Zstd(encoder) => encoder.finish(),
+            Encoder::Bzip2(encoder) => encoder.finish(),
+            Encoder::Xz(encoder) => encoder.finish(),
+        }
+    }
+}
+
+/// Compresses what's written to it on a separate thread.
+///
+/// Flushing completes the compressed stream, so that the output is valid
+/// as soon as the records have been flushed. Anything written after that
+/// goes to a new stream, which decompressors read as a continuation of
+/// the previous one.
+#[derive(Debug)]
+pub(crate) struct Compressed {
+    buffer: Vec<u8>,
+    sender: Option<SyncSender<Message>>,
+    worker: Option<JoinHandle<io::Result<()>>>,
+}
+
+impl Compressed {
+    pub(crate) fn new(output: Output, compression: OutputCompression, level: Option<u32>) -> Result<Compressed> {
+        let (default, levels) = compression.levels();
+        let level = level.unwrap_or(default);
+        if !levels.contains(&level) {
+            bail!(ErrorKind::InvalidArguments(format!("{:?} compression level must be from {} to {}, got {}",
+                                                      compression, levels.start(), levels.end(), level)));
+        }
+        let (sender, receiver) = mpsc::sync_channel(QUEUED_CHUNKS);
+        let worker = thread::spawn(move || compress(compression, level, output, receiver));
+        Ok(Compressed { buffer: Vec::with_capacity(CHUNK_SIZE), sender: Some(sender), worker: Some(worker) })
+    }
+
+    fn send(&mut self, message: Message) -> io::Result<()> {
+        let sent = match self.sender {
+            Some(ref sender) => sender.send(message).is_ok(),
+            None => false,
+        };
+        if sent {
+            Ok(())
+        } else {
+            Err(self.worker_error())
+        }
+    }
+
+    /// Why the compression thread stopped taking data
+    fn worker_error(&mut self) -> io::Error {
+        self.sender = None;
+        match self.worker.take().map(JoinHandle::join) {
+            Some(Ok(Err(error))) => error,
+            Some(Err(_)) => io::Error::other("compression thread panicked"),
+            _ => io::Error::new(io::ErrorKind::BrokenPipe, "compression thread stopped"),
+        }
+    }
+
+    fn send_buffer(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let data = std::mem::replace(&mut self.buffer, Vec::with_capacity(CHUNK_SIZE));
+        self.send(Message::Data(data))
+    }
+}
+
+impl Write for Compressed {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        if self.buffer.len() >= CHUNK_SIZE {
+            self.send_buffer()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send_buffer()?;
+        let (ack, finished) = mpsc::sync_channel(1);
+        self.send(Message::Finish(ack))?;
+        match finished.recv() {
+            Ok(result) => result,
+            Err(_) => Err(self.worker_error()),
+        }
+    }
+}
+
+/// Completes anything written since the last flush, without a way to
+/// report errors
+impl Drop for Compressed {
+    fn drop(&mut self) {
+        let _ = self.send_buffer();
+        self.sender = None;
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// Runs on the compression thread until the sender is gone
+fn compress(compression: OutputCompression, level: u32, output: Output, receiver: Receiver<Message>) -> io::Result<()> {
+    let mut idle = Some(output);
+    let mut encoder = None;
+    for message in receiver {
+        match message {
+            Message::Data(data) => {
+                if encoder.is_none() {
+                    if let Some(output) = idle.take() {
+                        encoder = Some(Encoder::new(compression, level, output)?);
+                    }
+                }
+                if let Some(ref mut encoder) = encoder {
+                    encoder.write_all(&data)?;
+                }
+            },
+            Message::Finish(ack) => {
+                let finished = match encoder.take() {
+                    Some(encoder) => encoder.finish().map(|output| idle = Some(output)),
+                    None => Ok(()),
+                };
+                let flushed = finished.and_then(|_| match idle {
+                    Some(ref mut output) => output.flush(),
+                    None => Ok(()),
+                });
+                let failed = flushed.as_ref().err().map(|e| io::Error::new(e.kind(), e.to_string()));
+                let _ = ack.send(flushed);
+                if let Some(error) = failed {
+                    return Err(error);
+                }
+            },
+        }
+    }
+    match encoder {
+        Some(encoder) => encoder.finish()?.flush(),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::sync::{Arc, Mutex};
+    use ::assert_matches::assert_matches;
+
+    /// Output that can be read once the writer is gone
+    #[derive(Clone,Debug,Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn compress_twice(compression: OutputCompression) -> Vec<u8> {
+        let shared = Shared::default();
+        let mut compressed = Compressed::new(Box::new(shared.clone()), compression, None).unwrap();
+        compressed.write_all(b"first\n").unwrap();
+        compressed.flush().unwrap();
+        compressed.write_all(b"second\n").unwrap();
+        compressed.flush().unwrap();
+        drop(compressed);
+        let bytes = shared.0.lock().unwrap().clone();
+        bytes
+    }
+
+    #[test]
+    fn test_gzip() {
+        let mut text = String::new();
+        let compressed = compress_twice(OutputCompression::Gzip);
+        let _ = ::flate2::read::MultiGzDecoder::new(&compressed[..]).read_to_string(&mut text).unwrap();
+        assert_eq!(text, "first\nsecond\n");
+    }
+
+    #[test]
+    fn test_zstd() {
+        let decompressed = ::zstd::stream::decode_all(&compress_twice(OutputCompression::Zstd)[..]).unwrap();
+        assert_eq!(decompressed, b"first\nsecond\n");
+    }
+
+    #[test]
+    fn test_bzip2() {
+        let mut text = String::new();
+        let compressed = compress_twice(OutputCompression::Bzip2);
+        let _ = ::bzip2::read::MultiBzDecoder::new(&compressed[..]).read_to_string(&mut text).unwrap();
+        assert_eq!(text, "first\nsecond\n");
+    }
+
+    #[test]
+    fn test_xz() {
+        let mut text = String::new();
+        let compressed = compress_twice(OutputCompression::Xz);
+        let _ = ::xz2::read::XzDecoder::new_multi_decoder(&compressed[..]).read_to_string(&mut text).unwrap();
+        assert_eq!(text, "first\nsecond\n");
+    }
+
+    #[test]
+    fn test_from_path_and_level() {
+        assert_eq!(OutputCompression::from_path(Path::new("out/part-1.jsonl.zst")), Some(OutputCompression::Zstd));
+        assert_eq!(OutputCompression::from_path(Path::new("out.jsonl")), None);
+        assert_matches!(Compressed::new(Box::new(io::sink()), OutputCompression::Bzip2, Some(0)),
+                        Err(Error(ErrorKind::InvalidArguments(_), _)));
+    }
+}
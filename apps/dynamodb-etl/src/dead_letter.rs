@@ -0,0 +1,86 @@
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+use std::string::FromUtf8Error;
+
+use ::base64;
+use ::serde_json::{json, Value};
+
+use crate::errors::*;
+
+/// Writes the input lines of records that failed, one json per line, with
+/// why they failed, so that they can be fixed and processed again:
+///
+/// `{"line":3,"input":"...","error":"GzipError","messages":[...],"path":".projectBinaryData.B"}`
+///
+/// The path is the data path that couldn't be decoded, or the path given
+/// to whatever failed, and null if it was the line itself. Lines that
+/// aren't valid UTF-8 also get their exact bytes as `input_base64`.
+#[derive(Debug)]
+pub(crate) struct DeadLetter {
+    output: io::BufWriter<File>,
+    bin_path: String,
+    text_path: String,
+}
+
+impl DeadLetter {
+    pub(crate) fn new(path: &Path, bin_path: &str, text_path: &str) -> Result<DeadLetter> {
+        Ok(DeadLetter {
+            output: io::BufWriter::new(File::create(path)?),
+            bin_path: bin_path.to_owned(),
+            text_path: text_path.to_owned(),
+        })
+    }
+
+    /// Lines that aren't valid UTF-8 are written with replacement characters
+    pub(crate) fn write(&mut self, line: Option<&str>, error: &Error) -> Result<()> {
+        let invalid = match line {
+            Some(_) => None,
+            None => invalid_utf8(error).map(FromUtf8Error::as_bytes),
+        };
+        let input = match (line, invalid) {
+            (Some(line), _) => Value::String(line.to_owned()),
+            (None, Some(bytes)) => Value::String(String::from_utf8_lossy(bytes).into_owned()),
+            (None, None) => Value::Null,
+        };
+        let mut entry = json!({
+            "line": line_number(error),
+            "input": input,
+            "error": cause_kind(error).name(),
+            "messages": error.iter().map(ToString::to_string).collect::<Vec<String>>(),
+            "path": self.failed_path(error),
+        });
+        if let Some(bytes) = invalid {
+            entry["input_base64"] = Value::String(base64::encode(bytes));
+        }
+        writeln!(self.output, "{}", entry)?;
+        Ok(())
+    }
+
+    pub(crate) fn finish(&mut self) -> Result<()> {
+        self.output.flush()?;
+        Ok(())
+    }
+
+    fn failed_path(&self, error: &Error) -> Option<String> {
+        chain_kinds(error).find_map(|kind| match kind {
+            ErrorKind::InvalidPath(path, _) | ErrorKind::TimestampError(path, _)
+            | ErrorKind::ExplodeError(path, _) | ErrorKind::MissingKey(path) => Some(path.clone()),
+            ErrorKind::Base64Error | ErrorKind::GzipError => Some(self.bin_path.clone()),
+            ErrorKind::JqParseError(when, _) | ErrorKind::JqError(when, _) if when.starts_with("updating binary") =>
+                Some(self.bin_path.clone()),
+            ErrorKind::JqParseError(when, _) | ErrorKind::JqError(when, _) if when.starts_with("updating text") =>
+                Some(self.text_path.clone()),
+            _ => None,
+        })
+    }
+}
+
+/// The undecodable line behind an invalid UTF-8 error
+fn invalid_utf8(error: &Error) -> Option<&FromUtf8Error> {
+    chain_kinds(error).find_map(|kind| match kind {
+        #[cfg(unix)]
+        ErrorKind::Io(e) => e.get_ref().and_then(|e| e.downcast_ref::<FromUtf8Error>()),
+        _ => None,
+    })
+}
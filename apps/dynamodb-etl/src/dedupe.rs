@@ -0,0 +1,457 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::str::FromStr;
+
+use ::serde_json::{self, Value};
+use ::sha2::{Digest, Sha256};
+
+use crate::errors::*;
+use crate::failures::Failures;
+use crate::json_queries::{parse_path_list, JsonPath};
+use crate::output::{RecordWriter, Source};
+
+const SLOT_SIZE: usize = 24;
+const INITIAL_CAPACITY: u64 = 1 << 16;
+
+/// Which of the records sharing a key is kept
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub(crate) enum DedupeKeep {
+    First,
+    Last,
+}
+
+impl FromStr for DedupeKeep {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "first" => Ok(DedupeKeep::First),
+            "last" => Ok(DedupeKeep::Last),
+            _ => Err(format!("unknown dedupe mode '{}', expected first or last", s))
+        }
+    }
+}
+
+/// Where the keys already seen are kept
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub(crate) enum DedupeStore {
+    Memory,
+    Disk,
+}
+
+impl FromStr for DedupeStore {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "memory" => Ok(DedupeStore::Memory),
+            "disk" => Ok(DedupeStore::Disk),
+            _ => Err(format!("unknown dedupe store '{}', expected memory or disk", s))
+        }
+    }
+}
+
+/// The paths records are deduplicated by
+#[derive(Debug)]
+pub(crate) struct DedupeKey {
+    paths: Vec<JsonPath>,
+}
+
+impl DedupeKey {
+    /// Key paths are a comma-separated list of jq paths
+    pub(crate) fn new(paths: &str) -> Result<DedupeKey> {
+        Ok(DedupeKey { paths: parse_path_list(paths)? })
+    }
+
+    /// Fingerprint of the json array of key values, or None if the record
+    /// has none of them
+    pub(crate) fn key(&self, record: &Value) -> Result<Option<u128>> {
+        let values: Vec<&Value> = self.paths.iter()
+            .map(|path| path.get(record).unwrap_or(&Value::Null))
+            .collect();
+        if values.iter().all(|value| value.is_null()) {
+            return Ok(None);
+        }
+        let digest = Sha256::digest(serde_json::to_string(&values)?.as_bytes());
+        let mut fingerprint = [0u8; 16];
+        fingerprint.copy_from_slice(&digest[..16]);
+        // zero marks empty slots on disk
+        Ok(Some(u128::from_le_bytes(fingerprint) | 1))
+    }
+}
+
+/// Drops records whose key was already seen. Records without a key are
+/// never dropped.
+///
+/// Keeping the last record requires holding every record until the end of
+/// the input, so they are spilled to a temporary file and written on
+/// `finish`.
+#[derive(Debug)]
+pub(crate) struct Dedupe {
+    seen: SeenKeys,
+    records: u64,
+    dropped: u64,
+    spill: Option<BufWriter<File>>,
+}
+
+impl Dedupe {
+    pub(crate) fn new(keep: DedupeKeep, store: DedupeStore) -> Result<Dedupe> {
+        let seen = match store {
+            DedupeStore::Memory => SeenKeys::Memory(HashMap::new()),
+            DedupeStore::Disk => SeenKeys::Disk(DiskMap::new()?),
+        };
+        let spill = match keep {
+            DedupeKeep::First => None,
+            DedupeKeep::Last => Some(BufWriter::new(::tempfile::tempfile()?)),
+        };
+        Ok(Dedupe { seen, records: 0, dropped: 0, spill })
+    }
+
+    pub(crate) fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Writes or holds back all records produced from the same input record,
+    /// with the key of that input record and the index of its line. Input
+    /// records that produced no records still count as the latest with
+    /// their key.
+    pub(crate) fn write(&mut self, key: Option<u128>, line: usize, records: &[String],
+                        output: &mut dyn RecordWriter) -> Result<()> {
+        let index = self.records;
+        self.records += 1;
+        let repeated = match key {
+            Some(key) => self.seen.insert(key, index)?.is_some(),
+            None => false,
+        };
+        if repeated {
+            self.dropped += 1;
+        }
+        for record in records {
+            match self.spill {
+                Some(ref mut spill) if key.is_some() => writeln!(spill, "{}\t{}\t{}", index, line, record)?,
+                Some(ref mut spill) => writeln!(spill, "-\t{}\t{}", line, record)?,
+                None if !repeated => output.write_record(record)?,
+                None => (),
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the records kept back, if any. Those the output can't take
+    /// fail with the line they came from.
+    pub(crate) fn finish(&mut self, output: &mut dyn RecordWriter, failures: &mut Failures) -> Result<()> {
+        let spilled = match self.spilled().chain_err(|| ErrorKind::OutputError)? {
+            Some(spilled) => spilled,
+            None => return Ok(()),
+        };
+        for spilled in spilled {
+            let (line, record) = spilled.chain_err(|| ErrorKind::OutputError)?;
+            output.set_source(&Source { index: line, line: None });
+            match output.write_record(&record) {
+                Err(error) if !error.is_fatal() =>
+                    failures.fail(Error::with_chain(error, ErrorKind::LineNo(line + 1, false)), None)?,
+                written => written.chain_err(|| ErrorKind::OutputError)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// The spilled records that were the last with their key, with the
+    /// index of the line each came from
+    fn spilled(&mut self) -> io::Result<Option<impl Iterator<Item = io::Result<(usize, String)>>>> {
+        let spill = match self.spill.take() {
+            Some(spill) => spill,
+            None => return Ok(None),
+        };
+        let mut winners = vec![0u64; (self.records / 64 + 1) as usize];
+        self.seen.for_each_index(|index| winners[(index / 64) as usize] |= 1 << (index % 64))?;
+        let mut file = spill.into_inner().map_err(|e| e.into_error())?;
+        let _ = file.seek(SeekFrom::Start(0))?;
+        Ok(Some(BufReader::new(file).lines().filter_map(move |spilled| {
+            let spilled = match spilled {
+                Ok(spilled) => spilled,
+                Err(error) => return Some(Err(error)),
+            };
+            let mut fields = spilled.splitn(3, '\t');
+            let is_winner = fields.next().and_then(|index| index.parse::<u64>().ok())
+                .is_none_or(|i| winners[(i / 64) as usize] & (1 << (i % 64)) != 0);
+            let line = fields.next().and_then(|line| line.parse().ok()).unwrap_or(0);
+            let record = fields.next().unwrap_or_default();
+            if is_winner { Some(Ok((line, record.to_owned()))) } else { None }
+        })))
+    }
+}
+
+#[derive(Debug)]
+enum SeenKeys {
+    Memory(HashMap<u128, u64>),
+    Disk(DiskMap),
+}
+
+impl SeenKeys {
+    /// Records the index of the latest record with that key, returning the
+    /// previous one
+    fn insert(&mut self, key: u128, index: u64) -> io::Result<Option<u64>> {
+        match self {
+            SeenKeys::Memory(map) => Ok(map.insert(key, index)),
+            SeenKeys::Disk(map) => map.insert(key, index),
+        }
+    }
+
+    fn for_each_index(&mut self, f: impl FnMut(u64)) -> io::Result<()> {
+        match self {
+            SeenKeys::Memory(map) => {
+                map.values().cloned().for_each(f);
+                Ok(())
+            },
+            SeenKeys::Disk(map) => map.for_each_value(f),
+        }
+    }
+}
+
+/// Open addressing hash table from fingerprints to record indexes, kept in
+/// a temporary file so that tables with more keys than fit in memory can
+/// be deduplicated. Slots are a 16 bytes key followed by an 8 bytes value.
+#[derive(Debug)]
+struct DiskMap {
+    file: File,
+    capacity: u64,
+    len: u64,
+}
+
+impl DiskMap {
+    fn new() -> io::Result<DiskMap> {
+        DiskMap::with_capacity(INITIAL_CAPACITY)
+    }
+
+    /// Capacity must be a power of two
+    fn with_capacity(capacity: u64) -> io::Result<DiskMap> {
+        let file = ::tempfile::tempfile()?;
+        file.set_len(capacity * SLOT_SIZE as u64)?;
+        Ok(DiskMap { file, capacity, len: 0 })
+    }
+
+    fn insert(&mut self, key: u128, value: u64) -> io::Result<Option<u64>> {
+        if (self.len + 1) * 2 > self.capacity {
+            self.grow()?;
+        }
+        // the low bit is forced to mark occupancy, so place by the high bits
+        let mut slot = (key >> 64) as u64 & (self.capacity - 1);
+        loop {
+            let (slot_key, slot_value) = self.read_slot(slot)?;
+            if slot_key == 0 || slot_key == key {
+                self.write_slot(slot, key, value)?;
+                if slot_key == 0 {
+                    self.len += 1;
+                    return Ok(None);
+                }
+                return Ok(Some(slot_value));
+            }
+            slot = (slot + 1) & (self.capacity - 1);
+        }
+    }
+
+    fn for_each_value(&mut self, mut f: impl FnMut(u64)) -> io::Result<()> {
+        let _ = self.file.seek(SeekFrom::Start(0))?;
+        let mut reader = BufReader::new(&self.file);
+        let mut buffer = [0u8; SLOT_SIZE];
+        for _ in 0..self.capacity {
+            reader.read_exact(&mut buffer)?;
+            let (key, value) = decode_slot(&buffer);
+            if key != 0 {
+                f(value);
+            }
+        }
+        Ok(())
+    }
+
+    fn grow(&mut self) -> io::Result<()> {
+        let mut bigger = DiskMap::with_capacity(self.capacity * 2)?;
+        let _ = self.file.seek(SeekFrom::Start(0))?;
+        let mut reader = BufReader::new(&self.file);
+        let mut buffer = [0u8; SLOT_SIZE];
+        for _ in 0..self.capacity {
+            reader.read_exact(&mut buffer)?;
+            let (key, value) = decode_slot(&buffer);
+            if key != 0 {
+                let _ = bigger.insert(key, value)?;
+            }
+        }
+        *self = bigger;
+        Ok(())
+    }
+
+    fn read_slot(&mut self, slot: u64) -> io::Result<(u128, u64)> {
+        let mut buffer = [0u8; SLOT_SIZE];
+        let _ = self.file.seek(SeekFrom::Start(slot * SLOT_SIZE as u64))?;
+        self.file.read_exact(&mut buffer)?;
+        Ok(decode_slot(&buffer))
+    }
+
+    fn write_slot(&mut self, slot: u64, key: u128, value: u64) -> io::Result<()> {
+        let mut buffer = [0u8; SLOT_SIZE];
+        buffer[..16].copy_from_slice(&key.to_le_bytes());
+        buffer[16..].copy_from_slice(&value.to_le_bytes());
+        let _ = self.file.seek(SeekFrom::Start(slot * SLOT_SIZE as u64))?;
+        self.file.write_all(&buffer)
+    }
+}
+
+fn decode_slot(buffer: &[u8; SLOT_SIZE]) -> (u128, u64) {
+    let mut key = [0u8; 16];
+    let mut value = [0u8; 8];
+    key.copy_from_slice(&buffer[..16]);
+    value.copy_from_slice(&buffer[16..]);
+    (u128::from_le_bytes(key), u64::from_le_bytes(value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::failures::ErrorBudget;
+    use ::assert_matches::assert_matches;
+    use ::error_chain::bail;
+
+    const RECORDS: &[&str] = &[
+        r#"{"pk":{"S":"a"},"sk":{"S":"1"},"v":1}"#,
+        r#"{"pk":{"S":"a"},"sk":{"S":"2"},"v":2}"#,
+        r#"{"pk":{"S":"a"},"sk":{"S":"1"},"v":3}"#,
+        r#"{"v":4}"#,
+        r#"{"v":5}"#,
+        r#"{"pk":{"S":"a"},"sk":{"S":"2"},"v":6}"#,
+    ];
+
+    fn key(paths: &str, record: &str) -> Option<u128> {
+        DedupeKey::new(paths).unwrap().key(&serde_json::from_str(record).unwrap()).unwrap()
+    }
+
+    fn dedupe(keep: DedupeKeep, store: DedupeStore) -> (Vec<String>, u64) {
+        let mut dedupe = Dedupe::new(keep, store).unwrap();
+        let mut output = Vec::new();
+        for record in RECORDS {
+            dedupe.write(key(".pk.S, .sk.S", record), 0, &[record.to_string()], &mut output).unwrap();
+        }
+        dedupe.finish(&mut output, &mut Failures::default()).unwrap();
+        let lines = String::from_utf8(output).unwrap().lines().map(|s| s.to_owned()).collect();
+        (lines, dedupe.dropped())
+    }
+
+    #[test]
+    fn test_dedupe_keep_first() {
+        for store in &[DedupeStore::Memory, DedupeStore::Disk] {
+            let (lines, dropped) = dedupe(DedupeKeep::First, *store);
+            assert_eq!(lines, vec![RECORDS[0], RECORDS[1], RECORDS[3], RECORDS[4]]);
+            assert_eq!(dropped, 2);
+        }
+    }
+
+    #[test]
+    fn test_dedupe_keep_last() {
+        for store in &[DedupeStore::Memory, DedupeStore::Disk] {
+            let (lines, dropped) = dedupe(DedupeKeep::Last, *store);
+            assert_eq!(lines, vec![RECORDS[2], RECORDS[3], RECORDS[4], RECORDS[5]]);
+            assert_eq!(dropped, 2);
+        }
+    }
+
+    #[test]
+    fn test_dedupe_groups() {
+        let mut dedupe = Dedupe::new(DedupeKeep::Last, DedupeStore::Memory).unwrap();
+        let mut output = Vec::new();
+        let group = |pk: &str, n: usize| (0..n)
+            .map(|i| format!(r#"{{"pk":"{}","_index":{}}}"#, pk, i))
+            .collect::<Vec<String>>();
+        let a = key(".pk", r#"{"pk":"a"}"#);
+        dedupe.write(a, 0, &group("a", 2), &mut output).unwrap();
+        dedupe.write(key(".pk", r#"{"pk":"b"}"#), 1, &group("b", 0), &mut output).unwrap();
+        dedupe.write(a, 2, &group("a", 1), &mut output).unwrap();
+        dedupe.finish(&mut output, &mut Failures::default()).unwrap();
+        assert_eq!(String::from_utf8(output).unwrap(), "{\"pk\":\"a\",\"_index\":0}\n");
+        assert_eq!(dedupe.dropped(), 1);
+    }
+
+    #[test]
+    fn test_dedupe_empty_group() {
+        let mut dedupe = Dedupe::new(DedupeKeep::Last, DedupeStore::Memory).unwrap();
+        let mut output = Vec::new();
+        let a = key(".pk", r#"{"pk":"a"}"#);
+        dedupe.write(a, 0, &[r#"{"pk":"a","item":1}"#.to_owned(), r#"{"pk":"a","item":2}"#.to_owned()], &mut output)
+            .unwrap();
+        dedupe.write(a, 1, &[], &mut output).unwrap();
+        dedupe.finish(&mut output, &mut Failures::default()).unwrap();
+        assert!(output.is_empty());
+        assert_eq!(dedupe.dropped(), 1);
+    }
+
+    /// Takes records with a "v", and rejects the others as not fitting
+    struct Picky(Vec<String>);
+
+    impl RecordWriter for Picky {
+        fn write_record(&mut self, record: &str) -> Result<()> {
+            if !record.contains("\"v\"") {
+                bail!(ErrorKind::SchemaMismatch("no v".to_owned()));
+            }
+            self.0.push(record.to_owned());
+            Ok(())
+        }
+
+        fn finish(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_dedupe_keep_last_fails() {
+        for &strict in &[false, true] {
+            let mut dedupe = Dedupe::new(DedupeKeep::Last, DedupeStore::Memory).unwrap();
+            let mut output = Picky(Vec::new());
+            let records = [r#"{"pk":"a","v":1}"#, r#"{"pk":"b"}"#, r#"{"pk":"a","v":2}"#];
+            for (line, record) in records.iter().enumerate() {
+                dedupe.write(key(".pk", record), line + 10, &[record.to_string()], &mut output).unwrap();
+            }
+            let failures = &mut Failures::new(None, ErrorBudget::default(), strict);
+            let result = dedupe.finish(&mut output, failures);
+            if strict {
+                assert_matches!(result, Err(Error(ErrorKind::Strict, _)));
+                assert_matches!(result.unwrap_err().iter().nth(1).map(|error| error.to_string()),
+                                Some(ref message) if message == "Error processing record number 12");
+            } else {
+                assert_matches!(result, Ok(()));
+                assert_eq!(output.0, vec![records[2]]);
+            }
+            assert_eq!(failures.kinds().collect::<Vec<_>>(), vec![("SchemaMismatch", 1)]);
+        }
+    }
+
+    #[test]
+    fn test_disk_map_grows() {
+        let mut map = DiskMap::with_capacity(4).unwrap();
+        for key in 1..=100u128 {
+            assert_eq!(map.insert(key << 64 | 1, key as u64).unwrap(), None);
+        }
+        assert_eq!(map.insert(42 << 64 | 1, 0).unwrap(), Some(42));
+        assert_eq!(map.len, 100);
+        assert!(map.capacity >= 200);
+        let mut total = 0;
+        map.for_each_value(|value| total += value).unwrap();
+        assert_eq!(total, (1..=100).sum::<u64>() - 42);
+    }
+
+    #[test]
+    fn test_disk_map_even_slots() {
+        let mut map = DiskMap::with_capacity(4).unwrap();
+        assert_eq!(map.insert(2 << 64 | 1, 7).unwrap(), None);
+        assert_eq!(map.read_slot(2).unwrap(), (2 << 64 | 1, 7));
+    }
+
+    #[test]
+    fn test_dedupe_options_from_str() {
+        assert_eq!("first".parse::<DedupeKeep>(), Ok(DedupeKeep::First));
+        assert_eq!("last".parse::<DedupeKeep>(), Ok(DedupeKeep::Last));
+        assert!("any".parse::<DedupeKeep>().is_err());
+        assert_eq!("memory".parse::<DedupeStore>(), Ok(DedupeStore::Memory));
+        assert_eq!("disk".parse::<DedupeStore>(), Ok(DedupeStore::Disk));
+        assert!("cloud".parse::<DedupeStore>().is_err());
+    }
+}
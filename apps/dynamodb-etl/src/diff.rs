@@ -0,0 +1,434 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io::{BufRead, BufReader, BufWriter, Seek, SeekFrom, Write};
+
+use ::error_chain::bail;
+use ::serde_json::{self, json, Map, Value};
+
+use crate::errors::*;
+use crate::json_queries::{parse_path_list, JsonPath};
+
+/// Upper bound on the partitions an input is split into at once, as each
+/// one keeps a file open. Partitions that are still too large are split
+/// again.
+const MAX_FAN_OUT: usize = 128;
+
+/// How many records were in each situation
+#[derive(Clone,Copy,Debug,Default,PartialEq)]
+pub(crate) struct DiffCounts {
+    pub(crate) added: u64,
+    pub(crate) removed: u64,
+    pub(crate) changed: u64,
+    pub(crate) unchanged: u64,
+}
+
+/// Compares two exports, matching records by key.
+///
+/// Inputs are split by a hash of the key into partitions spilled to
+/// temporary files, so that only the old records of one partition are held
+/// in memory at a time.
+#[derive(Debug)]
+pub(crate) struct Diff {
+    key: Vec<JsonPath>,
+    partitions: usize,
+    /// Bytes the old records of a partition may take
+    memory: u64,
+}
+
+/// Decodes one input line into json
+pub(crate) type Decode<'a> = dyn FnMut(&str) -> Result<String> + 'a;
+
+impl Diff {
+    /// Key paths are a comma-separated list of jq paths. Partitions whose
+    /// old records take more than `memory` bytes are split further.
+    pub(crate) fn new(key: &str, partitions: usize, memory: u64) -> Result<Diff> {
+        Ok(Diff { key: parse_path_list(key)?, partitions: partitions.clamp(1, MAX_FAN_OUT), memory: memory.max(1) })
+    }
+
+    /// Writes a line for each added, removed or changed record. Changes
+    /// are RFC 6902 json patches from the old record to the new one.
+    pub(crate) fn diff(&self,
+                       old: (&str, impl BufRead),
+                       new: (&str, impl BufRead),
+                       decode: &mut Decode<'_>,
+                       output: &mut impl Write) -> Result<DiffCounts> {
+        let mut counts = DiffCounts::default();
+        let old_partitions = self.spill(old, decode)?;
+        let new_partitions = self.spill(new, decode)?;
+        for (old_partition, new_partition) in old_partitions.into_iter().zip(new_partitions) {
+            self.compare(old_partition, new_partition, u64::MAX, 1, &mut counts, output)?;
+        }
+        Ok(counts)
+    }
+
+    /// Decodes each line of the input, passing on its key and record.
+    /// Records that can't be decoded or have no key are reported and
+    /// skipped.
+    fn read(&self,
+            (name, input): (&str, impl BufRead),
+            decode: &mut Decode<'_>,
+            mut f: impl FnMut(String, Value) -> Result<()>) -> Result<()> {
+        for (index, next_line) in input.lines().enumerate() {
+            let line_num = index + 1;
+            let result = next_line.map_err(Error::from)
+                .and_then(|line| decode(&line))
+                .and_then(|json| self.keyed(&json));
+            match result {
+                Ok((key, record)) => f(key, record)?,
+                Err(error) => {
+                    let is_fatal = error.is_fatal();
+                    let error = Error::with_chain(error, ErrorKind::FileLineNo(name.to_owned(), line_num, is_fatal));
+                    if is_fatal {
+                        return Err(error);
+                    }
+                    report_error(&error);
+                },
+            }
+        }
+        Ok(())
+    }
+
+    fn keyed(&self, json: &str) -> Result<(String, Value)> {
+        let record: Value = serde_json::from_str(json)?;
+        let values: Vec<&Value> = self.key.iter()
+            .map(|path| path.get(&record).unwrap_or(&Value::Null))
+            .collect();
+        if values.iter().all(|value| value.is_null()) {
+            let paths: Vec<String> = self.key.iter().map(JsonPath::to_string).collect();
+            bail!(ErrorKind::MissingKey(paths.join(",")));
+        }
+        let key = if values.len() == 1 {
+            serde_json::to_string(values[0])?
+        } else {
+            serde_json::to_string(&values)?
+        };
+        Ok((key, record))
+    }
+
+    fn spill(&self, input: (&str, impl BufRead), decode: &mut Decode<'_>) -> Result<Vec<File>> {
+        let mut partitions = Partitions::new(self.partitions, 0)?;
+        self.read(input, decode, |key, record| partitions.write(&key, &record.to_string()))?;
+        partitions.finish()
+    }
+
+    /// Compares the records of a partition, splitting it first if its old
+    /// records take more than the memory budget. A partition that the last
+    /// split didn't make any smaller, such as one with a single large
+    /// record, is compared as it is.
+    fn compare(&self,
+               old: File,
+               new: File,
+               parent_size: u64,
+               level: u64,
+               counts: &mut DiffCounts,
+               output: &mut impl Write) -> Result<()> {
+        let size = old.metadata()?.len();
+        if size > self.memory && size < parent_size {
+            let count = partitions_for(size, self.memory).min(MAX_FAN_OUT);
+            let old_partitions = split(old, count, level)?;
+            let new_partitions = split(new, count, level)?;
+            for (old_partition, new_partition) in old_partitions.into_iter().zip(new_partitions) {
+                self.compare(old_partition, new_partition, size, level + 1, counts, output)?;
+            }
+            return Ok(());
+        }
+
+        let mut old_records = HashMap::new();
+        for entry in read_spilled(old) {
+            let (key, record) = entry?;
+            if old_records.contains_key(&key) {
+                let key_value: Value = serde_json::from_str(&key)?;
+                report_notice(Notice::Warning, "RepeatedKey",
+                              &format!("key {} repeated on old records, ignoring it", key),
+                              json!({"key": key_value}));
+                continue;
+            }
+            let _ = old_records.insert(key, record);
+        }
+        let mut changes = Changes { old_records, seen: HashSet::new(), counts };
+        for entry in read_spilled(new) {
+            let (key, record) = entry?;
+            changes.compare(key, record, output)?;
+        }
+        changes.finish(output)
+    }
+}
+
+/// Temporary files that keyed records are spread over by a hash of the key
+#[derive(Debug)]
+struct Partitions {
+    files: Vec<BufWriter<File>>,
+    /// Seeds the hash, so that records of a partition split again spread
+    /// over all of the new ones
+    level: u64,
+}
+
+impl Partitions {
+    fn new(count: usize, level: u64) -> Result<Partitions> {
+        let files = (0..count)
+            .map(|_| ::tempfile::tempfile().map(BufWriter::new))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        Ok(Partitions { files, level })
+    }
+
+    fn write(&mut self, key: &str, record: &str) -> Result<()> {
+        let mut hasher = DefaultHasher::new();
+        self.level.hash(&mut hasher);
+        key.hash(&mut hasher);
+        let index = (hasher.finish() % self.files.len() as u64) as usize;
+        writeln!(self.files[index], "{}\t{}", key, record)?;
+        Ok(())
+    }
+
+    /// The files, rewound for reading
+    fn finish(self) -> Result<Vec<File>> {
+        self.files.into_iter()
+            .map(|partition| {
+                let mut file = partition.into_inner().map_err(|e| e.into_error())?;
+                let _ = file.seek(SeekFrom::Start(0))?;
+                Ok(file)
+            })
+            .collect()
+    }
+}
+
+/// Spreads the records of a spilled partition over `count` new ones
+fn split(file: File, count: usize, level: u64) -> Result<Vec<File>> {
+    let mut partitions = Partitions::new(count, level)?;
+    for entry in read_spilled(file) {
+        let (key, record) = entry?;
+        partitions.write(&key, &record)?;
+    }
+    partitions.finish()
+}
+
+/// Old records not yet matched, and the keys of the new records seen.
+/// Records are kept as json text, which takes less memory than values.
+#[derive(Debug)]
+struct Changes<'a> {
+    old_records: HashMap<String, String>,
+    seen: HashSet<String>,
+    counts: &'a mut DiffCounts,
+}
+
+impl Changes<'_> {
+    fn compare(&mut self, key: String, record: String, output: &mut impl Write) -> Result<()> {
+        let key_value: Value = serde_json::from_str(&key)?;
+        if !self.seen.insert(key.clone()) {
+            report_notice(Notice::Warning, "RepeatedKey", &format!("key {} repeated on new records, ignoring it", key),
+                          json!({"key": key_value}));
+            return Ok(());
+        }
+        let old_record = match self.old_records.remove(&key) {
+            Some(ref old_record) if *old_record == record => {
+                self.counts.unchanged += 1;
+                return Ok(());
+            },
+            Some(old_record) => serde_json::from_str::<Value>(&old_record)?,
+            None => {
+                self.counts.added += 1;
+                let record: Value = serde_json::from_str(&record)?;
+                writeln!(output, "{}", json!({"op": "added", "key": key_value, "record": record}))?;
+                return Ok(());
+            },
+        };
+        // Equal records can still differ in the order of their fields
+        let record: Value = serde_json::from_str(&record)?;
+        if old_record == record {
+            self.counts.unchanged += 1;
+        } else {
+            self.counts.changed += 1;
+            let patch = json_patch(&old_record, &record);
+            writeln!(output, "{}", json!({"op": "changed", "key": key_value, "patch": patch}))?;
+        }
+        Ok(())
+    }
+
+    /// Old records left unmatched were removed, output in order of key
+    fn finish(self, output: &mut impl Write) -> Result<()> {
+        let mut removed: Vec<(String, String)> = self.old_records.into_iter().collect();
+        removed.sort();
+        for (key, record) in removed {
+            self.counts.removed += 1;
+            let key_value: Value = serde_json::from_str(&key)?;
+            let record: Value = serde_json::from_str(&record)?;
+            writeln!(output, "{}", json!({"op": "removed", "key": key_value, "record": record}))?;
+        }
+        Ok(())
+    }
+}
+
+/// Keys and records of a spilled partition. Keys are json, so they have
+/// no tabs of their own.
+fn read_spilled(file: File) -> impl Iterator<Item = Result<(String, String)>> {
+    BufReader::new(file).lines().map(|line| {
+        let mut line = line?;
+        let tab = line.find('\t').unwrap_or(0);
+        let record = line.split_off(tab + 1);
+        line.truncate(tab);
+        Ok((line, record))
+    })
+}
+
+/// Number of partitions needed so that each one takes about `budget`
+/// bytes of input
+pub(crate) fn partitions_for(input_bytes: u64, budget: u64) -> usize {
+    (input_bytes / budget.max(1) + 1) as usize
+}
+
+/// RFC 6902 json patch that turns `old` into `new`
+pub(crate) fn json_patch(old: &Value, new: &Value) -> Vec<Value> {
+    let mut patch = Vec::new();
+    diff_values(&mut patch, String::new(), old, new);
+    patch
+}
+
+fn diff_values(patch: &mut Vec<Value>, path: String, old: &Value, new: &Value) {
+    match (old, new) {
+        _ if old == new => (),
+        (Value::Object(old), Value::Object(new)) => diff_objects(patch, path, old, new),
+        (Value::Array(old), Value::Array(new)) => {
+            let common = old.len().min(new.len());
+            for i in 0..common {
+                diff_values(patch, format!("{}/{}", path, i), &old[i], &new[i]);
+            }
+            for i in (common..old.len()).rev() {
+                patch.push(json!({"op": "remove", "path": format!("{}/{}", path, i)}));
+            }
+            for (i, value) in new.iter().enumerate().skip(common) {
+                patch.push(json!({"op": "add", "path": format!("{}/{}", path, i), "value": value}));
+            }
+        },
+        _ => patch.push(json!({"op": "replace", "path": path, "value": new})),
+    }
+}
+
+fn diff_objects(patch: &mut Vec<Value>, path: String, old: &Map<String, Value>, new: &Map<String, Value>) {
+    for (key, old_value) in old {
+        let key_path = format!("{}/{}", path, escape_pointer(key));
+        match new.get(key) {
+            Some(new_value) => diff_values(patch, key_path, old_value, new_value),
+            None => patch.push(json!({"op": "remove", "path": key_path})),
+        }
+    }
+    for (key, new_value) in new.iter().filter(|(key, _)| !old.contains_key(*key)) {
+        let key_path = format!("{}/{}", path, escape_pointer(key));
+        patch.push(json!({"op": "add", "path": key_path, "value": new_value}));
+    }
+}
+
+/// Escapes a key for a json pointer (RFC 6901)
+fn escape_pointer(key: &str) -> String {
+    key.replace('~', "~0").replace('/', "~1")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::assert_matches::assert_matches;
+    use std::io::Cursor;
+
+    const OLD: &str = r#"{"pk":"a","v":1}
+{"pk":"b","v":2}
+{"pk":"c","v":3}
+not json"#;
+
+    const NEW: &str = r#"{"pk":"b","v":2}
+{"pk":"c","v":4,"w":[1]}
+{"v":5}
+{"pk":"d","v":5}"#;
+
+    fn diff(partitions: usize, memory: u64, old: &str, new: &str) -> (DiffCounts, Vec<Value>) {
+        let diff = Diff::new(".pk", partitions, memory).unwrap();
+        let mut output = Vec::new();
+        let decode = &mut |line: &str| -> Result<String> {
+            serde_json::from_str::<Value>(line)
+                .map(|json| json.to_string())
+                .chain_err(|| ErrorKind::JqParseError("decoding".to_owned(), line.to_owned()))
+        };
+        let counts = diff.diff(("old", Cursor::new(old)), ("new", Cursor::new(new)), decode, &mut output).unwrap();
+        let lines: Vec<Value> = String::from_utf8(output).unwrap().lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        (counts, lines)
+    }
+
+    #[test]
+    fn test_diff() {
+        // Records take about 20 bytes, so 16 splits partitions down to one each
+        for &(partitions, memory) in &[(1, 1 << 20), (4, 1 << 20), (1, 16), (2, 40)] {
+            let (counts, mut lines) = diff(partitions, memory, OLD, NEW);
+            lines.sort_by_key(|line| line["key"].to_string());
+            assert_eq!(counts, DiffCounts { added: 1, removed: 1, changed: 1, unchanged: 1 });
+            assert_eq!(lines, vec![
+                json!({"op": "removed", "key": "a", "record": {"pk": "a", "v": 1}}),
+                json!({"op": "changed", "key": "c", "patch": [
+                    {"op": "replace", "path": "/v", "value": 4},
+                    {"op": "add", "path": "/w", "value": [1]},
+                ]}),
+                json!({"op": "added", "key": "d", "record": {"pk": "d", "v": 5}}),
+            ]);
+        }
+    }
+
+    #[test]
+    fn test_diff_removed_order() {
+        let old = r#"{"pk":"c","v":1}
+{"pk":"a","v":1}
+{"pk":"b","v":1}"#;
+        let (counts, lines) = diff(1, 1 << 20, old, r#"{"v":1,"pk":"b"}"#);
+        assert_eq!(counts, DiffCounts { added: 0, removed: 2, changed: 0, unchanged: 1 });
+        let keys: Vec<&Value> = lines.iter().map(|line| &line["key"]).collect();
+        assert_eq!(keys, vec!["a", "c"]);
+    }
+
+    #[test]
+    fn test_diff_repeated_keys() {
+        let old = r#"{"pk":"a","v":1}
+{"pk":"a","v":2}"#;
+        let new = r#"{"pk":"a","v":1}
+{"pk":"a","v":3}"#;
+        for &(partitions, memory) in &[(1, 1 << 20), (2, 16)] {
+            let (counts, lines) = diff(partitions, memory, old, new);
+            assert_eq!(counts, DiffCounts { added: 0, removed: 0, changed: 0, unchanged: 1 });
+            assert!(lines.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_json_patch() {
+        let old = json!({"a": [1, 2, 3], "b": {"c/d": 1, "e~": 2}, "f": 1});
+        let new = json!({"a": [1, 5], "b": {"c/d": 2}, "g": null});
+        assert_eq!(json_patch(&old, &new), vec![
+            json!({"op": "replace", "path": "/a/1", "value": 5}),
+            json!({"op": "remove", "path": "/a/2"}),
+            json!({"op": "replace", "path": "/b/c~1d", "value": 2}),
+            json!({"op": "remove", "path": "/b/e~0"}),
+            json!({"op": "remove", "path": "/f"}),
+            json!({"op": "add", "path": "/g", "value": null}),
+        ]);
+        assert_eq!(json_patch(&json!([1]), &json!([1, 2, 3])), vec![
+            json!({"op": "add", "path": "/1", "value": 2}),
+            json!({"op": "add", "path": "/2", "value": 3}),
+        ]);
+        assert_eq!(json_patch(&json!(1), &json!("x")), vec![json!({"op": "replace", "path": "", "value": "x"})]);
+        assert!(json_patch(&old, &old).is_empty());
+    }
+
+    #[test]
+    fn test_missing_key() {
+        let diff = Diff::new(".pk", 1, 1 << 20).unwrap();
+        let result = diff.keyed(r#"{"v":1}"#);
+        assert_matches!(result, Err(Error(ErrorKind::MissingKey(_), _)));
+        assert_matches!(result, Err(ref error) if !error.is_fatal())
+    }
+
+    #[test]
+    fn test_partitions_for() {
+        assert_eq!(partitions_for(0, 100), 1);
+        assert_eq!(partitions_for(99, 100), 1);
+        assert_eq!(partitions_for(250, 100), 3);
+    }
+}
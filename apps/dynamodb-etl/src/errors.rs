@@ -1,3 +1,7 @@
+// error_chain! implements the deprecated Error::description, as the flag
+// that would make it skip that is never set in this crate
+#![allow(deprecated)]
+
 use error_chain::error_chain;
 #[allow(unused_imports)]
 use error_chain::error_chain_processing;
@@ -8,6 +12,11 @@ use error_chain::impl_error_chain_kind;
 #[allow(unused_imports)]
 use error_chain::impl_extract_backtrace;
 
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+use ::serde_json::{self, json, Value};
+
 // Create the Error, ErrorKind, ResultExt, and Result types
 error_chain! {
 // Waiting on a new version of jq_rs (post 0.4.0)
@@ -15,8 +24,13 @@ error_chain! {
 //        Jq(jq_rs::Error);
 //    }
     foreign_links {
+        Arrow(::arrow::error::ArrowError);
+        Csv(::csv::Error);
         Fmt(::std::fmt::Error);
+        Json(::serde_json::Error);
         Io(::std::io::Error) #[cfg(unix)];
+        Parquet(::parquet::errors::ParquetError);
+        Sqlite(::rusqlite::Error);
     }
 
     // ErrorKind additional errors
@@ -36,9 +50,48 @@ error_chain! {
         JqError(when: String, d: String) {
             display("jq error {}: {}", when, d)
         }
+        InvalidPath(path: String, reason: String) {
+            display("Invalid path {}: {}", path, reason)
+        }
+        TimestampError(path: String, value: String) {
+            display("Error: value {} at {} is not a recognised timestamp", value, path)
+        }
+        ExplodeError(path: String, found: String) {
+            display("Error: value at {} is {}, not an array", path, found)
+        }
+        InvalidArguments(reason: String) {
+            display("Invalid arguments: {}", reason)
+        }
+        InvalidSchema(reason: String) {
+            display("Invalid schema: {}", reason)
+        }
+        SchemaMismatch(reason: String) {
+            display("Error: record does not fit the schema; {}", reason)
+        }
+        MissingKey(paths: String) {
+            display("Error: record has no value at {}", paths)
+        }
+        ErrorBudgetExceeded(reason: String) {
+            display("Too many errors: {}", reason)
+        }
+        InputError {
+            display("Error reading the input")
+        }
+        OutputError {
+            display("Error writing the output")
+        }
+        Strict {
+            display("Stopped at the first record error, in strict mode")
+        }
+        Interrupted(signal: String) {
+            display("Interrupted by {}", signal)
+        }
         LineNo(number: usize, is_fatal: bool) {
             display("Error processing record number {}", number)
         }
+        FileLineNo(file: String, number: usize, is_fatal: bool) {
+            display("Error processing record number {} of {}", number, file)
+        }
     }
 }
 
@@ -52,9 +105,266 @@ impl ErrorIsFatal for Error {
             ErrorKind::Base64Error => false,
             ErrorKind::GzipError => false,
             ErrorKind::JqParseError(_, _) => false,
+            ErrorKind::TimestampError(_, _) => false,
+            ErrorKind::ExplodeError(_, _) => false,
+            ErrorKind::SchemaMismatch(_) => false,
+            ErrorKind::MissingKey(_) => false,
             ErrorKind::LineNo(_, is_fatal) => is_fatal,
+            ErrorKind::FileLineNo(_, _, is_fatal) => is_fatal,
             ErrorKind::Io(ref err) if err.kind() == ::std::io::ErrorKind::InvalidData => false,
             _ => true
         }
     }
 }
+
+impl ErrorKind {
+    /// Name of the variant, for reports read by other programs
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            ErrorKind::Msg(_) => "Msg",
+            ErrorKind::Arrow(_) => "Arrow",
+            ErrorKind::Csv(_) => "Csv",
+            ErrorKind::Fmt(_) => "Fmt",
+            ErrorKind::Json(_) => "Json",
+            #[cfg(unix)]
+            ErrorKind::Io(_) => "Io",
+            ErrorKind::Parquet(_) => "Parquet",
+            ErrorKind::Sqlite(_) => "Sqlite",
+            ErrorKind::Base64Error => "Base64Error",
+            ErrorKind::GzipError => "GzipError",
+            ErrorKind::JqInvalidProgram(_) => "JqInvalidProgram",
+            ErrorKind::JqParseError(_, _) => "JqParseError",
+            ErrorKind::JqError(_, _) => "JqError",
+            ErrorKind::InvalidPath(_, _) => "InvalidPath",
+            ErrorKind::TimestampError(_, _) => "TimestampError",
+            ErrorKind::ExplodeError(_, _) => "ExplodeError",
+            ErrorKind::InvalidArguments(_) => "InvalidArguments",
+            ErrorKind::InvalidSchema(_) => "InvalidSchema",
+            ErrorKind::SchemaMismatch(_) => "SchemaMismatch",
+            ErrorKind::MissingKey(_) => "MissingKey",
+            ErrorKind::LineNo(_, _) => "LineNo",
+            ErrorKind::ErrorBudgetExceeded(_) => "ErrorBudgetExceeded",
+            ErrorKind::InputError => "InputError",
+            ErrorKind::OutputError => "OutputError",
+            ErrorKind::Strict => "Strict",
+            ErrorKind::Interrupted(_) => "Interrupted",
+            ErrorKind::FileLineNo(_, _, _) => "FileLineNo",
+            _ => "Unknown",
+        }
+    }
+}
+
+/// Kinds of the errors of this crate in the chain, from the outermost in
+pub(crate) fn chain_kinds(error: &Error) -> impl Iterator<Item = &ErrorKind> {
+    let mut next = Some(error);
+    std::iter::from_fn(move || {
+        let current = next?;
+        next = std::error::Error::source(current).and_then(|source| source.downcast_ref::<Error>());
+        Some(current.kind())
+    })
+}
+
+/// Kind of what went wrong, under the line number and strict mode context
+pub(crate) fn cause_kind(error: &Error) -> &ErrorKind {
+    chain_kinds(error)
+        .find(|kind| !matches!(kind, ErrorKind::LineNo(..) | ErrorKind::FileLineNo(..) | ErrorKind::Strict))
+        .unwrap_or_else(|| error.kind())
+}
+
+/// Line number of the record the error is about, if any
+pub(crate) fn line_number(error: &Error) -> Option<usize> {
+    chain_kinds(error).find_map(|kind| match kind {
+        ErrorKind::LineNo(number, _) | ErrorKind::FileLineNo(_, number, _) => Some(*number),
+        _ => None,
+    })
+}
+
+/// Exit codes of the process, which wrappers can rely on:
+///
+/// | code | meaning |
+/// |------|---------|
+/// | 0    | success, possibly with records skipped because of their errors |
+/// | 1    | any other error |
+/// | 2    | invalid arguments |
+/// | 3    | invalid jq program or path |
+/// | 4    | the input could not be read |
+/// | 5    | the output could not be written, such as a broken pipe |
+/// | 6    | the error budget was exceeded |
+/// | 130  | interrupted by SIGINT or SIGTERM |
+pub(crate) const EXIT_ERROR: i32 = 1;
+pub(crate) const EXIT_INVALID_ARGUMENTS: i32 = 2;
+pub(crate) const EXIT_INVALID_PROGRAM: i32 = 3;
+pub(crate) const EXIT_INPUT: i32 = 4;
+pub(crate) const EXIT_OUTPUT: i32 = 5;
+pub(crate) const EXIT_ERROR_BUDGET: i32 = 6;
+pub(crate) const EXIT_INTERRUPTED: i32 = 130;
+
+/// Exit code for an error that stopped processing. When the chain has
+/// more than one of the kinds, the code that comes first in this order
+/// wins: interrupted, error budget, arguments, jq, output, input.
+pub(crate) fn exit_code(error: &Error) -> i32 {
+    const PRIORITY: [i32; 6] = [EXIT_INTERRUPTED, EXIT_ERROR_BUDGET, EXIT_INVALID_ARGUMENTS, EXIT_INVALID_PROGRAM,
+                                EXIT_OUTPUT, EXIT_INPUT];
+    let codes: Vec<i32> = chain_kinds(error).filter_map(kind_exit_code).collect();
+    PRIORITY.iter().copied().find(|code| codes.contains(code)).unwrap_or(EXIT_ERROR)
+}
+
+fn kind_exit_code(kind: &ErrorKind) -> Option<i32> {
+    match kind {
+        ErrorKind::Interrupted(_) => Some(EXIT_INTERRUPTED),
+        ErrorKind::ErrorBudgetExceeded(_) => Some(EXIT_ERROR_BUDGET),
+        ErrorKind::InvalidArguments(_) => Some(EXIT_INVALID_ARGUMENTS),
+        ErrorKind::JqInvalidProgram(_) | ErrorKind::InvalidPath(_, _) => Some(EXIT_INVALID_PROGRAM),
+        ErrorKind::OutputError => Some(EXIT_OUTPUT),
+        ErrorKind::InputError => Some(EXIT_INPUT),
+        _ => None,
+    }
+}
+
+/// How errors are printed
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub(crate) enum ErrorFormat {
+    /// An "Error:" line, and a "caused by:" line per cause
+    Text,
+    /// A json object per line
+    Json,
+}
+
+impl FromStr for ErrorFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(ErrorFormat::Text),
+            "json" => Ok(ErrorFormat::Json),
+            _ => Err(format!("unknown error format '{}', expected text or json", s))
+        }
+    }
+}
+
+static ERROR_FORMAT: OnceLock<ErrorFormat> = OnceLock::new();
+
+/// Sets how errors are printed, once, before any is reported
+pub(crate) fn set_error_format(format: ErrorFormat) {
+    let _ = ERROR_FORMAT.set(format);
+}
+
+pub(crate) fn error_format() -> ErrorFormat {
+    ERROR_FORMAT.get().copied().unwrap_or(ErrorFormat::Text)
+}
+
+/// The error as a json object, with the kind of what went wrong, the
+/// record's line number and file, whether it stops processing, what jq was
+/// doing, and the messages of the whole chain
+pub(crate) fn error_json(error: &Error) -> Value {
+    let mut report = json!({
+        "error": cause_kind(error).name(),
+        "line": line_number(error),
+        "is_fatal": error.is_fatal(),
+        "when": chain_kinds(error).find_map(|kind| match kind {
+            ErrorKind::JqError(when, _) | ErrorKind::JqParseError(when, _) | ErrorKind::JqInvalidProgram(when) =>
+                Some(when.clone()),
+            _ => None,
+        }),
+        "messages": error.iter().map(ToString::to_string).collect::<Vec<String>>(),
+    });
+    if let Some(file) = chain_kinds(error).find_map(|kind| match kind {
+        ErrorKind::FileLineNo(file, _, _) => Some(file.clone()),
+        _ => None,
+    }) {
+        report["file"] = Value::String(file);
+    }
+    report
+}
+
+/// How much a notice about the run matters
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub(crate) enum Notice {
+    /// Counts and other results
+    Info,
+    /// Something that may not have gone as intended
+    Warning,
+}
+
+/// A notice as a json object, with the level and kind of the notice, its
+/// message and its details
+pub(crate) fn notice_json(notice: Notice, kind: &str, message: &str, details: Value) -> Value {
+    let level = match notice {
+        Notice::Info => "info",
+        Notice::Warning => "warning",
+    };
+    let mut report = serde_json::Map::new();
+    let _ = report.insert(level.to_owned(), json!(kind));
+    let _ = report.insert("message".to_owned(), json!(message));
+    if let Value::Object(details) = details {
+        report.extend(details);
+    }
+    Value::Object(report)
+}
+
+/// Prints a notice that isn't an error in the same format as errors
+pub(crate) fn report_notice(notice: Notice, kind: &str, message: &str, details: Value) {
+    match (error_format(), notice) {
+        (ErrorFormat::Text, Notice::Info) => eprintln!("{}", message),
+        (ErrorFormat::Text, Notice::Warning) => eprintln!("Warning: {}", message),
+        (ErrorFormat::Json, _) => eprintln!("{}", notice_json(notice, kind, message, details)),
+    }
+}
+
+/// Prints an error that doesn't stop processing, with its causes
+pub(crate) fn report_error(error: &Error) {
+    match error_format() {
+        ErrorFormat::Text => {
+            eprintln!("Error: {}", error);
+            for e in error.iter().skip(1) {
+                eprintln!("caused by: {}", e);
+            }
+        },
+        ErrorFormat::Json => eprintln!("{}", error_json(error)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_json() {
+        let error = Error::from(ErrorKind::JqParseError("updating text data".to_owned(), "bad".to_owned()))
+            .chain_err(|| ErrorKind::LineNo(7, false));
+        assert_eq!(error_json(&error), json!({
+            "error": "JqParseError",
+            "line": 7,
+            "is_fatal": false,
+            "when": "updating text data",
+            "messages": ["Error processing record number 7", "Error updating text data: data is not valid json; bad"],
+        }));
+
+        let error = Error::from(ErrorKind::MissingKey(".k".to_owned()))
+            .chain_err(|| ErrorKind::FileLineNo("old.jsonl".to_owned(), 2, true));
+        let report = error_json(&error);
+        assert_eq!((&report["error"], &report["is_fatal"], &report["file"], &report["when"]),
+                   (&json!("MissingKey"), &json!(true), &json!("old.jsonl"), &Value::Null));
+    }
+
+    #[test]
+    fn test_notice_json() {
+        assert_eq!(notice_json(Notice::Info, "DuplicatesDropped", "Dropped 2 duplicate records", json!({"dropped": 2})),
+                   json!({"info": "DuplicatesDropped", "message": "Dropped 2 duplicate records", "dropped": 2}));
+        assert_eq!(notice_json(Notice::Warning, "RepeatedKey", "key \"a\" repeated", json!({"key": "a"})),
+                   json!({"warning": "RepeatedKey", "message": "key \"a\" repeated", "key": "a"}));
+    }
+
+    #[test]
+    fn test_exit_code() {
+        let broken_pipe = ::std::io::Error::new(::std::io::ErrorKind::BrokenPipe, "closed");
+        assert_eq!(exit_code(&Error::with_chain(broken_pipe, ErrorKind::OutputError)), EXIT_OUTPUT);
+        let invalid = Error::from(ErrorKind::InvalidArguments("bad".to_owned())).chain_err(|| ErrorKind::OutputError);
+        assert_eq!(exit_code(&invalid), EXIT_INVALID_ARGUMENTS);
+        assert_eq!(exit_code(&Error::from(ErrorKind::JqInvalidProgram("compiling".to_owned()))), EXIT_INVALID_PROGRAM);
+        let input = Error::from(ErrorKind::InputError).chain_err(|| ErrorKind::LineNo(3, true));
+        assert_eq!(exit_code(&input), EXIT_INPUT);
+        assert_eq!(exit_code(&Error::from(ErrorKind::Interrupted("SIGTERM".to_owned()))), EXIT_INTERRUPTED);
+        assert_eq!(exit_code(&Error::from("other")), EXIT_ERROR);
+    }
+}
@@ -0,0 +1,90 @@
+use ::error_chain::bail;
+use ::serde_json::Value;
+
+use crate::errors::*;
+use crate::json_queries::{type_name, JsonPath};
+
+/// Splits a record into one record per element of an array. Each record
+/// is a copy of the original with the array replaced by the element, and
+/// the element index on a top-level field.
+///
+/// As with jq's `.items[]`, empty arrays produce no records. Records where
+/// the path is missing or null are kept as they are.
+#[derive(Debug)]
+pub(crate) struct Explode {
+    path: JsonPath,
+    index_field: String,
+}
+
+impl Explode {
+    /// The path must end in `[]`, as in `.items[]`
+    pub(crate) fn new(path: &str, index_field: &str) -> Result<Explode> {
+        let array_path = match path.trim().trim_end_matches("[]") {
+            trimmed if trimmed.len() < path.trim().len() => trimmed,
+            _ => bail!(ErrorKind::InvalidPath(path.to_owned(), "explode path must end in []".to_owned())),
+        };
+        let path = JsonPath::new(if array_path.is_empty() { "." } else { array_path })?;
+        Ok(Explode { path, index_field: index_field.to_owned() })
+    }
+
+    pub(crate) fn explode(&self, mut json: Value) -> Result<Vec<Value>> {
+        let elements = match self.path.get_mut(&mut json) {
+            Some(Value::Array(elements)) => std::mem::take(elements),
+            None | Some(Value::Null) => return Ok(vec![json]),
+            Some(other) => bail!(ErrorKind::ExplodeError(self.path.to_string(), type_name(other).to_owned())),
+        };
+        if !json.is_object() {
+            bail!(ErrorKind::ExplodeError(self.path.to_string(), "inside a non-object record".to_owned()));
+        }
+        let records = elements.into_iter().enumerate()
+            .map(|(index, element)| {
+                let mut record = json.clone();
+                let _ = self.path.set(&mut record, element);
+                if let Value::Object(ref mut map) = record {
+                    let _ = map.insert(self.index_field.clone(), Value::from(index));
+                }
+                record
+            })
+            .collect();
+        Ok(records)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::assert_matches::assert_matches;
+    use ::serde_json::json;
+
+    #[test]
+    fn test_explode() {
+        let explode = Explode::new(".data.items[]", "_index").unwrap();
+        let json = json!({"id": 1, "data": {"items": [{"a": 1}, {"a": 2}], "name": "x"}});
+        let result = explode.explode(json);
+        assert_matches!(result, Ok(ref actual) if actual == &vec![
+            json!({"id": 1, "data": {"items": {"a": 1}, "name": "x"}, "_index": 0}),
+            json!({"id": 1, "data": {"items": {"a": 2}, "name": "x"}, "_index": 1}),
+        ]);
+    }
+
+    #[test]
+    fn test_explode_empty_or_missing() {
+        let explode = Explode::new(".items[]", "_index").unwrap();
+        assert_matches!(explode.explode(json!({"items": []})), Ok(ref actual) if actual.is_empty());
+        assert_matches!(explode.explode(json!({"id": 1})), Ok(ref actual) if actual == &vec![json!({"id": 1})]);
+    }
+
+    #[test]
+    fn test_explode_not_an_array() {
+        let explode = Explode::new(".items[]", "_index").unwrap();
+        let result = explode.explode(json!({"items": "many"}));
+        assert_matches!(result, Err(Error(ErrorKind::ExplodeError(_, _), _)));
+        assert_matches!(result, Err(ref error) if !error.is_fatal())
+    }
+
+    #[test]
+    fn test_explode_invalid_path() {
+        assert_matches!(Explode::new(".items", "_index"), Err(Error(ErrorKind::InvalidPath(_, _), _)));
+        assert_matches!(Explode::new(".items[].sub[]", "_index"), Err(Error(ErrorKind::InvalidPath(_, _), _)));
+    }
+}
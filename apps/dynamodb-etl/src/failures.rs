@@ -0,0 +1,186 @@
+use std::collections::{HashMap, VecDeque};
+
+use ::error_chain::bail;
+
+use crate::dead_letter::DeadLetter;
+use crate::errors::*;
+
+/// Error kinds listed when the budget is exceeded
+const TOP_KINDS: usize = 5;
+
+/// How many records can fail before the run is aborted
+#[derive(Clone,Copy,Debug,Default)]
+pub(crate) struct ErrorBudget {
+    /// Most failed records in the whole run
+    pub(crate) max_errors: Option<u64>,
+    /// Highest fraction of failed records among the last `window` ones
+    pub(crate) max_rate: Option<f64>,
+    pub(crate) window: usize,
+}
+
+pub(crate) fn parse_error_rate(rate: &str) -> std::result::Result<f64, String> {
+    match rate.parse::<f64>() {
+        Ok(rate) if (0.0..1.0).contains(&rate) => Ok(rate),
+        _ => Err(format!("error rate must be at least 0 and less than 1, got '{}'", rate)),
+    }
+}
+
+/// What happens to records that fail without stopping the processing
+#[derive(Debug,Default)]
+pub(crate) struct Failures {
+    dead_letter: Option<DeadLetter>,
+    budget: ErrorBudget,
+    /// Whether the first failure stops processing
+    strict: bool,
+    errors: u64,
+    kinds: HashMap<&'static str, u64>,
+    /// Whether each of the last records failed, if there's a maximum rate
+    window: VecDeque<bool>,
+    window_errors: usize,
+}
+
+impl Failures {
+    pub(crate) fn new(dead_letter: Option<DeadLetter>, budget: ErrorBudget, strict: bool) -> Failures {
+        Failures { dead_letter, budget, strict, ..Failures::default() }
+    }
+
+    /// Whether failures need the input line
+    pub(crate) fn keeps_lines(&self) -> bool {
+        self.dead_letter.is_some()
+    }
+
+    /// Failed records by kind of error
+    pub(crate) fn kinds(&self) -> impl Iterator<Item = (&'static str, u64)> + '_ {
+        self.kinds.iter().map(|(kind, count)| (*kind, *count))
+    }
+
+    /// Counts a record that went through
+    pub(crate) fn succeed(&mut self) {
+        self.observe(false);
+    }
+
+    /// Reports the error, and sends the input line to the dead letters.
+    /// Fails if that was one error too many, or with the error itself in
+    /// strict mode.
+    pub(crate) fn fail(&mut self, error: Error, line: Option<&str>) -> Result<()> {
+        if !self.strict {
+            report_error(&error);
+        }
+        if let Some(ref mut dead_letter) = self.dead_letter {
+            dead_letter.write(line, &error).chain_err(|| ErrorKind::OutputError)?;
+        }
+        self.errors += 1;
+        *self.kinds.entry(cause_kind(&error).name()).or_insert(0) += 1;
+        if self.strict {
+            return Err(Error::with_chain(error, ErrorKind::Strict));
+        }
+        self.observe(true);
+        self.check(false)
+    }
+
+    /// Runs shorter than the error window are held to the maximum rate
+    /// over all their records
+    pub(crate) fn finish(&mut self) -> Result<()> {
+        if let Some(ref mut dead_letter) = self.dead_letter {
+            dead_letter.finish().chain_err(|| ErrorKind::OutputError)?;
+        }
+        self.check(true)
+    }
+
+    fn observe(&mut self, failed: bool) {
+        if self.budget.max_rate.is_none() {
+            return;
+        }
+        self.window.push_back(failed);
+        self.window_errors += failed as usize;
+        if self.window.len() > self.budget.window.max(1) && self.window.pop_front() == Some(true) {
+            self.window_errors -= 1;
+        }
+    }
+
+    fn check(&self, finished: bool) -> Result<()> {
+        if let Some(max_errors) = self.budget.max_errors {
+            if self.errors > max_errors {
+                bail!(ErrorKind::ErrorBudgetExceeded(format!("{} records failed, more than the maximum of {}; {}",
+                                                             self.errors, max_errors, self.top_kinds())));
+            }
+        }
+        if let Some(max_rate) = self.budget.max_rate {
+            let records = self.window.len();
+            let full = records >= self.budget.window || (finished && records > 0);
+            if full && self.window_errors as f64 / records as f64 > max_rate {
+                bail!(ErrorKind::ErrorBudgetExceeded(format!("{} of the last {} records failed, more than the \
+                                                              maximum rate of {}; {}", self.window_errors, records,
+                                                             max_rate, self.top_kinds())));
+            }
+        }
+        Ok(())
+    }
+
+    /// The most common kinds of error, such as "GzipError 950, JqParseError 40"
+    fn top_kinds(&self) -> String {
+        let mut kinds: Vec<(&str, u64)> = self.kinds.iter().map(|(kind, count)| (*kind, *count)).collect();
+        kinds.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        let top: Vec<String> = kinds.iter().take(TOP_KINDS).map(|(kind, count)| format!("{} {}", kind, count)).collect();
+        format!("most common errors: {}", top.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::assert_matches::assert_matches;
+
+    fn error(kind: ErrorKind) -> Error {
+        Error::from(kind).chain_err(|| ErrorKind::LineNo(1, false))
+    }
+
+    #[test]
+    fn test_max_errors() {
+        let mut failures = Failures::new(None, ErrorBudget { max_errors: Some(2), ..ErrorBudget::default() }, false);
+        assert_matches!(failures.fail(error(ErrorKind::GzipError), None), Ok(()));
+        assert_matches!(failures.fail(error(ErrorKind::Base64Error), None), Ok(()));
+        match failures.fail(error(ErrorKind::GzipError), None) {
+            Err(Error(ErrorKind::ErrorBudgetExceeded(reason), _)) =>
+                assert_eq!(reason, "3 records failed, more than the maximum of 2; most common errors: GzipError 2, Base64Error 1"),
+            other => panic!("expected the budget to be exceeded, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_max_error_rate() {
+        let budget = ErrorBudget { max_errors: None, max_rate: Some(0.5), window: 4 };
+        let mut failures = Failures::new(None, budget, false);
+        assert_matches!(failures.fail(error(ErrorKind::GzipError), None), Ok(()));
+        assert_matches!(failures.fail(error(ErrorKind::GzipError), None), Ok(()));
+        failures.succeed();
+        failures.succeed();
+        assert_matches!(failures.fail(error(ErrorKind::GzipError), None), Ok(()));
+        failures.succeed();
+        failures.succeed();
+        assert_matches!(failures.fail(error(ErrorKind::GzipError), None), Ok(()));
+        assert_matches!(failures.fail(error(ErrorKind::GzipError), None), Ok(()));
+        assert_matches!(failures.fail(error(ErrorKind::GzipError), None),
+                        Err(Error(ErrorKind::ErrorBudgetExceeded(_), _)));
+    }
+
+    #[test]
+    fn test_strict() {
+        let mut failures = Failures::new(None, ErrorBudget::default(), true);
+        let failed = failures.fail(error(ErrorKind::GzipError), None).unwrap_err();
+        assert_matches!(failed.kind(), ErrorKind::Strict);
+        assert!(failed.is_fatal());
+        assert_eq!(line_number(&failed), Some(1));
+        assert_matches!(cause_kind(&failed), ErrorKind::GzipError);
+    }
+
+    #[test]
+    fn test_short_run_rate() {
+        let budget = ErrorBudget { max_errors: None, max_rate: Some(0.5), window: 1000 };
+        let mut failures = Failures::new(None, budget, false);
+        assert_matches!(failures.fail(error(ErrorKind::GzipError), None), Ok(()));
+        assert_matches!(failures.finish(), Err(Error(ErrorKind::ErrorBudgetExceeded(_), _)));
+        assert!(parse_error_rate("1").is_err());
+        assert_eq!(parse_error_rate("0"), Ok(0.0));
+    }
+}
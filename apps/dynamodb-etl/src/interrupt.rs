@@ -0,0 +1,189 @@
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, LazyLock};
+
+use ::error_chain::bail;
+use ::serde_json::{self, json, Value};
+use ::signal_hook::consts::{SIGINT, SIGTERM};
+use ::signal_hook::flag;
+
+use crate::errors::*;
+
+/// Whether a termination signal was received
+static TERMINATING: LazyLock<Arc<AtomicBool>> = LazyLock::new(Arc::default);
+/// The termination signal received, or 0
+static SIGNAL: LazyLock<Arc<AtomicUsize>> = LazyLock::new(Arc::default);
+
+/// Makes SIGINT and SIGTERM stop processing after the current record, or
+/// while waiting for input. A second signal exits straight away, as when
+/// finishing the output takes too long.
+pub(crate) fn catch_termination() -> Result<()> {
+    for signal in [SIGINT, SIGTERM] {
+        // Registered first, so that it only sees an earlier signal
+        let _ = flag::register_conditional_shutdown(signal, EXIT_INTERRUPTED, TERMINATING.clone())?;
+        let _ = flag::register(signal, TERMINATING.clone())?;
+        let _ = flag::register_usize(signal, SIGNAL.clone(), signal as usize)?;
+        interrupt_reads(signal)?;
+    }
+    Ok(())
+}
+
+/// signal-hook installs its handler with SA_RESTART, which would keep a
+/// read waiting for input until more arrives, so that flag is cleared
+fn interrupt_reads(signal: libc::c_int) -> io::Result<()> {
+    // SAFETY: the action installed is the one read back, with only the
+    // flag changed
+    let changed = unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        match libc::sigaction(signal, std::ptr::null(), &mut action) {
+            0 => {
+                action.sa_flags &= !libc::SA_RESTART;
+                libc::sigaction(signal, &action, std::ptr::null_mut())
+            },
+            failed => failed,
+        }
+    };
+    match changed {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
+
+/// Name of the termination signal received so far, if any
+pub(crate) fn termination_signal() -> Option<&'static str> {
+    match SIGNAL.load(Ordering::SeqCst) as libc::c_int {
+        0 => None,
+        SIGINT => Some("SIGINT"),
+        SIGTERM => Some("SIGTERM"),
+        _ => Some("a signal"),
+    }
+}
+
+/// Reads that a termination signal cut short, which unlike EINTR
+/// `BufRead` doesn't retry
+#[derive(Debug)]
+struct Terminated;
+
+impl std::fmt::Display for Terminated {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "read interrupted by a termination signal")
+    }
+}
+
+impl std::error::Error for Terminated {}
+
+/// Input whose reads stop when a termination signal arrives while they
+/// wait, and are retried after any other signal
+#[derive(Debug)]
+pub(crate) struct Interruptible<R>(pub(crate) R);
+
+impl<R: Read> Read for Interruptible<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.0.read(buf) {
+                Err(ref error) if error.kind() == io::ErrorKind::Interrupted => if termination_signal().is_some() {
+                    return Err(io::Error::other(Terminated));
+                },
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Whether a read failed because of a termination signal
+pub(crate) fn is_terminated(error: &io::Error) -> bool {
+    error.get_ref().is_some_and(|error| error.is::<Terminated>())
+}
+
+/// Where processing of the input stopped, so that a later run on the same
+/// input can carry on from there
+#[derive(Clone,Copy,Debug,Default,PartialEq)]
+pub(crate) struct Checkpoint {
+    /// Input lines already processed
+    pub(crate) line: usize,
+    /// Bytes of input those lines took
+    pub(crate) offset: u64,
+}
+
+impl Checkpoint {
+    /// The checkpoint saved in the file, or the start of the input if
+    /// there's no file
+    pub(crate) fn load(path: &Path) -> Result<Checkpoint> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(Checkpoint::default()),
+            Err(error) => return Err(error.into()),
+        };
+        let checkpoint: Value = serde_json::from_str(&text)?;
+        match (checkpoint["line"].as_u64(), checkpoint["offset"].as_u64()) {
+            (Some(line), Some(offset)) => Ok(Checkpoint { line: line as usize, offset }),
+            _ => bail!(ErrorKind::InvalidArguments(format!("checkpoint {} has no line and offset", path.display()))),
+        }
+    }
+
+    pub(crate) fn save(&self, path: &Path) -> Result<()> {
+        let name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+        let temp = path.with_file_name(format!(".{}.tmp", name));
+        fs::write(&temp, json!({"line": self.line, "offset": self.offset}).to_string() + "\n")?;
+        fs::rename(&temp, path)?;
+        Ok(())
+    }
+
+    /// Reads past the part of the input that was already processed
+    pub(crate) fn skip(&self, input: &mut impl Read) -> Result<()> {
+        let skipped = io::copy(&mut input.by_ref().take(self.offset), &mut io::sink())?;
+        if skipped < self.offset {
+            bail!(ErrorKind::InvalidArguments(format!("input ends at byte {}, before the checkpoint at byte {}",
+                                                      skipped, self.offset)));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, Cursor};
+    use ::assert_matches::assert_matches;
+
+    /// Fails its first read as if a signal came, then reads from a string
+    struct Signalled(bool, Cursor<&'static str>);
+
+    impl Read for Signalled {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match std::mem::replace(&mut self.0, false) {
+                true => Err(io::ErrorKind::Interrupted.into()),
+                false => self.1.read(buf),
+            }
+        }
+    }
+
+    #[test]
+    fn test_interruptible() {
+        // Other tests run processing, so this one can't receive a signal
+        let mut text = String::new();
+        let _ = Interruptible(Signalled(true, Cursor::new("one\n"))).read_to_string(&mut text).unwrap();
+        assert_eq!(text, "one\n");
+        assert!(is_terminated(&io::Error::other(Terminated)));
+        assert!(!is_terminated(&io::ErrorKind::Interrupted.into()));
+    }
+
+    #[test]
+    fn test_checkpoint() {
+        let directory = ::tempfile::tempdir().unwrap();
+        let path = directory.path().join("checkpoint.json");
+        assert_eq!(Checkpoint::load(&path).unwrap(), Checkpoint::default());
+
+        let checkpoint = Checkpoint { line: 2, offset: 8 };
+        checkpoint.save(&path).unwrap();
+        assert_eq!(Checkpoint::load(&path).unwrap(), checkpoint);
+
+        let mut input = Cursor::new("one\ntwo\nthree\n");
+        checkpoint.skip(&mut input).unwrap();
+        assert_eq!(input.lines().next().unwrap().unwrap(), "three");
+        assert_matches!(Checkpoint { line: 9, offset: 100 }.skip(&mut Cursor::new("short\n")),
+                        Err(Error(ErrorKind::InvalidArguments(_), _)));
+    }
+}
@@ -1,7 +1,9 @@
 use std::fmt::Formatter;
 
+use ::error_chain::bail;
 use ::jq_rs;
 use ::jq_rs::JqProgram;
+use ::serde_json::{self, Value};
 
 use crate::errors::*;
 
@@ -35,7 +37,7 @@ impl Queries {
     }
 
     pub(crate) fn update(&mut self, json: &str) -> Result<String> {
-        self.update.run(&json)
+        self.update.run(json)
             .map_err(|e| e.to_error("updating text data"))
             .map(|result| raw_output(&result))
     }
@@ -47,6 +49,147 @@ impl std::fmt::Debug for Queries {
     }
 }
 
+/// A jq path resolved to its components, so that it can be applied to
+/// already parsed json without going through jq again.
+///
+/// The path expression must produce exactly one path, as in
+/// `jq -n '[path(.x.y.z)]'`.
+#[derive(Clone,PartialEq)]
+pub(crate) struct JsonPath {
+    segments: Vec<Value>,
+    desc: String
+}
+
+impl JsonPath {
+    pub(crate) fn new(path: &str) -> Result<JsonPath> {
+        let query = format!("[path({path})]", path = path);
+        let result = jq_rs::run(&query, "null").map_err(|e| match e {
+            jq_rs::Error::InvalidProgram => e.to_error("compiling path"),
+            _ => ErrorKind::InvalidPath(path.to_owned(), e.to_string()).into()
+        })?;
+        let mut paths: Vec<Vec<Value>> = serde_json::from_str(&result)
+            .map_err(|e| ErrorKind::InvalidPath(path.to_owned(), e.to_string()))?;
+        if paths.len() != 1 {
+            bail!(ErrorKind::InvalidPath(path.to_owned(),
+                                         format!("expected one path, got {}", paths.len())));
+        }
+        let segments = paths.remove(0);
+        if let Some(segment) = segments.iter().find(|s| !s.is_string() && !s.is_i64()) {
+            bail!(ErrorKind::InvalidPath(path.to_owned(),
+                                         format!("unsupported path component {}", segment)));
+        }
+        Ok(JsonPath { segments, desc: path.to_owned() })
+    }
+
+    /// Object keys and array indexes, from the outermost in
+    pub(crate) fn segments(&self) -> &[Value] {
+        &self.segments
+    }
+
+    pub(crate) fn get<'a>(&self, json: &'a Value) -> Option<&'a Value> {
+        self.segments.iter().try_fold(json, |current, segment| match (current, segment) {
+            (Value::Object(map), Value::String(key)) => map.get(key),
+            (Value::Array(vec), Value::Number(index)) =>
+                array_index(vec.len(), index.as_i64()?).and_then(|i| vec.get(i)),
+            _ => None
+        })
+    }
+
+    pub(crate) fn get_mut<'a>(&self, json: &'a mut Value) -> Option<&'a mut Value> {
+        self.segments.iter().try_fold(json, |current, segment| match (current, segment) {
+            (Value::Object(map), Value::String(key)) => map.get_mut(key),
+            (Value::Array(vec), Value::Number(index)) =>
+                array_index(vec.len(), index.as_i64()?).and_then(move |i| vec.get_mut(i)),
+            _ => None
+        })
+    }
+
+    /// Sets the value at this path, creating intermediate objects and
+    /// arrays as needed, like jq's `setpath`. Returns false if the path
+    /// goes through a value that can't hold it.
+    pub(crate) fn set(&self, json: &mut Value, value: Value) -> bool {
+        let mut current = json;
+        for segment in &self.segments {
+            if current.is_null() {
+                *current = match segment {
+                    Value::String(_) => Value::Object(serde_json::Map::new()),
+                    _ => Value::Array(Vec::new())
+                };
+            }
+            current = match (current, segment) {
+                (Value::Object(map), Value::String(key)) =>
+                    map.entry(key.as_str()).or_insert(Value::Null),
+                (Value::Array(vec), Value::Number(index)) => {
+                    let index = match index.as_i64().and_then(|i| array_index(vec.len(), i)) {
+                        Some(index) => index,
+                        None => return false
+                    };
+                    if index >= vec.len() {
+                        vec.resize(index + 1, Value::Null);
+                    }
+                    &mut vec[index]
+                },
+                _ => return false
+            };
+        }
+        *current = value;
+        true
+    }
+}
+
+impl std::fmt::Display for JsonPath {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::result::Result<(), std::fmt::Error> {
+        write!(f, "{}", self.desc)
+    }
+}
+
+impl std::fmt::Debug for JsonPath {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::result::Result<(), std::fmt::Error> {
+        write!(f, "JsonPath {{ path = \"{}\" }}", self.desc)
+    }
+}
+
+/// Parses a comma-separated list of jq paths. Commas inside brackets,
+/// parenthesis or quoted strings do not separate paths.
+pub(crate) fn parse_path_list(paths: &str) -> Result<Vec<JsonPath>> {
+    split_list(paths).iter().map(|path| JsonPath::new(path)).collect()
+}
+
+/// Splits on top-level commas, trimming each element
+pub(crate) fn split_list(list: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut depth = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut start = 0;
+    for (i, c) in list.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            '[' | '(' | '{' if !in_string => depth += 1,
+            ']' | ')' | '}' if !in_string => depth -= 1,
+            ',' if !in_string && depth == 0 => {
+                parts.push(list[start..i].trim());
+                start = i + 1;
+            },
+            _ => ()
+        }
+    }
+    parts.push(list[start..].trim());
+    parts.into_iter().filter(|part| !part.is_empty()).collect()
+}
+
+/// Resolves negative indexes from the end of the array, as jq does
+fn array_index(len: usize, index: i64) -> Option<usize> {
+    if index >= 0 {
+        Some(index as usize)
+    } else {
+        let from_end = index.unsigned_abs() as usize;
+        if from_end <= len { Some(len - from_end) } else { None }
+    }
+}
+
 fn jq_get_query(path: &str) -> Result<JqProgram> {
     let query = format!("if {path} | type != \"null\" then {path} else empty end",
                             path = path);
@@ -65,6 +208,18 @@ fn jq_update_query(path: &str) -> Result<JqProgram> {
     jq_rs::compile(&query).map_err(|e| e.to_error("compiling update query"))
 }
 
+/// Describes the type of a json value, for error messages
+pub(crate) fn type_name(json: &Value) -> &'static str {
+    match json {
+        Value::Null => "null",
+        Value::Bool(_) => "a boolean",
+        Value::Number(_) => "a number",
+        Value::String(_) => "a string",
+        Value::Array(_) => "an array",
+        Value::Object(_) => "an object",
+    }
+}
+
 /// Trims newlines and removes quotes if json is string
 pub(crate) fn raw_output(json: &str) -> String {
     let trimmed = json.trim();
@@ -259,6 +414,52 @@ mod tests {
         assert_eq!(raw_output([r#""string""#, "\n"].concat().as_str()), "string");
     }
 
+    #[test]
+    fn test_json_path_get() {
+        let json: Value = serde_json::from_str(r#"{"a":{"b":[1,{"c":"x"}]}}"#).unwrap();
+        assert_eq!(JsonPath::new(".a.b[1].c").unwrap().get(&json), Some(&Value::String("x".to_owned())));
+        assert_eq!(JsonPath::new(".a.b[-2]").unwrap().get(&json), Some(&serde_json::json!(1)));
+        assert_eq!(JsonPath::new(".a.b[5]").unwrap().get(&json), None);
+        assert_eq!(JsonPath::new(".a.b.c").unwrap().get(&json), None);
+    }
+
+    #[test]
+    fn test_json_path_get_mut() {
+        let mut json: Value = serde_json::from_str(r#"{"a":{"b":[1,{"c":"x"}]}}"#).unwrap();
+        let path = JsonPath::new(".a.b[1].c").unwrap();
+        assert_eq!(path.get_mut(&mut json), Some(&mut Value::String("x".to_owned())));
+        let path = JsonPath::new(".a.b[-2]").unwrap();
+        assert_eq!(path.get_mut(&mut json), Some(&mut serde_json::json!(1)));
+        let path = JsonPath::new(".a.missing").unwrap();
+        assert_eq!(path.get_mut(&mut json), None);
+    }
+
+    #[test]
+    fn test_json_path_set() {
+        let mut json: Value = serde_json::from_str(r#"{"a":{"b":1}}"#).unwrap();
+        assert!(JsonPath::new(".a.b").unwrap().set(&mut json, serde_json::json!(2)));
+        assert!(JsonPath::new(".x.y[1]").unwrap().set(&mut json, serde_json::json!(3)));
+        assert_eq!(json.to_string(), r#"{"a":{"b":2},"x":{"y":[null,3]}}"#);
+        assert!(!JsonPath::new(".a.b.c").unwrap().set(&mut json, serde_json::json!(4)));
+    }
+
+    #[test]
+    fn test_split_list() {
+        assert_eq!(split_list(".a, .b"), vec![".a", ".b"]);
+        assert_eq!(split_list(r#".["x,y"],.z[1,2]"#), vec![r#".["x,y"]"#, ".z[1,2]"]);
+        assert_eq!(split_list(""), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn test_json_path_invalid() {
+        assert_matches!(JsonPath::new("this is not jq code"),
+                        Err(Error(ErrorKind::JqInvalidProgram(_), _)));
+        assert_matches!(JsonPath::new(".a, .b"), Err(Error(ErrorKind::InvalidPath(_, _), _)));
+        assert_matches!(JsonPath::new(".a[1:2]"), Err(Error(ErrorKind::InvalidPath(_, _), _)));
+        assert_matches!(JsonPath::new(".a[].b"), Err(Error(ErrorKind::InvalidPath(_, _), _)));
+        assert_matches!(JsonPath::new(".a, .b"), Err(ref error) if error.is_fatal())
+    }
+
     fn nl(text: &str) -> String {
         [text, "\n"].concat()
     }
@@ -4,20 +4,68 @@ elided_lifetimes_in_paths,macro_use_extern_crate,missing_copy_implementations,
 missing_debug_implementations,single_use_lifetimes,unreachable_pub,unused_extern_crates,
 unused_import_braces,unused_lifetimes,unused_qualifications,unused_results)]
 
+mod annotate;
+mod avro;
+mod bulk;
+mod canonical;
+mod columnar;
+mod compress;
+mod dead_letter;
+mod dedupe;
+mod diff;
 mod errors;
+mod explode;
+mod failures;
+mod interrupt;
 mod json_queries;
+mod output;
+mod partition;
+mod postgres;
+mod rotate;
+mod sampling;
+mod sqlite;
+mod stats;
+mod tabular;
+mod timestamps;
 
-use std::io::{self, BufRead, Read, Write};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, IsTerminal, Read, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
 
-use ::error_chain::quick_main;
+use ::error_chain::bail;
 use ::base64;
 use ::flate2::bufread::GzDecoder;
+use ::parquet::basic::Compression;
+use ::serde_json::{json, Value};
 use ::structopt::{self, StructOpt};
 
+use crate::annotate::*;
+use crate::avro::*;
+use crate::bulk::*;
+use crate::canonical::*;
+use crate::columnar::*;
+use crate::compress::*;
+use crate::dead_letter::*;
+use crate::dedupe::*;
+use crate::diff::*;
 use crate::errors::*;
+use crate::explode::*;
+use crate::failures::*;
+use crate::interrupt::*;
 use crate::json_queries::*;
-
-quick_main!(run);
+use crate::output::*;
+use crate::partition::*;
+use crate::postgres::*;
+use crate::rotate::*;
+use crate::sampling::*;
+use crate::sqlite::*;
+use crate::stats::*;
+use crate::tabular::*;
+use crate::timestamps::*;
 
 const DEFAULT_BIN_PATH: &str = ".projectBinaryData.B";
 const DEFAULT_TEXT_PATH: &str = ".projectData.S";
@@ -38,8 +86,168 @@ const DEFAULT_TEXT_PATH: &str = ".projectData.S";
 /// example, ".no.binary.path .path.to.string" if there's string data
 /// on the .path.to.string, but not binary data, and ".no.binary.path"
 /// is not an existing path in the input data.
+///
+/// Timestamps are either a comma-separated list of paths, or "auto" to
+/// detect ISO-8601 strings anywhere, and epoch seconds or milliseconds on
+/// fields whose name ends in a timestamp word (createdAt, updated_at,
+/// eventTime...), also when wrapped in a DynamoDB N or S attribute value.
+/// Listed paths holding something other than a timestamp are errors.
+///
+/// Dedupe keys are a comma-separated list of paths, and records are
+/// duplicates if all of them are equal. Records missing all of them are
+/// always kept. Keeping the last duplicate holds back all output until the
+/// input ends. Disk storage is slower, but not limited by memory.
+///
+/// Explode paths end in [], as in .projectBinaryData.B.items[], and output
+/// one record per array element, with the element in place of the array
+/// and its index on a top-level field. Records are deduplicated before
+/// being exploded.
+///
+/// Annotated records get a _meta object with the input line number, the
+/// source name, the byte offset of the line, when it was processed, the
+/// sha256 of the raw line and which of the binary and text data were
+/// decoded.
+///
+/// Sampling hashes the sample key of each input line, or the whole line if
+/// there's no key, so reruns select the same records. A sample rate keeps
+/// about that fraction of the records. A sample size keeps exactly that
+/// many records, if available, but only outputs them once all input has
+/// been read. When both are used, the sample is taken from the records
+/// selected by the rate.
+///
+/// Canonical output follows RFC 8785 (JCS), with sorted keys and numbers
+/// printed the same way no matter how they were written on the input, so
+/// that exports can be compared, hashed and deduplicated byte for byte.
+///
+/// CSV and TSV output have a cell for each column, given as a
+/// comma-separated list of name=path, or for each top-level field of the
+/// first record. Nested values are written as json, or flattened into a
+/// cell per scalar inside them, named like data.tags.0, as found on the
+/// first record.
+///
+/// Parquet output takes its schema from a file with a parquet message
+/// type, or infers it from the first records. Fields missing from the
+/// schema are dropped. Records are decoded in batches, and those that
+/// don't fit the schema fail with their line when their batch is. Row
+/// groups are closed once their data reaches the row group size.
+/// Compression is uncompressed, snappy, gzip(level) or zstd(level).
+///
+/// Arrow output writes the IPC file format, also known as Feather, or the
+/// IPC stream format, in record batches of the given size. Its schema is
+/// given or inferred as with parquet, with objects and arrays as struct
+/// and list columns.
+///
+/// Avro output takes its schema from an .avsc file, or infers it from the
+/// first records. Values are coerced to the schema types, also taking
+/// DynamoDB attribute values such as {"N": "42"} as their content:
+///
+///   int, long, float, double: numbers, or strings holding them
+///   decimal: numbers or numeric strings, within precision and scale
+///   date: integer days, or YYYY-MM-DD strings
+///   timestamp-millis/micros: integers, or ISO-8601 strings
+///   string: strings only
+///   bytes, fixed: base64 for DynamoDB B, other strings as UTF-8
+///   enum: strings that are one of the symbols
+///   array: arrays, or DynamoDB L, SS, NS and BS
+///   map, record: objects, or DynamoDB M
+///   union: null if allowed, otherwise the first type that fits
+///
+/// Missing record fields take their default, or null. Fields can set
+/// "json_key" to read another key of the record, which is how inferred
+/// schemas keep keys that are not valid avro names.
+///
+/// SQLite output inserts records into a table of the --output database,
+/// creating it if needed. The fields layout has a column per top-level
+/// field, added as new fields show up, with objects and arrays as json
+/// text. The paths layout has a json column per --columns entry, or for
+/// the binary and text paths. Inserts are committed in transactions of
+/// the batch size, and the index paths are indexed at the end, using
+/// json_extract for paths inside a column.
+///
+/// PostgreSQL output is a script for psql that loads the records into the
+/// table, as COPY blocks or multi-row INSERT statements of the batch size.
+/// Columns are jsonb, one per --columns entry or else a data column with
+/// the whole record, and NULL where the record has no value. Records with
+/// \u0000 in them are errors, since jsonb can't hold it.
+///
+/// Elasticsearch bulk output has an index action line before each record,
+/// ready for the _bulk API of Elasticsearch or OpenSearch. The index name
+/// can take record fields, as in "projects-{.kind.S}", lowercased and with
+/// characters not allowed in index names replaced by _. Document ids come
+/// from the id path. Records missing either are errors. DynamoDB binary
+/// attributes over the maximum binary size are dropped.
+///
+/// Pretty json output is indented, and coloured like jq's when writing to
+/// a terminal. Array output wraps all records in one json array, written
+/// as they come, so it can be as long as the input. Records with errors
+/// are left out of both.
+///
+/// Rotated output writes numbered parts named by an --output pattern with
+/// a %d, as in part-%05d.jsonl, starting a new part once the current one
+/// reaches the size or number of records. Parts are written to hidden
+/// temp names and renamed once complete, and a manifest.json listing the
+/// records, bytes and sha256 of each part is written next to them.
+///
+/// Partitioned output goes under the --output directory, in a directory
+/// per partition column and value, as in customer=acme/date=2019-09-01,
+/// each with part-0 and, if it had to be closed to stay within the most
+/// open files, further parts. Characters that don't belong in file names
+/// are escaped as %XX. Records without a string, number or boolean for a
+/// partition column go under __HIVE_DEFAULT_PARTITION__.
+///
+/// Compressed output is compressed on its own thread, with gzip, zstd,
+/// bzip2 or xz. It's inferred from .gz, .zst, .bz2 and .xz output
+/// extensions, also on rotated parts, and partition files get the
+/// compression's extension.
+///
+/// Dead letters are the input lines of records that failed, written as
+/// they came, one json per line with the line number, the kind of error,
+/// its messages and the path that failed, so that they can be fixed and
+/// processed again. Lines that aren't valid UTF-8 are also kept as base64.
+///
+/// Json errors are printed one per line, as objects with the kind of
+/// error, the line number of the record, whether it stopped processing,
+/// what jq was doing and the messages of the error and its causes.
+/// Warnings, counts and statistics are printed as objects too, with an
+/// "info" or "warning" field for what they're about, the message and the
+/// details.
+///
+/// Error budgets abort the run, with exit code 6 and the most common kinds
+/// of error, once more records failed than the maximum errors, or than the
+/// maximum error rate among the last error window records. Runs shorter
+/// than the window are held to the rate over all their records.
+///
+/// Strict mode makes any record error stop processing, as when a single
+/// bad record makes the whole export invalid. The records written before
+/// it are flushed, and the error tells the line number of the record.
+///
+/// Statistics of the run cover records read, written and skipped by kind
+/// of error, bytes in, out and decompressed, how many records had the
+/// binary path, the text path or neither, and the time taken overall and
+/// by reading, decoding, transforming and writing.
+///
+/// SIGINT and SIGTERM stop processing after the current record, or while
+/// waiting for input. Outputs are flushed and closed, the summary is
+/// printed, the line and byte offset reached are saved to the checkpoint,
+/// and the exit code is 130. A run with the same checkpoint skips that much
+/// of the input and carries on, so it should write to a new output; the
+/// checkpoint is removed once a run completes. A second signal exits
+/// straight away with code 130.
+///
+/// Exit codes are 0 on success, even with records skipped because of their
+/// errors, 1 for any other error, 2 for invalid arguments, 3 for an
+/// invalid jq program or path, 4 when the input can't be read, 5 when the
+/// output can't be written, as on a broken pipe, 6 when the error budget
+/// is exceeded and 130 when interrupted by SIGINT or SIGTERM.
+///
+/// The diff command decodes two exports and outputs a line for each record
+/// added, removed or changed between them, with RFC 6902 patches for the
+/// changes. Records are matched by their key paths. Binary and text paths
+/// go before the command, as in "dynamodb-etl -t .data.S diff old new".
+/// Inputs are spilled to disk in partitions by key, which are split until
+/// the old records of each fit in --memory.
 #[derive(Debug,StructOpt)]
-#[structopt(name = "dynamodb-etl", about = "", author = "")]
+#[structopt(name = "dynamodb-etl", about = "", author = "", rename_all = "kebab-case")]
 struct Opt {
     /// Binary data path
     #[structopt(short, long, raw(default_value = "DEFAULT_BIN_PATH"))]
@@ -48,54 +256,729 @@ struct Opt {
     /// Text data path
     #[structopt(short, long, raw(default_value = "DEFAULT_TEXT_PATH"))]
     textpath: String,
+
+    /// Timestamp paths to normalise, or "auto"
+    #[structopt(long)]
+    timestamps: Option<String>,
+
+    /// Normalised timestamp format: rfc3339 or epoch-millis
+    #[structopt(long, default_value = "rfc3339")]
+    timestamp_format: TimestampFormat,
+
+    /// Key paths used to drop duplicate records
+    #[structopt(long)]
+    dedupe_key: Option<String>,
+
+    /// Which duplicate record to keep: first or last
+    #[structopt(long, default_value = "first")]
+    dedupe_keep: DedupeKeep,
+
+    /// Where to track dedupe keys: memory or disk
+    #[structopt(long, default_value = "memory")]
+    dedupe_store: DedupeStore,
+
+    /// Array path to output one record per element of
+    #[structopt(long)]
+    explode: Option<String>,
+
+    /// Field for the index of exploded elements
+    #[structopt(long, default_value = "_index")]
+    explode_index: String,
+
+    /// Add provenance metadata to each record as _meta
+    #[structopt(long)]
+    annotate: bool,
+
+    /// Source name for the annotations, such as a file or segment
+    #[structopt(long, default_value = "stdin")]
+    source: String,
+
+    /// Fraction of the input records to sample, from 0 to 1
+    #[structopt(long, parse(try_from_str = "parse_sample_rate"))]
+    sample_rate: Option<f64>,
+
+    /// Number of input records to sample
+    #[structopt(long)]
+    sample_size: Option<usize>,
+
+    /// Path hashed to select sampled records
+    #[structopt(long)]
+    sample_key: Option<String>,
+
+    /// Output canonical json (RFC 8785)
+    #[structopt(long)]
+    canonical: bool,
+
+    /// Indent json output, in colour on a terminal
+    #[structopt(long)]
+    pretty: bool,
+
+    /// Colour pretty json output: auto, always or never
+    #[structopt(long, default_value = "auto")]
+    color: ColorChoice,
+
+    /// Write json output as a single array
+    #[structopt(long)]
+    array: bool,
+
+    /// How errors are printed: text or json
+    #[structopt(long, default_value = "text")]
+    error_format: ErrorFormat,
+
+    /// Abort once more than this many records failed
+    #[structopt(long)]
+    max_errors: Option<u64>,
+
+    /// Abort once more than this fraction of the records in the error window failed
+    #[structopt(long, parse(try_from_str = "parse_error_rate"))]
+    max_error_rate: Option<f64>,
+
+    /// Number of most recent records the error rate is taken over
+    #[structopt(long, default_value = "1000")]
+    error_window: usize,
+
+    /// Print statistics of the run on stderr at the end
+    #[structopt(long)]
+    stats: bool,
+
+    /// Write statistics of the run to this file as json
+    #[structopt(long, parse(from_os_str))]
+    stats_file: Option<PathBuf>,
+
+    /// Stop at the first record that fails, keeping what was written before it
+    #[structopt(long)]
+    strict: bool,
+
+    /// File to save where the input was left when interrupted, and to resume from
+    #[structopt(long, parse(from_os_str))]
+    checkpoint: Option<PathBuf>,
+
+    /// File for the input lines of records that failed, with their errors
+    #[structopt(long, parse(from_os_str))]
+    dead_letter: Option<PathBuf>,
+
+    /// Output format: json, csv, tsv, parquet, avro, arrow, sqlite, pg-copy, sql-insert or es-bulk
+    #[structopt(long, default_value = "json")]
+    format: OutputFormat,
+
+    /// Output file, instead of the standard output, or pattern of rotated parts
+    #[structopt(long, parse(from_os_str))]
+    output: Option<PathBuf>,
+
+    /// Output compression: gzip, zstd, bzip2 or xz, by default from the output file extension
+    #[structopt(long)]
+    output_compression: Option<OutputCompression>,
+
+    /// Output compression level, from 0 or 1 to 9, or to 22 for zstd
+    #[structopt(long)]
+    compression_level: Option<u32>,
+
+    /// Partition columns, as name=path, splitting the output directory into name=value directories
+    #[structopt(long)]
+    partition_by: Option<String>,
+
+    /// Most partition files open at once
+    #[structopt(long, default_value = "64")]
+    max_open_files: usize,
+
+    /// Start a new output part once the current one reaches this size, such as 1G
+    #[structopt(long, parse(try_from_str = "parse_bytes"))]
+    rotate_bytes: Option<u64>,
+
+    /// Start a new output part after this many records
+    #[structopt(long)]
+    rotate_records: Option<u64>,
+
+    /// CSV, TSV, SQL and SQLite paths layout columns, as name=path
+    #[structopt(long)]
+    columns: Option<String>,
+
+    /// CSV and TSV nested values: json or flatten
+    #[structopt(long, default_value = "json")]
+    nested: NestedValues,
+
+    /// Write a header row on CSV and TSV output
+    #[structopt(long)]
+    header: bool,
+
+    /// Schema file, an avro .avsc or else a parquet message type, instead of inferring it
+    #[structopt(long, parse(from_os_str))]
+    schema: Option<PathBuf>,
+
+    /// Number of records the schema is inferred from
+    #[structopt(long, default_value = "1000")]
+    schema_sample: usize,
+
+    /// Parquet row group size, such as 128M
+    #[structopt(long, default_value = "128M", parse(try_from_str = "parse_bytes"))]
+    row_group_size: u64,
+
+    /// Parquet compression
+    #[structopt(long, default_value = "snappy")]
+    parquet_compression: Compression,
+
+    /// Avro compression: null or deflate
+    #[structopt(long, default_value = "deflate")]
+    avro_codec: AvroCodec,
+
+    /// Arrow IPC format: file or stream
+    #[structopt(long, default_value = "file")]
+    arrow_ipc: ArrowIpc,
+
+    /// Records per arrow record batch, SQLite transaction or SQL statement
+    #[structopt(long, default_value = "1024")]
+    batch_size: usize,
+
+    /// SQLite or SQL table name
+    #[structopt(long, default_value = "export")]
+    table: String,
+
+    /// SQLite table layout: fields or paths
+    #[structopt(long, default_value = "fields")]
+    sqlite_layout: SqliteLayout,
+
+    /// Key paths to index on SQLite output
+    #[structopt(long)]
+    index_paths: Option<String>,
+
+    /// Elasticsearch index name, which may include {path} fields
+    #[structopt(long)]
+    index: Option<String>,
+
+    /// Path of the Elasticsearch document id
+    #[structopt(long)]
+    id_path: Option<String>,
+
+    /// Drop binary attributes larger than this from Elasticsearch documents, such as 64K
+    #[structopt(long, parse(try_from_str = "parse_bytes"))]
+    max_binary_size: Option<u64>,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug,StructOpt)]
+#[structopt(rename_all = "kebab-case")]
+enum Command {
+    /// Compares two exports, matching records by key
+    #[structopt(name = "diff")]
+    Diff {
+        /// Older export
+        #[structopt(parse(from_os_str))]
+        old: PathBuf,
+
+        /// Newer export
+        #[structopt(parse(from_os_str))]
+        new: PathBuf,
+
+        /// Key paths that identify records
+        #[structopt(long)]
+        key: String,
+
+        /// Partitions spilled to disk, by default enough for --memory of input each
+        #[structopt(long)]
+        partitions: Option<usize>,
+
+        /// Memory for the old records of a partition, such as 256M. Larger
+        /// partitions are split again.
+        #[structopt(long, default_value = "256M", parse(try_from_str = "parse_bytes"))]
+        memory: u64,
+    },
+}
+
+/// The records made from an input line
+#[derive(Debug,Default,PartialEq)]
+struct Processed {
+    records: Vec<String>,
+    /// Dedupe key of the input record, before it was exploded
+    key: Option<u128>,
+}
+
+/// Changes applied to each record after its data has been decoded
+#[derive(Debug,Default)]
+struct Transforms {
+    timestamps: Option<Timestamps>,
+    dedupe_key: Option<DedupeKey>,
+    explode: Option<Explode>,
+    annotate: Option<Annotate>,
+    canonical: bool,
+}
+
+impl Transforms {
+    fn new(opt: &Opt) -> Result<Transforms> {
+        let timestamps = match opt.timestamps {
+            Some(ref fields) => Some(Timestamps::new(fields, opt.timestamp_format)?),
+            None => None,
+        };
+        let dedupe_key = match opt.dedupe_key {
+            Some(ref paths) => Some(DedupeKey::new(paths)?),
+            None => None,
+        };
+        let explode = match opt.explode {
+            Some(ref path) => Some(Explode::new(path, &opt.explode_index)?),
+            None => None,
+        };
+        let annotate = if opt.annotate { Some(Annotate::new(&opt.source)) } else { None };
+        Ok(Transforms { timestamps, dedupe_key, explode, annotate, canonical: opt.canonical })
+    }
+
+    fn is_empty(&self) -> bool {
+        self.timestamps.is_none() && self.dedupe_key.is_none() && self.explode.is_none() && self.annotate.is_none()
+            && !self.canonical
+    }
+
+    /// Records are only parsed and serialized again if there's some
+    /// transformation to apply. The dedupe key is taken before exploding.
+    fn apply(&self, json: String, meta: Option<&Value>) -> Result<Processed> {
+        if self.is_empty() {
+            return Ok(Processed { records: vec![json], key: None });
+        }
+        let mut record: Value = serde_json::from_str(&json)?;
+        if let Some(ref timestamps) = self.timestamps {
+            timestamps.normalise(&mut record)?;
+        }
+        let key = match self.dedupe_key {
+            Some(ref dedupe_key) => dedupe_key.key(&record)?,
+            None => None,
+        };
+        let mut records = match self.explode {
+            Some(ref explode) => explode.explode(record)?,
+            None => vec![record],
+        };
+        if let (Some(annotate), Some(meta)) = (self.annotate.as_ref(), meta) {
+            records.iter_mut().for_each(|record| annotate.annotate(record, meta));
+        }
+        let serialize = if self.canonical { to_canonical_string } else { Value::to_string };
+        Ok(Processed { records: records.iter().map(serialize).collect(), key })
+    }
+}
+
+fn main() {
+    let code = match run() {
+        Ok(()) => 0,
+        Err(error) => {
+            report_error(&error);
+            exit_code(&error)
+        },
+    };
+    std::process::exit(code);
 }
 
 fn run() -> Result<()> {
-    let opt: Opt = Opt::from_args();
+    let opt = match Opt::from_iter_safe(std::env::args_os()) {
+        Ok(opt) => opt,
+        // Help and version go to stdout, and aren't errors
+        Err(error) if !error.use_stderr() => error.exit(),
+        Err(error) => {
+            eprintln!("{}", error.message);
+            std::process::exit(EXIT_INVALID_ARGUMENTS);
+        },
+    };
+    set_error_format(opt.error_format);
+    match opt.command {
+        Some(Command::Diff { ref old, ref new, ref key, partitions, memory }) =>
+            run_diff(&opt, old, new, key, partitions, memory),
+        None => run_export(&opt),
+    }
+}
 
-    let stdin = io::stdin();
-    let input = stdin.lock();
-    let stdout = io::stdout();
-    let mut output = stdout.lock();
+fn run_export(opt: &Opt) -> Result<()> {
+    let mut input = BufReader::new(Interruptible(io::stdin()));
+    let start = match opt.checkpoint {
+        Some(ref path) => Checkpoint::load(path)?,
+        None => Checkpoint::default(),
+    };
+    start.skip(&mut input).chain_err(|| ErrorKind::InputError)?;
+    let mut input = MeasuredInput::new(input);
+    let stats = &mut Stats::new(opt.stats || opt.stats_file.is_some());
+    let bytes_out = stats.bytes_out.clone();
 
     let bin_path = &opt.binpath;
     let text_path = &opt.textpath;
 
-    let bin_queries = &mut json_queries::Queries::new(bin_path)?;
-    let text_queries = &mut json_queries::Queries::new(text_path)?;
+    let bin_queries = &mut Queries::new(bin_path)?;
+    let text_queries = &mut Queries::new(text_path)?;
+    let transforms = &Transforms::new(opt)?;
+    let mut dedupe = match opt.dedupe_key {
+        Some(_) => Some(Dedupe::new(opt.dedupe_keep, opt.dedupe_store)?),
+        None => None,
+    };
+    let mut sampler = if opt.sample_rate.is_some() || opt.sample_size.is_some() {
+        Some(Sampler::new(opt.sample_key.as_deref(), opt.sample_rate, opt.sample_size)?)
+    } else {
+        None
+    };
 
-    process_input(input, &mut output, bin_queries, text_queries)
+    if (opt.pretty || opt.array) && opt.format != OutputFormat::Json {
+        bail!(ErrorKind::InvalidArguments("--pretty and --array only apply to json output".to_owned()));
+    }
+    let columns = match opt.columns {
+        Some(ref columns) => Some(parse_columns(columns)?),
+        None => None,
+    };
+    let rotation = Rotation { bytes: opt.rotate_bytes, records: opt.rotate_records };
+    let partition_by = match opt.partition_by {
+        Some(ref columns) => Some(parse_columns(columns)?),
+        None => None,
+    };
+    let compression = opt.output_compression
+        .or_else(|| opt.output.as_deref().and_then(OutputCompression::from_path));
+    let mut output: Box<dyn RecordWriter + '_> = match (opt.format, &opt.output) {
+        (OutputFormat::Sqlite, _) if rotation.is_set() || partition_by.is_some() || compression.is_some() =>
+            bail!(ErrorKind::InvalidArguments("sqlite output can't be rotated, partitioned or compressed".to_owned())),
+        (OutputFormat::Sqlite, Some(path)) => {
+            let paths = match columns {
+                Some(columns) => columns,
+                None => parse_columns(&format!("{},{}", bin_path, text_path))?,
+            };
+            let indexes = match opt.index_paths {
+                Some(ref paths) => parse_path_list(paths)?,
+                None => Vec::new(),
+            };
+            let sqlite = Sqlite::new(path, &opt.table, opt.sqlite_layout, paths, indexes, opt.batch_size)
+                .chain_err(|| ErrorKind::OutputError)?;
+            Box::new(sqlite)
+        },
+        (OutputFormat::Sqlite, None) =>
+            bail!(ErrorKind::InvalidArguments("sqlite output needs an --output database".to_owned())),
+        (_, _) if rotation.is_set() && partition_by.is_some() =>
+            bail!(ErrorKind::InvalidArguments("output can't be both rotated and partitioned".to_owned())),
+        (_, Some(directory)) if partition_by.is_some() => {
+            let open = Box::new(|file: Box<dyn Write + Send>|
+                stream_writer(opt, columns.clone(), compression, &bytes_out, file));
+            let extension = match compression {
+                Some(compression) => format!("{}.{}", opt.format.extension(), compression.extension()),
+                None => opt.format.extension().to_owned(),
+            };
+            let partition_by = partition_by.unwrap_or_default();
+            Box::new(Partitioned::new(directory.clone(), partition_by, &extension, opt.max_open_files, open))
+        },
+        (_, None) if partition_by.is_some() =>
+            bail!(ErrorKind::InvalidArguments("partitioned output needs an --output directory".to_owned())),
+        (_, Some(pattern)) if rotation.is_set() => {
+            let open = Box::new(|file: Box<dyn Write + Send>|
+                stream_writer(opt, columns.clone(), compression, &bytes_out, file));
+            Box::new(Rotating::new(pattern, rotation, open).chain_err(|| ErrorKind::OutputError)?)
+        },
+        (_, None) if rotation.is_set() =>
+            bail!(ErrorKind::InvalidArguments("rotated output needs an --output pattern".to_owned())),
+        (_, Some(path)) => {
+            let file = File::create(path).chain_err(|| ErrorKind::OutputError)?;
+            stream_writer(opt, columns, compression, &bytes_out, Box::new(io::BufWriter::new(file)))?
+        },
+        (_, None) => stream_writer(opt, columns, compression, &bytes_out, Box::new(io::BufWriter::new(io::stdout())))?,
+    };
+
+    let dead_letter = match opt.dead_letter {
+        Some(ref path) => Some(DeadLetter::new(path, bin_path, text_path).chain_err(|| ErrorKind::OutputError)?),
+        None => None,
+    };
+    let budget = ErrorBudget {
+        max_errors: opt.max_errors,
+        max_rate: opt.max_error_rate,
+        window: opt.error_window,
+    };
+    let failures = &mut Failures::new(dead_letter, budget, opt.strict);
+
+    catch_termination()?;
+    let measured = &mut MeasuredOutput::new(&mut *output);
+    let mut position = start;
+    let result = match process_input(&mut input, measured, bin_queries, text_queries, transforms,
+                                     dedupe.as_mut(), sampler.as_mut(), failures, stats, &mut position) {
+        // What was written before the record that failed is kept
+        Err(error) if matches!(error.kind(), ErrorKind::Strict) =>
+            measured.finish().chain_err(|| ErrorKind::OutputError).and(Err(error)),
+        result => result,
+    };
+
+    stats.records_written = measured.records;
+    stats.add_time(Stage::Write, measured.elapsed);
+    stats.bytes_in = input.bytes;
+    stats.add_time(Stage::Read, input.elapsed);
+    stats.skipped = failures.kinds().collect();
+    if let (OutputFormat::Sqlite, Some(path)) = (opt.format, &opt.output) {
+        stats.bytes_out.store(std::fs::metadata(path).map_or(0, |metadata| metadata.len()), Ordering::Relaxed);
+    }
+    let interrupted = matches!(result, Err(Error(ErrorKind::Interrupted(_), _)));
+    if opt.stats || interrupted {
+        report_notice(Notice::Info, "Stats", stats.summary().trim_end(), json!({"stats": stats.to_json()}));
+    }
+    if let Some(ref path) = opt.stats_file {
+        stats.write(path).chain_err(|| ErrorKind::OutputError)?;
+    }
+    match opt.checkpoint {
+        Some(ref path) if interrupted => position.save(path).chain_err(|| ErrorKind::OutputError)?,
+        Some(ref path) if result.is_ok() && path.exists() => std::fs::remove_file(path)?,
+        _ => (),
+    }
+    result?;
+
+    if let Some(ref dedupe) = dedupe {
+        report_notice(Notice::Info, "DuplicatesDropped", &format!("Dropped {} duplicate records", dedupe.dropped()),
+                      json!({"dropped": dedupe.dropped()}));
+    }
+    Ok(())
+}
+
+/// Writer of the output formats that go to a file or the standard output
+fn stream_writer(opt: &Opt, columns: Option<Vec<Column>>, compression: Option<OutputCompression>,
+                 bytes_out: &Arc<AtomicU64>, destination: Box<dyn Write + Send>) -> Result<Box<dyn RecordWriter>> {
+    let destination: Box<dyn Write + Send> = Box::new(CountedOutput::new(destination, bytes_out.clone()));
+    let destination: Box<dyn Write + Send> = match compression {
+        Some(compression) => Box::new(Compressed::new(destination, compression, opt.compression_level)?),
+        None => destination,
+    };
+    Ok(match opt.format {
+        OutputFormat::Json => {
+            let color = match opt.color {
+                ColorChoice::Auto => opt.output.is_none() && compression.is_none() && io::stdout().is_terminal()
+                    && std::env::var_os("NO_COLOR").is_none(),
+                color => color == ColorChoice::Always,
+            };
+            Box::new(JsonWriter::new(destination, opt.pretty, color, opt.array))
+        },
+        OutputFormat::Csv => Box::new(Tabular::new(destination, b',', columns, opt.nested, opt.header)),
+        OutputFormat::Tsv => Box::new(Tabular::new(destination, b'\t', columns, opt.nested, opt.header)),
+        OutputFormat::Parquet => {
+            let schema = match opt.schema {
+                Some(ref path) => Some(read_parquet_schema(path)?),
+                None => None,
+            };
+            let parquet = ParquetOptions {
+                compression: opt.parquet_compression,
+                row_group_bytes: opt.row_group_size as usize,
+            };
+            Box::new(Columnar::new(destination, ColumnarFormat::Parquet(parquet), schema, opt.schema_sample))
+        },
+        OutputFormat::Arrow => {
+            let schema = match opt.schema {
+                Some(ref path) => Some(read_parquet_schema(path)?),
+                None => None,
+            };
+            let format = ColumnarFormat::Arrow { ipc: opt.arrow_ipc, batch_size: opt.batch_size };
+            Box::new(Columnar::new(destination, format, schema, opt.schema_sample))
+        },
+        OutputFormat::Avro => {
+            let schema = match opt.schema {
+                Some(ref path) => Some(read_avro_schema(path)?),
+                None => None,
+            };
+            Box::new(Avro::new(destination, opt.avro_codec, schema, opt.schema_sample)?)
+        },
+        OutputFormat::PgCopy =>
+            Box::new(Postgres::new(destination, SqlStatement::Copy, &opt.table, columns, opt.batch_size)?),
+        OutputFormat::SqlInsert =>
+            Box::new(Postgres::new(destination, SqlStatement::Insert, &opt.table, columns, opt.batch_size)?),
+        OutputFormat::EsBulk => {
+            let index = match opt.index {
+                Some(ref index) => index,
+                None => bail!(ErrorKind::InvalidArguments("es-bulk output needs an --index".to_owned())),
+            };
+            Box::new(EsBulk::new(destination, index, opt.id_path.as_deref(), opt.max_binary_size)?)
+        },
+        OutputFormat::Sqlite =>
+            bail!(ErrorKind::InvalidArguments("sqlite output goes to a database, not a stream".to_owned())),
+    })
+}
+
+fn run_diff(opt: &Opt, old: &Path, new: &Path, key: &str, partitions: Option<usize>, memory: u64) -> Result<()> {
+    let bin_queries = &mut Queries::new(&opt.binpath)?;
+    let text_queries = &mut Queries::new(&opt.textpath)?;
+    let old_file = File::open(old).chain_err(|| ErrorKind::InputError)?;
+    let new_file = File::open(new).chain_err(|| ErrorKind::InputError)?;
+    let partitions = match partitions {
+        Some(partitions) => partitions,
+        None => partitions_for(old_file.metadata()?.len(), memory),
+    };
+    let diff = Diff::new(key, partitions, memory)?;
+    let stats = &mut Stats::default();
+
+    let stdout = io::stdout();
+    let mut output = io::BufWriter::new(stdout.lock());
+    let counts = diff.diff((&old.to_string_lossy(), BufReader::new(old_file)),
+                           (&new.to_string_lossy(), BufReader::new(new_file)),
+                           &mut |line| re_encode_json(line, bin_queries, text_queries, stats),
+                           &mut output)?;
+    output.flush()?;
+    let message = format!("{} added, {} removed, {} changed, {} unchanged",
+                          counts.added, counts.removed, counts.changed, counts.unchanged);
+    report_notice(Notice::Info, "DiffCounts", &message, json!({
+        "added": counts.added,
+        "removed": counts.removed,
+        "changed": counts.changed,
+        "unchanged": counts.unchanged,
+    }));
+    Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
 fn process_input(input: impl BufRead,
-                 mut output: impl Write,
+                 output: &mut dyn RecordWriter,
                  bin_queries: &mut Queries,
-                 text_queries: &mut Queries) -> Result<()> {
-    for (index, next_line) in input.lines().enumerate() {
-        let processed_line =
-            process_line(next_line.map_err(|e| e.into()), index, bin_queries, text_queries);
-        match processed_line {
-            Err(ref error) if error.is_fatal() => processed_line.map(|_| ())?,
-            Err(ref error) => {
-                eprintln!("Error: {}", error);
-                for e in error.iter().skip(1) {
-                    eprintln!("caused by: {}", e);
-                }
+                 text_queries: &mut Queries,
+                 transforms: &Transforms,
+                 mut dedupe: Option<&mut Dedupe>,
+                 mut sampler: Option<&mut Sampler>,
+                 failures: &mut Failures,
+                 stats: &mut Stats,
+                 position: &mut Checkpoint) -> Result<()> {
+    let mut interrupted = None;
+    let mut lines = lines_with_offsets(input, position.offset);
+    loop {
+        // Stops between records, before reading the next one, so that the
+        // input can be resumed from there
+        if let Some(signal) = termination_signal() {
+            interrupted = Some(signal);
+            break;
+        }
+        let (range, next_line) = match lines.next() {
+            Some(line) => line,
+            None => break,
+        };
+        // A read cut short by the signal leaves its line for the next run
+        if matches!(next_line, Err(ref error) if is_terminated(error)) {
+            interrupted = termination_signal();
+            break;
+        }
+        let (index, offset) = (position.line, range.start);
+        position.line += 1;
+        position.offset = range.end;
+        stats.records_read += 1;
+        let next_line = match (sampler.as_mut(), next_line) {
+            (Some(sampler), Ok(line)) => match sampler.sample(index, offset, line) {
+                Ok(Some(line)) => Ok(line),
+                Ok(None) => continue,
+                Err(error) => Err(error),
             },
-            Ok(ref message) => writeln!(output, "{}", message)?,
+            // Lines that aren't UTF-8 are the record's problem, not the input's
+            (_, Err(error)) if error.kind() == io::ErrorKind::InvalidData => Err(error.into()),
+            (_, Err(error)) => Err(Error::with_chain(error, ErrorKind::InputError)),
+            (_, Ok(line)) => Ok(line),
+        };
+        let line = if failures.keeps_lines() { next_line.as_ref().ok().cloned() } else { None };
+        let processed_line = process_line(next_line, index, offset,
+                                          bin_queries, text_queries, transforms, stats);
+        let source = Source { index, line };
+        write_processed_line(processed_line, &source, output, dedupe.as_deref_mut(), failures)?;
+    }
+    if let Some(sampler) = sampler {
+        for (index, offset, line) in sampler.drain() {
+            let kept = if failures.keeps_lines() { Some(line.clone()) } else { None };
+            let processed_line = process_line(Ok(line), index, offset,
+                                              bin_queries, text_queries, transforms, stats);
+            let source = Source { index, line: kept };
+            write_processed_line(processed_line, &source, output, dedupe.as_deref_mut(), failures)?;
         }
     }
+    if let Some(dedupe) = dedupe {
+        dedupe.finish(output, failures)?;
+        fail_rejected(output, failures)?;
+    }
+    output.finish().chain_err(|| ErrorKind::OutputError)?;
+    fail_rejected(output, failures)?;
+    failures.finish()?;
+    match interrupted {
+        Some(signal) => bail!(ErrorKind::Interrupted(signal.to_owned())),
+        None => Ok(()),
+    }
+}
+
+/// Writes the records, or reports the error if it's not fatal.
+///
+/// Records the output can't take, such as those that don't fit its
+/// schema, are reported the same way.
+fn write_processed_line(processed_line: Result<Processed>,
+                        source: &Source,
+                        output: &mut dyn RecordWriter,
+                        dedupe: Option<&mut Dedupe>,
+                        failures: &mut Failures) -> Result<()> {
+    let index = source.index;
+    let error = match processed_line {
+        Err(ref error) if error.is_fatal() => return processed_line.map(|_| ()),
+        Err(error) => error,
+        Ok(ref processed) => {
+            output.set_source(source);
+            let written = match dedupe {
+                Some(dedupe) => dedupe.write(processed.key, index, &processed.records, output),
+                None => processed.records.iter().try_for_each(|record| output.write_record(record)),
+            };
+            match written {
+                Err(error) if !error.is_fatal() => Error::with_chain(error, ErrorKind::LineNo(index + 1, false)),
+                Ok(()) => {
+                    failures.succeed();
+                    return fail_rejected(output, failures);
+                },
+                Err(error) => return Err(Error::with_chain(error, ErrorKind::OutputError)),
+            }
+        },
+    };
+    failures.fail(error, source.line.as_deref())?;
+    fail_rejected(output, failures)
+}
+
+/// Fails the records the output held back and then couldn't write, with
+/// the lines they came from
+fn fail_rejected(output: &mut dyn RecordWriter, failures: &mut Failures) -> Result<()> {
+    for rejected in output.take_rejected() {
+        let error = Error::with_chain(rejected.error, ErrorKind::LineNo(rejected.source.index + 1, false));
+        failures.fail(error, rejected.source.line.as_deref())?;
+    }
     Ok(())
 }
 
+/// Like `BufRead::lines`, but also returns the bytes each line spans,
+/// counting from the given offset of the first one
+fn lines_with_offsets(mut input: impl BufRead, mut offset: u64) -> impl Iterator<Item = (Range<u64>, io::Result<String>)> {
+    std::iter::from_fn(move || {
+        let mut buffer = Vec::new();
+        let start = offset;
+        match input.read_until(b'\n', &mut buffer) {
+            Ok(0) => None,
+            Ok(read) => {
+                offset += read as u64;
+                if buffer.ends_with(b"\n") {
+                    let _ = buffer.pop();
+                    if buffer.ends_with(b"\r") {
+                        let _ = buffer.pop();
+                    }
+                }
+                let line = String::from_utf8(buffer)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e));
+                Some((start..offset, line))
+            },
+            Err(error) => Some((start..start, Err(error))),
+        }
+    })
+}
+
 fn process_line(next_line: Result<String>,
                 index: usize,
+                offset: u64,
                 bin_queries: &mut Queries,
-                text_queries: &mut Queries) -> Result<String> {
+                text_queries: &mut Queries,
+                transforms: &Transforms,
+                stats: &mut Stats) -> Result<Processed> {
     let line_num = index + 1;
     let result = next_line
         .and_then(|line| {
-            re_encode_json(&line, bin_queries, text_queries)
+            let started = Instant::now();
+            let decoders = if transforms.annotate.is_some() || stats.is_enabled() {
+                let decoders = find_decoders(&line, bin_queries, text_queries)?;
+                stats.decoded(decoders);
+                Some(decoders)
+            } else {
+                None
+            };
+            let meta = match (&transforms.annotate, decoders) {
+                (Some(annotate), Some(decoders)) => Some(annotate.meta(line_num, offset, &line, decoders)),
+                _ => None,
+            };
+            let json = re_encode_json(&line, bin_queries, text_queries, stats)?;
+            stats.add_time(Stage::Decode, started.elapsed());
+            let started = Instant::now();
+            let records = transforms.apply(json, meta.as_ref());
+            stats.add_time(Stage::Transform, started.elapsed());
+            records
         });
     // TODO: print "line" on error, if available
     match result {
@@ -107,21 +990,31 @@ fn process_line(next_line: Result<String>,
     }
 }
 
-fn re_encode_json(str_line: &str, bin_queries: &mut Queries, text_queries: &mut Queries) -> Result<String> {
-    let re_encoded_bin = re_encode_binary_data(str_line, bin_queries)?;
+/// Which data paths are present on the input, and so will be decoded
+fn find_decoders(str_line: &str, bin_queries: &mut Queries, text_queries: &mut Queries) -> Result<Decoders> {
+    Ok(Decoders {
+        binary: !bin_queries.get(str_line)?.is_empty(),
+        text: !text_queries.get(str_line)?.is_empty(),
+    })
+}
+
+fn re_encode_json(str_line: &str, bin_queries: &mut Queries, text_queries: &mut Queries,
+                  stats: &mut Stats) -> Result<String> {
+    let re_encoded_bin = re_encode_binary_data(str_line, bin_queries, stats)?;
     re_encode_text_data(&re_encoded_bin, text_queries)
 }
 
 /// Replace strings containing json with that json
 fn re_encode_text_data(json: &str, queries: &mut Queries) -> Result<String> {
-    queries.update(&json)
+    queries.update(json)
 }
 
 /// Replace strings containing base64-encoded, gzipped json with that json
-fn re_encode_binary_data(json: &str, queries: &mut Queries) -> Result<String> {
+fn re_encode_binary_data(json: &str, queries: &mut Queries, stats: &mut Stats) -> Result<String> {
     let binary_data = queries.get(json)?;
     if !binary_data.is_empty() {
-        let decoded = decode_binary_data(&(binary_data.trim()))?;
+        let decoded = decode_binary_data(binary_data.trim())?;
+        stats.decompressed_bytes += decoded.len() as u64;
         queries.set(json, &decoded)
     } else {
         Ok(raw_output(json))
@@ -173,7 +1066,7 @@ mod tests {
         let json = r#"{ "projectBinaryData" : { "B": "H4sIABWa/lwCA6uu5QIABrCh3QMAAAA=" } }"#;
         let expected = r#"{"projectBinaryData":{"B":{}}}"#;
         let queries = &mut Queries::new(DEFAULT_BIN_PATH).unwrap();
-        let result = re_encode_binary_data(json, queries);
+        let result = re_encode_binary_data(json, queries, &mut Stats::default());
         assert_matches!(result, Ok(ref actual) if actual == expected)
     }
 
@@ -181,7 +1074,7 @@ mod tests {
     fn test_re_encode_binary_data_does_not_add_it() {
         let json = r#"{ "a": 1 }"#;
         let queries = &mut Queries::new(DEFAULT_BIN_PATH).unwrap();
-        let result = re_encode_binary_data(json, queries);
+        let result = re_encode_binary_data(json, queries, &mut Stats::default());
         assert_matches!(result, Ok(ref actual) if actual == json)
     }
 
@@ -189,7 +1082,7 @@ mod tests {
     fn test_re_encode_binary_data_fail_not_encoded() {
         let json = r#"{ "projectBinaryData" : { "B": {} } }"#;
         let queries = &mut Queries::new(DEFAULT_BIN_PATH).unwrap();
-        let result = re_encode_binary_data(json, queries);
+        let result = re_encode_binary_data(json, queries, &mut Stats::default());
         assert_matches!(result, Err(ref error) if !error.is_fatal())
     }
 
@@ -197,7 +1090,7 @@ mod tests {
     fn test_re_encode_binary_data_fail_not_json() {
         let json = r#"{ "projectBinaryData" : { "B": "H4sIAEafTF0AA8vMK0vMyUxRyCrOz+MCAIg5TZANAAAA" } }"#;
         let queries = &mut Queries::new(DEFAULT_BIN_PATH).unwrap();
-        let result = re_encode_binary_data(json, queries);
+        let result = re_encode_binary_data(json, queries, &mut Stats::default());
         assert_matches!(result, Err(ref error) if !error.is_fatal())
     }
 
@@ -232,7 +1125,7 @@ mod tests {
         let expected = &json.to_owned();
         let bin_queries = &mut Queries::new(DEFAULT_BIN_PATH).unwrap();
         let text_queries = &mut Queries::new(DEFAULT_TEXT_PATH).unwrap();
-        let result = re_encode_json(json, bin_queries, text_queries);
+        let result = re_encode_json(json, bin_queries, text_queries, &mut Stats::default());
         assert_matches!(result, Ok(ref actual) if actual == expected);
     }
 
@@ -242,7 +1135,7 @@ mod tests {
         let expected = r#"{"projectData":{"S":{}}}"#;
         let bin_queries = &mut Queries::new(DEFAULT_BIN_PATH).unwrap();
         let text_queries = &mut Queries::new(DEFAULT_TEXT_PATH).unwrap();
-        let result = re_encode_json(json, bin_queries, text_queries);
+        let result = re_encode_json(json, bin_queries, text_queries, &mut Stats::default());
         assert_matches!(result, Ok(ref actual) if actual == expected)
     }
 
@@ -252,7 +1145,7 @@ mod tests {
         let expected = r#"{"projectBinaryData":{"B":{}}}"#;
         let bin_queries = &mut Queries::new(DEFAULT_BIN_PATH).unwrap();
         let text_queries = &mut Queries::new(DEFAULT_TEXT_PATH).unwrap();
-        let result = re_encode_json(json, bin_queries, text_queries);
+        let result = re_encode_json(json, bin_queries, text_queries, &mut Stats::default());
         assert_matches!(result, Ok(ref actual) if actual == expected)
     }
 
@@ -272,7 +1165,7 @@ mod tests {
         "#.replace(|c: char| c.is_whitespace(), "");
         let bin_queries = &mut Queries::new(DEFAULT_BIN_PATH).unwrap();
         let text_queries = &mut Queries::new(DEFAULT_TEXT_PATH).unwrap();
-        let result = re_encode_json(json, bin_queries, text_queries);
+        let result = re_encode_json(json, bin_queries, text_queries, &mut Stats::default());
         assert_matches!(result, Ok(ref actual) if actual == expected)
     }
 
@@ -294,7 +1187,7 @@ mod tests {
         "#.replace(|c: char| c.is_whitespace(), "");
         let bin_queries = &mut Queries::new(DEFAULT_BIN_PATH).unwrap();
         let text_queries = &mut Queries::new(DEFAULT_TEXT_PATH).unwrap();
-        let result = re_encode_json(json, bin_queries, text_queries);
+        let result = re_encode_json(json, bin_queries, text_queries, &mut Stats::default());
         assert_matches!(result, Ok(ref actual) if actual == expected)
     }
 
@@ -309,7 +1202,7 @@ mod tests {
         "#;
         let bin_queries = &mut Queries::new(DEFAULT_BIN_PATH).unwrap();
         let text_queries = &mut Queries::new(DEFAULT_TEXT_PATH).unwrap();
-        let result = re_encode_json(json, bin_queries, text_queries);
+        let result = re_encode_json(json, bin_queries, text_queries, &mut Stats::default());
         assert_matches!(result, Err(ref error) if !error.is_fatal())
     }
 
@@ -324,7 +1217,7 @@ mod tests {
         "#;
         let bin_queries = &mut Queries::new(DEFAULT_BIN_PATH).unwrap();
         let text_queries = &mut Queries::new(DEFAULT_TEXT_PATH).unwrap();
-        let result = re_encode_json(json, bin_queries, text_queries);
+        let result = re_encode_json(json, bin_queries, text_queries, &mut Stats::default());
         assert_matches!(result, Err(ref error) if !error.is_fatal())
     }
 
@@ -346,8 +1239,9 @@ mod tests {
         "#.replace(|c: char| c.is_whitespace(), "");
         let bin_queries = &mut Queries::new(DEFAULT_BIN_PATH).unwrap();
         let text_queries = &mut Queries::new(DEFAULT_TEXT_PATH).unwrap();
-        let result = process_line(Ok(json.to_owned()), 0, bin_queries, text_queries);
-        assert_matches!(result, Ok(ref actual) if actual == expected)
+        let result = process_line(Ok(json.to_owned()), 0, 0, bin_queries, text_queries,
+                                  &Transforms::default(), &mut Stats::default());
+        assert_matches!(result, Ok(ref actual) if actual.records == [expected.to_owned()])
     }
 
     #[test]
@@ -358,7 +1252,7 @@ mod tests {
         let bin_queries = &mut Queries::new(DEFAULT_BIN_PATH).unwrap();
         let text_queries = &mut Queries::new(DEFAULT_TEXT_PATH).unwrap();
         let line = lines_iter.next().unwrap().map_err(|e| e.into());
-        let result = process_line(line, 17, bin_queries, text_queries);
+        let result = process_line(line, 17, 0, bin_queries, text_queries, &Transforms::default(), &mut Stats::default());
         assert_matches!(result, Err(Error(ErrorKind::LineNo(18, false), _)))
     }
 
@@ -379,7 +1273,10 @@ mod tests {
         let mut output = Vec::<u8>::with_capacity(1024);
         let bin_queries = &mut Queries::new(DEFAULT_BIN_PATH).unwrap();
         let text_queries = &mut Queries::new(DEFAULT_TEXT_PATH).unwrap();
-        let result = process_input(input, &mut output, bin_queries, text_queries);
+        let result = process_input(input, &mut output, bin_queries, text_queries,
+                                   &Transforms::default(), None, None, &mut Failures::default(),
+                                   &mut Stats::default(),
+                                   &mut Checkpoint::default());
         assert_matches!(result, Ok(()));
         let result_as_text = std::str::from_utf8(&output);
         if let Ok(text) = result_as_text {
@@ -392,5 +1289,229 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_process_line_timestamps() {
+        let json = r#"{ "createdAt": 1567341000, "projectData" : { "S": "{\"updatedAt\": 1567341000000}" } }"#;
+        let expected = r#"{"createdAt":"2019-09-01T12:30:00Z","projectData":{"S":{"updatedAt":"2019-09-01T12:30:00Z"}}}"#;
+        let bin_queries = &mut Queries::new(DEFAULT_BIN_PATH).unwrap();
+        let text_queries = &mut Queries::new(DEFAULT_TEXT_PATH).unwrap();
+        let transforms = &Transforms {
+            timestamps: Some(Timestamps::new("auto", TimestampFormat::Rfc3339).unwrap()),
+            ..Transforms::default()
+        };
+        let result = process_line(Ok(json.to_owned()), 0, 0, bin_queries, text_queries, transforms,
+                                  &mut Stats::default());
+        assert_matches!(result, Ok(ref actual) if actual.records == [expected])
+    }
+
+    #[test]
+    fn test_process_line_bad_timestamp() {
+        let json = r#"{ "createdAt": "soon" }"#;
+        let bin_queries = &mut Queries::new(DEFAULT_BIN_PATH).unwrap();
+        let text_queries = &mut Queries::new(DEFAULT_TEXT_PATH).unwrap();
+        let transforms = &Transforms {
+            timestamps: Some(Timestamps::new(".createdAt", TimestampFormat::Rfc3339).unwrap()),
+            ..Transforms::default()
+        };
+        let result = process_line(Ok(json.to_owned()), 4, 0, bin_queries, text_queries, transforms,
+                                  &mut Stats::default());
+        assert_matches!(result, Err(Error(ErrorKind::LineNo(5, false), _)))
+    }
+
+    #[test]
+    fn test_process_line_explode() {
+        let json = r#"{ "pk": { "S": "a" }, "projectData" : { "S": "{\"items\": [1, 2]}" } }"#;
+        let bin_queries = &mut Queries::new(DEFAULT_BIN_PATH).unwrap();
+        let text_queries = &mut Queries::new(DEFAULT_TEXT_PATH).unwrap();
+        let transforms = &Transforms {
+            explode: Some(Explode::new(".projectData.S.items[]", "_index").unwrap()),
+            ..Transforms::default()
+        };
+        let result = process_line(Ok(json.to_owned()), 0, 0, bin_queries, text_queries, transforms,
+                                  &mut Stats::default());
+        assert_matches!(result, Ok(ref actual) if actual.records == [
+            r#"{"pk":{"S":"a"},"projectData":{"S":{"items":1}},"_index":0}"#,
+            r#"{"pk":{"S":"a"},"projectData":{"S":{"items":2}},"_index":1}"#,
+        ])
+    }
+
+    #[test]
+    fn test_process_line_canonical() {
+        let json = r#"{ "z": 1.50, "projectData" : { "S": "{\"b\": 2e0, \"a\": [10.0]}" } }"#;
+        let expected = r#"{"projectData":{"S":{"a":[10],"b":2}},"z":1.5}"#;
+        let bin_queries = &mut Queries::new(DEFAULT_BIN_PATH).unwrap();
+        let text_queries = &mut Queries::new(DEFAULT_TEXT_PATH).unwrap();
+        let transforms = &Transforms { canonical: true, ..Transforms::default() };
+        let result = process_line(Ok(json.to_owned()), 0, 0, bin_queries, text_queries, transforms,
+                                  &mut Stats::default());
+        assert_matches!(result, Ok(ref actual) if actual.records == [expected])
+    }
+
+    #[test]
+    fn test_process_line_annotate() {
+        let json = r#"{ "projectData" : { "S": "{}" } }"#;
+        let bin_queries = &mut Queries::new(DEFAULT_BIN_PATH).unwrap();
+        let text_queries = &mut Queries::new(DEFAULT_TEXT_PATH).unwrap();
+        let transforms = &Transforms {
+            annotate: Some(Annotate::new("segment-1")),
+            ..Transforms::default()
+        };
+        let result = process_line(Ok(json.to_owned()), 2, 100, bin_queries, text_queries, transforms,
+                                  &mut Stats::default());
+        let record: Value = serde_json::from_str(&result.unwrap().records[0]).unwrap();
+        let meta = &record["_meta"];
+        assert_eq!(meta["line"], 3);
+        assert_eq!(meta["offset"], 100);
+        assert_eq!(meta["source"], "segment-1");
+        assert_eq!(meta["decoders"], serde_json::json!(["text"]));
+        assert_eq!(record["projectData"]["S"], serde_json::json!({}));
+    }
+
+    #[test]
+    fn test_process_input_sample_size() {
+        let data = (0..20).map(|i| format!(r#"{{"pk":{{"S":"{}"}}}}"#, i)).collect::<Vec<_>>().join("\n");
+        let mut output = Vec::<u8>::with_capacity(1024);
+        let bin_queries = &mut Queries::new(DEFAULT_BIN_PATH).unwrap();
+        let text_queries = &mut Queries::new(DEFAULT_TEXT_PATH).unwrap();
+        let sampler = &mut Sampler::new(Some(".pk.S"), None, Some(3)).unwrap();
+        let transforms = &Transforms {
+            annotate: Some(Annotate::new("stdin")),
+            ..Transforms::default()
+        };
+        let result = process_input(Cursor::new(data), &mut output, bin_queries, text_queries,
+                                   transforms, None, Some(sampler), &mut Failures::default(),
+                                   &mut Stats::default(),
+                                   &mut Checkpoint::default());
+        assert_matches!(result, Ok(()));
+        let lines: Vec<Value> = std::str::from_utf8(&output).unwrap().lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines.iter().all(|line| line["_meta"]["line"].as_u64().unwrap()
+            == line["pk"]["S"].as_str().unwrap().parse::<u64>().unwrap() + 1));
+        assert!(lines.windows(2).all(|pair| pair[0]["_meta"]["line"].as_u64() < pair[1]["_meta"]["line"].as_u64()));
+    }
+
+    #[test]
+    fn test_lines_with_offsets() {
+        let input = Cursor::new(b"a\r\nbc\n\xc3\x28\nd".to_vec());
+        let lines: Vec<(Range<u64>, io::Result<String>)> = lines_with_offsets(input, 0).collect();
+        assert_eq!(lines.len(), 4);
+        assert_matches!(lines[0], (Range { start: 0, end: 3 }, Ok(ref line)) if line == "a");
+        assert_matches!(lines[1], (Range { start: 3, end: 6 }, Ok(ref line)) if line == "bc");
+        assert_matches!(lines[2], (Range { start: 6, end: 9 }, Err(ref error)) if error.kind() == io::ErrorKind::InvalidData);
+        assert_matches!(lines[3], (Range { start: 9, end: 10 }, Ok(ref line)) if line == "d");
+    }
+
+    #[test]
+    fn test_process_input_dedupe() {
+        let first = r#"{ "pk": { "S": "a" }, "projectData" : { "S": "{\"v\": 1}" } }"#;
+        let second = r#"{ "pk": { "S": "a" }, "projectData" : { "S": "{\"v\": 2}" } }"#;
+        let other = r#"{ "pk": { "S": "b" } }"#;
+        let data = [first, other, second].join("\n");
+        let mut output = Vec::<u8>::with_capacity(1024);
+        let bin_queries = &mut Queries::new(DEFAULT_BIN_PATH).unwrap();
+        let text_queries = &mut Queries::new(DEFAULT_TEXT_PATH).unwrap();
+        let dedupe = &mut Dedupe::new(DedupeKeep::Last, DedupeStore::Memory).unwrap();
+        let transforms = &Transforms { dedupe_key: Some(DedupeKey::new(".pk.S").unwrap()), ..Transforms::default() };
+        let result = process_input(Cursor::new(data), &mut output, bin_queries, text_queries,
+                                   transforms, Some(dedupe), None, &mut Failures::default(),
+                                   &mut Stats::default(),
+                                   &mut Checkpoint::default());
+        assert_matches!(result, Ok(()));
+        assert_eq!(std::str::from_utf8(&output).unwrap(),
+                   "{\"pk\":{\"S\":\"b\"}}\n{\"pk\":{\"S\":\"a\"},\"projectData\":{\"S\":{\"v\":2}}}\n");
+        assert_eq!(dedupe.dropped(), 1);
+    }
+
+    #[test]
+    fn test_process_input_dedupe_explode() {
+        let data = [r#"{"pk":{"S":"a"},"items":[1,2]}"#, r#"{"pk":{"S":"a"},"items":[]}"#].join("\n");
+        let mut output = Vec::<u8>::new();
+        let bin_queries = &mut Queries::new(DEFAULT_BIN_PATH).unwrap();
+        let text_queries = &mut Queries::new(DEFAULT_TEXT_PATH).unwrap();
+        let dedupe = &mut Dedupe::new(DedupeKeep::Last, DedupeStore::Memory).unwrap();
+        let transforms = &Transforms {
+            dedupe_key: Some(DedupeKey::new(".pk.S").unwrap()),
+            explode: Some(Explode::new(".items[]", "_index").unwrap()),
+            ..Transforms::default()
+        };
+        let result = process_input(Cursor::new(data), &mut output, bin_queries, text_queries,
+                                   transforms, Some(dedupe), None, &mut Failures::default(),
+                                   &mut Stats::default(),
+                                   &mut Checkpoint::default());
+        assert_matches!(result, Ok(()));
+        assert_eq!(std::str::from_utf8(&output).unwrap(), "");
+        assert_eq!(dedupe.dropped(), 1);
+    }
+
+    #[test]
+    fn test_process_input_dead_letter() {
+        let bad_bin = r#"{ "projectBinaryData" : { "B": "H4sIAEafTF0AA8vMK0vMyUxRyCrOz+MCAIg5TZANAAAA" } }"#;
+        let bad_text = r#"{ "projectData" : { "S": "invalid json" } }"#;
+        let mut data = [bad_text, "{}", bad_bin, "not json", ""].join("\n").into_bytes();
+        data.extend_from_slice(&[0xc3, 0x28]);
+        let file = ::tempfile::NamedTempFile::new().unwrap();
+        let dead_letter = DeadLetter::new(file.path(), DEFAULT_BIN_PATH, DEFAULT_TEXT_PATH).unwrap();
+        let mut output = Vec::<u8>::new();
+        let bin_queries = &mut Queries::new(DEFAULT_BIN_PATH).unwrap();
+        let text_queries = &mut Queries::new(DEFAULT_TEXT_PATH).unwrap();
+        let result = process_input(Cursor::new(data), &mut output, bin_queries, text_queries,
+                                   &Transforms::default(), None, None,
+                                   &mut Failures::new(Some(dead_letter), ErrorBudget::default(), false),
+                                   &mut Stats::default(),
+                                   &mut Checkpoint::default());
+        assert_matches!(result, Ok(()));
+        let letters: Vec<Value> = std::fs::read_to_string(file.path()).unwrap().lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let summary: Vec<(u64, &str, &str)> = letters.iter()
+            .map(|letter| (letter["line"].as_u64().unwrap(), letter["error"].as_str().unwrap(),
+                           letter["path"].as_str().unwrap_or("")))
+            .collect();
+        assert_eq!(summary, vec![(1, "JqParseError", DEFAULT_TEXT_PATH), (3, "JqParseError", DEFAULT_BIN_PATH),
+                                 (4, "JqParseError", ""), (5, "Io", "")]);
+        assert_eq!(letters[0]["input"], ::serde_json::json!(bad_text));
+        assert_eq!(letters[3]["input"], ::serde_json::json!("\u{fffd}("));
+        assert_eq!(letters[3]["input_base64"], ::serde_json::json!("wyg="));
+        assert!(letters[0].get("input_base64").is_none());
+        assert_eq!(letters[0]["messages"][0], ::serde_json::json!("Error processing record number 1"));
+    }
+
+    #[test]
+    fn test_process_input_rejected() {
+        use ::arrow::datatypes::{DataType, Field, Schema};
+        let data = [r#"{"n":1}"#, r#"{"n":"many"}"#, r#"{"n":3}"#].join("\n");
+        let schema = Arc::new(Schema::new(vec![Field::new("n", DataType::Int64, true)]));
+        let format = ColumnarFormat::Arrow { ipc: ArrowIpc::Stream, batch_size: 10 };
+        for &strict in &[false, true] {
+            let file = ::tempfile::NamedTempFile::new().unwrap();
+            let dead_letter = DeadLetter::new(file.path(), DEFAULT_BIN_PATH, DEFAULT_TEXT_PATH).unwrap();
+            let columnar = &mut Columnar::new(Vec::new(), format, Some(schema.clone()), 1);
+            let mut output = MeasuredOutput::new(columnar);
+            let bin_queries = &mut Queries::new(DEFAULT_BIN_PATH).unwrap();
+            let text_queries = &mut Queries::new(DEFAULT_TEXT_PATH).unwrap();
+            let result = process_input(Cursor::new(data.clone()), &mut output, bin_queries, text_queries,
+                                       &Transforms::default(), None, None,
+                                       &mut Failures::new(Some(dead_letter), ErrorBudget::default(), strict),
+                                       &mut Stats::default(),
+                                       &mut Checkpoint::default());
+            if strict {
+                assert_matches!(result, Err(Error(ErrorKind::Strict, _)));
+                assert!(result.unwrap_err().iter().any(|error| error.to_string() == "Error processing record number 2"));
+            } else {
+                assert_matches!(result, Ok(()));
+            }
+            assert_eq!(output.records, 2);
+            let letters: Vec<Value> = std::fs::read_to_string(file.path()).unwrap().lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect();
+            assert_eq!(letters.len(), 1);
+            assert_eq!(letters[0]["line"], ::serde_json::json!(2));
+            assert_eq!(letters[0]["error"], ::serde_json::json!("SchemaMismatch"));
+            assert_eq!(letters[0]["input"], ::serde_json::json!(r#"{"n":"many"}"#));
+        }
+    }
+
     // TODO: assert stderr output on bad input data from process_input
 }
@@ -0,0 +1,304 @@
+use std::io::Write;
+use std::str::FromStr;
+
+use ::serde_json::{self, Value};
+
+use crate::errors::*;
+
+/// How output records are written
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub(crate) enum OutputFormat {
+    Json,
+    Csv,
+    Tsv,
+    Parquet,
+    Avro,
+    Arrow,
+    Sqlite,
+    PgCopy,
+    SqlInsert,
+    EsBulk,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(OutputFormat::Json),
+            "csv" => Ok(OutputFormat::Csv),
+            "tsv" => Ok(OutputFormat::Tsv),
+            "parquet" => Ok(OutputFormat::Parquet),
+            "avro" => Ok(OutputFormat::Avro),
+            "arrow" => Ok(OutputFormat::Arrow),
+            "sqlite" => Ok(OutputFormat::Sqlite),
+            "pg-copy" => Ok(OutputFormat::PgCopy),
+            "sql-insert" => Ok(OutputFormat::SqlInsert),
+            "es-bulk" => Ok(OutputFormat::EsBulk),
+            _ => Err(format!("unknown format '{}', expected json, csv, tsv, parquet, avro, arrow, sqlite, \
+                              pg-copy, sql-insert or es-bulk", s))
+        }
+    }
+}
+
+impl OutputFormat {
+    /// File extension of the format
+    pub(crate) fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Json => "jsonl",
+            OutputFormat::Csv => "csv",
+            OutputFormat::Tsv => "tsv",
+            OutputFormat::Parquet => "parquet",
+            OutputFormat::Avro => "avro",
+            OutputFormat::Arrow => "arrow",
+            OutputFormat::Sqlite => "db",
+            OutputFormat::PgCopy | OutputFormat::SqlInsert => "sql",
+            OutputFormat::EsBulk => "ndjson",
+        }
+    }
+}
+
+/// Input line that records come from
+#[derive(Clone,Debug,Default,PartialEq)]
+pub(crate) struct Source {
+    pub(crate) index: usize,
+    /// Only kept when failures need it
+    pub(crate) line: Option<String>,
+}
+
+/// A record that was held back, such as to infer a schema from, and then
+/// couldn't be written
+#[derive(Debug)]
+pub(crate) struct Rejected {
+    pub(crate) error: Error,
+    pub(crate) source: Source,
+}
+
+/// Destination of the output records, which arrive as serialized json
+pub(crate) trait RecordWriter {
+    /// Where the next records come from, for writers that hold records
+    /// back and can fail them later
+    fn set_source(&mut self, _source: &Source) {}
+
+    fn write_record(&mut self, record: &str) -> Result<()>;
+
+    /// Records held back that failed since the last call
+    fn take_rejected(&mut self) -> Vec<Rejected> {
+        Vec::new()
+    }
+
+    /// Called once all records have been written
+    fn finish(&mut self) -> Result<()>;
+}
+
+/// Anything that can be written to takes json lines
+impl<W: Write> RecordWriter for W {
+    fn write_record(&mut self, record: &str) -> Result<()> {
+        writeln!(self, "{}", record)?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.flush()?;
+        Ok(())
+    }
+}
+
+/// When pretty output is colourised
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub(crate) enum ColorChoice {
+    /// Only on a terminal, unless NO_COLOR is set
+    Auto,
+    Always,
+    Never,
+}
+
+impl FromStr for ColorChoice {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(ColorChoice::Auto),
+            "always" => Ok(ColorChoice::Always),
+            "never" => Ok(ColorChoice::Never),
+            _ => Err(format!("unknown color choice '{}', expected auto, always or never", s))
+        }
+    }
+}
+
+// Colours of jq's default JQ_COLORS
+const COLOR_NULL: &str = "1;30";
+const COLOR_SCALAR: &str = "0;39";
+const COLOR_STRING: &str = "0;32";
+const COLOR_CONTAINER: &str = "1;39";
+const COLOR_KEY: &str = "34;1";
+
+/// Writes json records one per line, or indented, or as the elements of a
+/// single json array, which is streamed with its commas as records come.
+/// Records that fail before reaching the writer leave no trace on it.
+#[derive(Debug)]
+pub(crate) struct JsonWriter<W: Write> {
+    output: W,
+    pretty: bool,
+    color: bool,
+    array: bool,
+    written: u64,
+}
+
+impl<W: Write> JsonWriter<W> {
+    pub(crate) fn new(output: W, pretty: bool, color: bool, array: bool) -> JsonWriter<W> {
+        JsonWriter { output, pretty, color: pretty && color, array, written: 0 }
+    }
+}
+
+impl<W: Write> RecordWriter for JsonWriter<W> {
+    fn write_record(&mut self, record: &str) -> Result<()> {
+        let rendered = if self.pretty {
+            let value: Value = serde_json::from_str(record)?;
+            let mut rendered = String::new();
+            render(&mut rendered, &value, if self.array { 1 } else { 0 }, self.color);
+            rendered
+        } else {
+            record.to_owned()
+        };
+        match (self.array, self.pretty, self.written) {
+            (false, _, _) => writeln!(self.output, "{}", rendered)?,
+            (true, false, 0) => write!(self.output, "[{}", rendered)?,
+            (true, false, _) => write!(self.output, ",{}", rendered)?,
+            (true, true, 0) => write!(self.output, "[\n  {}", rendered)?,
+            (true, true, _) => write!(self.output, ",\n  {}", rendered)?,
+        }
+        self.written += 1;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        match (self.array, self.pretty, self.written) {
+            (false, _, _) => (),
+            (true, _, 0) => writeln!(self.output, "[]")?,
+            (true, false, _) => writeln!(self.output, "]")?,
+            (true, true, _) => writeln!(self.output, "\n]")?,
+        }
+        self.output.flush()?;
+        Ok(())
+    }
+}
+
+/// Appends the value indented by two spaces per level, from `depth`
+fn render(out: &mut String, value: &Value, depth: usize, color: bool) {
+    let paint = |out: &mut String, code: &str, text: &str| if color {
+        out.push_str(&format!("\x1b[{}m{}\x1b[0m", code, text));
+    } else {
+        out.push_str(text);
+    };
+    let newline = |out: &mut String, depth: usize| {
+        out.push('\n');
+        out.push_str(&"  ".repeat(depth));
+    };
+    match value {
+        Value::Null => paint(out, COLOR_NULL, "null"),
+        Value::Bool(_) | Value::Number(_) => paint(out, COLOR_SCALAR, &value.to_string()),
+        Value::String(_) => paint(out, COLOR_STRING, &value.to_string()),
+        Value::Array(vec) if vec.is_empty() => paint(out, COLOR_CONTAINER, "[]"),
+        Value::Object(map) if map.is_empty() => paint(out, COLOR_CONTAINER, "{}"),
+        Value::Array(vec) => {
+            paint(out, COLOR_CONTAINER, "[");
+            for (index, element) in vec.iter().enumerate() {
+                if index > 0 {
+                    paint(out, COLOR_CONTAINER, ",");
+                }
+                newline(out, depth + 1);
+                render(out, element, depth + 1, color);
+            }
+            newline(out, depth);
+            paint(out, COLOR_CONTAINER, "]");
+        },
+        Value::Object(map) => {
+            paint(out, COLOR_CONTAINER, "{");
+            for (index, (key, element)) in map.iter().enumerate() {
+                if index > 0 {
+                    paint(out, COLOR_CONTAINER, ",");
+                }
+                newline(out, depth + 1);
+                paint(out, COLOR_KEY, &Value::String(key.clone()).to_string());
+                paint(out, COLOR_CONTAINER, ":");
+                out.push(' ');
+                render(out, element, depth + 1, color);
+            }
+            newline(out, depth);
+            paint(out, COLOR_CONTAINER, "}");
+        },
+    }
+}
+
+/// Parses a byte size such as 512K, 128M or 1G, in powers of 1024
+pub(crate) fn parse_bytes(size: &str) -> std::result::Result<u64, String> {
+    let trimmed = size.trim().trim_end_matches(['B', 'b']);
+    let (number, unit) = match trimmed.char_indices().last() {
+        Some((i, c)) if c.is_ascii_alphabetic() => (&trimmed[..i], c.to_ascii_uppercase()),
+        _ => (trimmed, ' '),
+    };
+    let multiplier: u64 = match unit {
+        ' ' => 1,
+        'K' => 1 << 10,
+        'M' => 1 << 20,
+        'G' => 1 << 30,
+        'T' => 1 << 40,
+        _ => return Err(format!("unknown size unit in '{}', expected K, M, G or T", size)),
+    };
+    match number.trim().parse::<u64>() {
+        Ok(number) if number > 0 => number.checked_mul(multiplier)
+            .ok_or_else(|| format!("size '{}' is too large", size)),
+        _ => Err(format!("size must be a positive number of bytes, got '{}'", size)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_json(pretty: bool, color: bool, array: bool, records: &[&str]) -> String {
+        let mut writer = JsonWriter::new(Vec::new(), pretty, color, array);
+        for record in records {
+            writer.write_record(record).unwrap();
+        }
+        writer.finish().unwrap();
+        String::from_utf8(writer.output).unwrap()
+    }
+
+    #[test]
+    fn test_json_array() {
+        assert_eq!(write_json(false, false, true, &[r#"{"a":1}"#, "2"]), "[{\"a\":1},2]\n");
+        assert_eq!(write_json(false, false, true, &[]), "[]\n");
+        assert_eq!(write_json(true, false, true, &[]), "[]\n");
+        assert_eq!(write_json(false, false, false, &["1", "2"]), "1\n2\n");
+    }
+
+    #[test]
+    fn test_json_pretty() {
+        let records = &[r#"{"a":[1,{}],"b":"x"}"#, "null"];
+        assert_eq!(write_json(true, false, false, records),
+                   "{\n  \"a\": [\n    1,\n    {}\n  ],\n  \"b\": \"x\"\n}\nnull\n");
+        assert_eq!(write_json(true, false, true, records),
+                   "[\n  {\n    \"a\": [\n      1,\n      {}\n    ],\n    \"b\": \"x\"\n  },\n  null\n]\n");
+    }
+
+    #[test]
+    fn test_json_color() {
+        assert_eq!(write_json(true, true, false, &[r#"{"k":[null]}"#]),
+                   "\x1b[1;39m{\x1b[0m\n  \x1b[34;1m\"k\"\x1b[0m\x1b[1;39m:\x1b[0m \x1b[1;39m[\x1b[0m\n    \
+                    \x1b[1;30mnull\x1b[0m\n  \x1b[1;39m]\x1b[0m\n\x1b[1;39m}\x1b[0m\n");
+        assert_eq!(write_json(false, true, false, &[r#"{"k":1}"#]), "{\"k\":1}\n");
+    }
+
+    #[test]
+    fn test_parse_bytes() {
+        assert_eq!(parse_bytes("100"), Ok(100));
+        assert_eq!(parse_bytes("512K"), Ok(512 * 1024));
+        assert_eq!(parse_bytes("128MB"), Ok(128 * 1024 * 1024));
+        assert_eq!(parse_bytes("1g"), Ok(1 << 30));
+        assert!(parse_bytes("0").is_err());
+        assert!(parse_bytes("1X").is_err());
+        assert!(parse_bytes("M").is_err());
+    }
+}
@@ -0,0 +1,226 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io;
+use std::path::PathBuf;
+
+use ::serde_json::{self, Value};
+
+use crate::errors::*;
+use crate::output::{RecordWriter, Rejected, Source};
+use crate::rotate::OpenPart;
+use crate::tabular::Column;
+
+/// Partition of records without a value for a partition key, as Hive names it
+const DEFAULT_PARTITION: &str = "__HIVE_DEFAULT_PARTITION__";
+
+/// A partition's open part, and when it was last written
+struct OpenPartition {
+    writer: Box<dyn RecordWriter>,
+    last_used: u64,
+}
+
+/// Writes records under Hive-style directories, such as
+/// `customer=acme/date=2019-09-01/part-0.jsonl`, one level per partition
+/// column.
+///
+/// At most `max_open` parts are open at once. When another one is needed,
+/// the least recently used is finished, and that partition continues on a
+/// new part if more records come for it later.
+pub(crate) struct Partitioned<'a> {
+    directory: PathBuf,
+    columns: Vec<Column>,
+    extension: String,
+    max_open: usize,
+    open: OpenPart<'a>,
+    partitions: HashMap<PathBuf, OpenPartition>,
+    /// Number of the next part of each partition
+    next_part: HashMap<PathBuf, usize>,
+    clock: u64,
+    source: Source,
+    rejected: Vec<Rejected>,
+}
+
+impl std::fmt::Debug for Partitioned<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::result::Result<(), std::fmt::Error> {
+        write!(f, "Partitioned {{ directory = {:?}, columns = {:?}, open = {} }}",
+               self.directory, self.columns, self.partitions.len())
+    }
+}
+
+impl<'a> Partitioned<'a> {
+    pub(crate) fn new(directory: PathBuf, columns: Vec<Column>, extension: &str, max_open: usize,
+                      open: OpenPart<'a>) -> Partitioned<'a> {
+        Partitioned {
+            directory,
+            columns,
+            extension: extension.to_owned(),
+            max_open: max_open.max(1),
+            open,
+            partitions: HashMap::new(),
+            next_part: HashMap::new(),
+            clock: 0,
+            source: Source::default(),
+            rejected: Vec::new(),
+        }
+    }
+
+    /// Directory of the record's partition, relative to the output directory
+    fn partition(&self, record: &Value) -> PathBuf {
+        self.columns.iter()
+            .map(|column| {
+                let value = column.path.get(record).and_then(partition_value)
+                    .filter(|value| !value.is_empty())
+                    .map(|value| escape(&value))
+                    .unwrap_or_else(|| DEFAULT_PARTITION.to_owned());
+                format!("{}={}", escape(&column.name), value)
+            })
+            .collect()
+    }
+
+    /// Finishes the part that was written the longest ago
+    fn close_least_recent(&mut self) -> Result<()> {
+        let oldest = self.partitions.iter()
+            .min_by_key(|(_, partition)| partition.last_used)
+            .map(|(path, _)| path.clone());
+        if let Some(mut partition) = oldest.and_then(|path| self.partitions.remove(&path)) {
+            partition.writer.finish()?;
+            self.rejected.extend(partition.writer.take_rejected());
+        }
+        Ok(())
+    }
+
+    fn open_partition(&mut self, partition: &PathBuf) -> Result<OpenPartition> {
+        if self.partitions.len() >= self.max_open {
+            self.close_least_recent()?;
+        }
+        let directory = self.directory.join(partition);
+        fs::create_dir_all(&directory)?;
+        let number = self.next_part.entry(partition.clone()).or_insert(0);
+        let file = File::create(directory.join(format!("part-{}.{}", number, self.extension)))?;
+        *number += 1;
+        let writer = (self.open)(Box::new(io::BufWriter::new(file)))?;
+        Ok(OpenPartition { writer, last_used: 0 })
+    }
+}
+
+impl RecordWriter for Partitioned<'_> {
+    fn set_source(&mut self, source: &Source) {
+        self.source.clone_from(source);
+    }
+
+    fn write_record(&mut self, record: &str) -> Result<()> {
+        let value: Value = serde_json::from_str(record)?;
+        let partition = self.partition(&value);
+        if !self.partitions.contains_key(&partition) {
+            let open = self.open_partition(&partition)?;
+            let _ = self.partitions.insert(partition.clone(), open);
+        }
+        self.clock += 1;
+        match self.partitions.get_mut(&partition) {
+            Some(open) => {
+                open.last_used = self.clock;
+                open.writer.set_source(&self.source);
+                let written = open.writer.write_record(record);
+                self.rejected.extend(open.writer.take_rejected());
+                written
+            },
+            None => Ok(()),
+        }
+    }
+
+    fn take_rejected(&mut self) -> Vec<Rejected> {
+        std::mem::take(&mut self.rejected)
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        for (_, mut partition) in self.partitions.drain() {
+            partition.writer.finish()?;
+            self.rejected.extend(partition.writer.take_rejected());
+        }
+        Ok(())
+    }
+}
+
+/// Text of a partition value: strings, numbers and booleans, also inside
+/// DynamoDB S, N and BOOL
+fn partition_value(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Bool(b) => Some(b.to_string()),
+        Value::Object(map) if map.len() == 1 =>
+            map.get("S").or_else(|| map.get("N")).or_else(|| map.get("BOOL")).and_then(partition_value),
+        _ => None,
+    }
+}
+
+/// Escapes characters that can't go in a path or would be confused with
+/// its structure, as %XX, like Hive does
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\u{0}'..='\u{1f}' | '"' | '#' | '%' | '\'' | '*' | '/' | ':' | '=' | '?' | '\\' | '\u{7f}'
+            | '{' | '[' | ']' | '^' => escaped.push_str(&format!("%{:02X}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    if escaped == "." || escaped == ".." {
+        escaped.replace('.', "%2E")
+    } else {
+        escaped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::JsonWriter;
+    use crate::tabular::parse_columns;
+
+    fn partitioned(directory: PathBuf, max_open: usize) -> Partitioned<'static> {
+        let columns = parse_columns("customer=.customer.S, date=.date").unwrap();
+        Partitioned::new(directory, columns, "jsonl", max_open,
+                         Box::new(|file| Ok(Box::new(JsonWriter::new(file, false, false, false)) as Box<dyn RecordWriter>)))
+    }
+
+    fn read(directory: &std::path::Path, file: &str) -> String {
+        fs::read_to_string(directory.join(file)).unwrap()
+    }
+
+    #[test]
+    fn test_partitions() {
+        let directory = ::tempfile::tempdir().unwrap();
+        let mut partitioned = partitioned(directory.path().to_owned(), 10);
+        for record in &[
+            r#"{"customer":{"S":"acme"},"date":"2019-09-01","n":1}"#,
+            r#"{"customer":{"S":"a/b"},"date":"..","n":2}"#,
+            r#"{"customer":{"S":"acme"},"date":"2019-09-01","n":3}"#,
+            r#"{"date":null,"n":4}"#,
+        ] {
+            partitioned.write_record(record).unwrap();
+        }
+        partitioned.finish().unwrap();
+        let path = directory.path();
+        assert_eq!(read(path, "customer=acme/date=2019-09-01/part-0.jsonl").lines().count(), 2);
+        assert!(read(path, "customer=a%2Fb/date=%2E%2E/part-0.jsonl").contains("\"n\":2"));
+        assert!(read(path, "customer=__HIVE_DEFAULT_PARTITION__/date=__HIVE_DEFAULT_PARTITION__/part-0.jsonl")
+            .contains("\"n\":4"));
+    }
+
+    #[test]
+    fn test_least_recently_used() {
+        let directory = ::tempfile::tempdir().unwrap();
+        let mut partitioned = partitioned(directory.path().to_owned(), 2);
+        for (customer, n) in &[("a", 1), ("b", 2), ("a", 3), ("c", 4), ("a", 5), ("b", 6)] {
+            partitioned.write_record(&format!(r#"{{"customer":{{"S":"{}"}},"date":1,"n":{}}}"#, customer, n)).unwrap();
+            assert!(partitioned.partitions.len() <= 2);
+        }
+        partitioned.finish().unwrap();
+        let path = directory.path();
+        assert_eq!(read(path, "customer=a/date=1/part-0.jsonl").lines().count(), 3);
+        assert!(read(path, "customer=b/date=1/part-0.jsonl").contains("\"n\":2"));
+        assert!(read(path, "customer=b/date=1/part-1.jsonl").contains("\"n\":6"));
+        assert!(read(path, "customer=c/date=1/part-0.jsonl").contains("\"n\":4"));
+    }
+}
@@ -0,0 +1,198 @@
+use std::io::Write;
+
+use ::error_chain::bail;
+use ::serde_json::{self, Value};
+
+use crate::errors::*;
+use crate::json_queries::JsonPath;
+use crate::output::RecordWriter;
+use crate::tabular::Column;
+
+/// Statements loading the records into PostgreSQL
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub(crate) enum SqlStatement {
+    /// COPY ... FROM STDIN blocks, in the text format
+    Copy,
+    /// Multi-row INSERT statements
+    Insert,
+}
+
+/// Writes records as a psql script that loads them into a table of jsonb
+/// columns, `batch_size` rows per statement.
+///
+/// Each column holds the json at its path, or NULL if it's missing. The
+/// default column is `data`, with the whole record.
+#[derive(Debug)]
+pub(crate) struct Postgres<W: Write> {
+    output: W,
+    statement: SqlStatement,
+    /// Statement start, up to the rows
+    prefix: String,
+    columns: Vec<Column>,
+    batch_size: usize,
+    pending: usize,
+}
+
+impl<W: Write> Postgres<W> {
+    pub(crate) fn new(output: W, statement: SqlStatement, table: &str, columns: Option<Vec<Column>>,
+                      batch_size: usize) -> Result<Postgres<W>> {
+        let columns = match columns {
+            Some(columns) => columns,
+            None => vec![Column { name: "data".to_owned(), path: JsonPath::new(".")? }],
+        };
+        let names: Vec<String> = columns.iter().map(|column| quote(&column.name)).collect();
+        let prefix = match statement {
+            SqlStatement::Copy => format!("COPY {} ({}) FROM STDIN;\n", quote_table(table), names.join(", ")),
+            SqlStatement::Insert => format!("INSERT INTO {} ({}) VALUES\n", quote_table(table), names.join(", ")),
+        };
+        Ok(Postgres { output, statement, prefix, columns, batch_size: batch_size.max(1), pending: 0 })
+    }
+
+    fn end_statement(&mut self) -> Result<()> {
+        match self.statement {
+            SqlStatement::Copy => self.output.write_all(b"\\.\n")?,
+            SqlStatement::Insert => self.output.write_all(b";\n")?,
+        }
+        self.pending = 0;
+        Ok(())
+    }
+}
+
+impl<W: Write> RecordWriter for Postgres<W> {
+    fn write_record(&mut self, record: &str) -> Result<()> {
+        let record: Value = serde_json::from_str(record)?;
+        let values = self.columns.iter()
+            .map(|column| match column.path.get(&record) {
+                Some(value) if has_nul(value) =>
+                    bail!(ErrorKind::SchemaMismatch(format!("{} has a \\u0000, which jsonb can't hold", column.name))),
+                Some(value) => Ok(Some(value.to_string())),
+                None => Ok(None),
+            })
+            .collect::<Result<Vec<Option<String>>>>()?;
+
+        if self.pending == 0 {
+            self.output.write_all(self.prefix.as_bytes())?;
+        }
+        let row = match self.statement {
+            SqlStatement::Copy => {
+                let fields: Vec<String> = values.iter()
+                    .map(|value| value.as_deref().map_or_else(|| "\\N".to_owned(), copy_escape))
+                    .collect();
+                format!("{}\n", fields.join("\t"))
+            },
+            SqlStatement::Insert => {
+                let fields: Vec<String> = values.iter()
+                    .map(|value| value.as_deref().map_or_else(|| "NULL".to_owned(), literal))
+                    .collect();
+                let separator = if self.pending == 0 { "" } else { ",\n" };
+                format!("{}({})", separator, fields.join(", "))
+            },
+        };
+        self.output.write_all(row.as_bytes())?;
+        self.pending += 1;
+        if self.pending >= self.batch_size {
+            self.end_statement()?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        if self.pending > 0 {
+            self.end_statement()?;
+        }
+        self.output.flush()?;
+        Ok(())
+    }
+}
+
+/// Whether a string or key inside the value has a NUL character
+fn has_nul(value: &Value) -> bool {
+    match value {
+        Value::String(s) => s.contains('\0'),
+        Value::Array(vec) => vec.iter().any(has_nul),
+        Value::Object(map) => map.iter().any(|(key, value)| key.contains('\0') || has_nul(value)),
+        _ => false,
+    }
+}
+
+/// Escapes a field of the COPY text format
+fn copy_escape(field: &str) -> String {
+    let mut escaped = String::with_capacity(field.len());
+    for c in field.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// A jsonb literal, for standard conforming strings
+fn literal(json: &str) -> String {
+    format!("'{}'::jsonb", json.replace('\'', "''"))
+}
+
+/// Quotes an identifier
+fn quote(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// Quotes a table name, which may be qualified by its schema
+fn quote_table(table: &str) -> String {
+    table.split('.').map(quote).collect::<Vec<String>>().join(".")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tabular::parse_columns;
+    use ::assert_matches::assert_matches;
+
+    fn write(statement: SqlStatement, columns: Option<&str>, batch_size: usize, records: &[&str]) -> String {
+        let columns = columns.map(|columns| parse_columns(columns).unwrap());
+        let mut postgres = Postgres::new(Vec::new(), statement, "public.export", columns, batch_size).unwrap();
+        for record in records {
+            postgres.write_record(record).unwrap();
+        }
+        postgres.finish().unwrap();
+        String::from_utf8(postgres.output).unwrap()
+    }
+
+    #[test]
+    fn test_copy() {
+        let records = &[r#"{"pk":{"S":"a"},"text":"it's a\\b\n"}"#, r#"{"pk":{"S":"b"}}"#, r#"{"text":"\t"}"#];
+        let copy = write(SqlStatement::Copy, Some("id=.pk.S, .text"), 2, records);
+        assert_eq!(copy, concat!(
+            "COPY \"public\".\"export\" (\"id\", \"text\") FROM STDIN;\n",
+            "\"a\"\t\"it's a\\\\\\\\b\\\\n\"\n",
+            "\"b\"\t\\N\n",
+            "\\.\n",
+            "COPY \"public\".\"export\" (\"id\", \"text\") FROM STDIN;\n",
+            "\\N\t\"\\\\t\"\n",
+            "\\.\n"));
+    }
+
+    #[test]
+    fn test_insert() {
+        let records = &[r#"{"name":"it's"}"#, r#"[1,"\\"]"#, "null"];
+        let insert = write(SqlStatement::Insert, None, 2, records);
+        assert_eq!(insert, concat!(
+            "INSERT INTO \"public\".\"export\" (\"data\") VALUES\n",
+            "('{\"name\":\"it''s\"}'::jsonb),\n",
+            "('[1,\"\\\\\"]'::jsonb);\n",
+            "INSERT INTO \"public\".\"export\" (\"data\") VALUES\n",
+            "('null'::jsonb);\n"));
+        assert_eq!(write(SqlStatement::Insert, None, 2, &[]), "");
+    }
+
+    #[test]
+    fn test_nul() {
+        let mut postgres = Postgres::new(Vec::new(), SqlStatement::Copy, "t", None, 10).unwrap();
+        assert_matches!(postgres.write_record(r#"{"a\u0000":1}"#), Err(Error(ErrorKind::SchemaMismatch(_), _)));
+        postgres.finish().unwrap();
+        assert!(postgres.output.is_empty());
+    }
+}
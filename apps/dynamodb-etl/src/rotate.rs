@@ -0,0 +1,324 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use ::error_chain::bail;
+use ::serde_json::{self, json, Value};
+use ::sha2::{Digest, Sha256};
+
+use crate::annotate::hex;
+use crate::errors::*;
+use crate::output::{RecordWriter, Rejected, Source};
+
+/// Name of the manifest written next to the parts
+const MANIFEST: &str = "manifest.json";
+
+/// When to start a new part. Sizes are checked between records, so parts
+/// can go over them by a record, or by what a format buffers.
+#[derive(Clone,Copy,Debug,Default)]
+pub(crate) struct Rotation {
+    pub(crate) bytes: Option<u64>,
+    pub(crate) records: Option<u64>,
+}
+
+impl Rotation {
+    pub(crate) fn is_set(&self) -> bool {
+        self.bytes.is_some() || self.records.is_some()
+    }
+}
+
+/// Creates the record writer of each part, on its file
+pub(crate) type OpenPart<'a> = Box<dyn FnMut(Box<dyn Write + Send>) -> Result<Box<dyn RecordWriter>> + 'a>;
+
+/// A part file name pattern, with a single printf-like `%d`, `%5d` or `%05d`
+#[derive(Clone,Debug,PartialEq)]
+struct PartPattern {
+    before: String,
+    zero: bool,
+    width: usize,
+    after: String,
+}
+
+impl PartPattern {
+    fn new(pattern: &str) -> Result<PartPattern> {
+        let invalid = |reason: &str| ErrorKind::InvalidArguments(format!("output pattern '{}' {}", pattern, reason));
+        let mut text = String::new();
+        let mut number = None;
+        let mut chars = pattern.chars().peekable();
+        while let Some(c) = chars.next() {
+            if c != '%' {
+                text.push(c);
+                continue;
+            }
+            if chars.peek() == Some(&'%') {
+                text.push('%');
+                let _ = chars.next();
+                continue;
+            }
+            let mut spec = String::new();
+            while let Some(digit) = chars.peek().filter(|c| c.is_ascii_digit()) {
+                spec.push(*digit);
+                let _ = chars.next();
+            }
+            if chars.next() != Some('d') {
+                bail!(invalid("only takes %d, %5d or %05d"));
+            }
+            if number.is_some() {
+                bail!(invalid("has more than one %d"));
+            }
+            number = Some((spec.starts_with('0'), spec.parse().unwrap_or(0), std::mem::take(&mut text)));
+        }
+        match number {
+            Some((zero, width, before)) => Ok(PartPattern { before, zero, width, after: text }),
+            None => bail!(invalid("needs a %d for the part number")),
+        }
+    }
+
+    fn path(&self, number: usize) -> PathBuf {
+        let number = match self.zero {
+            true => format!("{:0width$}", number, width = self.width),
+            false => format!("{:width$}", number, width = self.width),
+        };
+        PathBuf::from(format!("{}{}{}", self.before, number, self.after))
+    }
+}
+
+/// Bytes and checksum of what has been written to a part
+#[derive(Debug)]
+struct Progress {
+    bytes: u64,
+    sha256: Sha256,
+}
+
+/// Keeps track of the bytes going through it
+#[derive(Debug)]
+struct Counting<W: Write> {
+    inner: W,
+    progress: Arc<Mutex<Progress>>,
+}
+
+impl<W: Write> Write for Counting<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        let mut progress = self.progress.lock().map_err(|_| io::Error::other("part progress poisoned"))?;
+        progress.bytes += written as u64;
+        progress.sha256.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// The part being written
+struct Part {
+    writer: Box<dyn RecordWriter>,
+    progress: Arc<Mutex<Progress>>,
+    temp: PathBuf,
+    path: PathBuf,
+    records: u64,
+}
+
+/// Writes records to numbered parts, starting a new one when the current
+/// one is over the rotation size. Parts are written under a hidden temp
+/// name and renamed once complete, and a manifest with the records, bytes
+/// and sha256 of each part is written next to them at the end.
+pub(crate) struct Rotating<'a> {
+    pattern: PartPattern,
+    rotation: Rotation,
+    open: OpenPart<'a>,
+    current: Option<Part>,
+    parts: Vec<Value>,
+    source: Source,
+    rejected: Vec<Rejected>,
+}
+
+impl std::fmt::Debug for Rotating<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::result::Result<(), std::fmt::Error> {
+        write!(f, "Rotating {{ pattern = {:?}, rotation = {:?}, parts = {} }}",
+               self.pattern, self.rotation, self.parts.len())
+    }
+}
+
+impl<'a> Rotating<'a> {
+    /// Opens the first part straight away, so that output errors show up
+    /// before any input is read
+    pub(crate) fn new(pattern: &Path, rotation: Rotation, open: OpenPart<'a>) -> Result<Rotating<'a>> {
+        let pattern = PartPattern::new(&pattern.to_string_lossy())?;
+        let mut rotating = Rotating {
+            pattern,
+            rotation,
+            open,
+            current: None,
+            parts: Vec::new(),
+            source: Source::default(),
+            rejected: Vec::new(),
+        };
+        rotating.open_part()?;
+        Ok(rotating)
+    }
+
+    fn open_part(&mut self) -> Result<()> {
+        let path = self.pattern.path(self.parts.len());
+        let name = path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+        let temp = path.with_file_name(format!(".{}.tmp", name));
+        let progress = Arc::new(Mutex::new(Progress { bytes: 0, sha256: Sha256::new() }));
+        let file = Counting { inner: io::BufWriter::new(File::create(&temp)?), progress: progress.clone() };
+        let writer = (self.open)(Box::new(file))?;
+        self.current = Some(Part { writer, progress, temp, path, records: 0 });
+        Ok(())
+    }
+
+    fn close_part(&mut self) -> Result<()> {
+        let mut part = match self.current.take() {
+            Some(part) => part,
+            None => return Ok(()),
+        };
+        part.writer.finish()?;
+        let rejected = part.writer.take_rejected();
+        part.records -= rejected.len() as u64;
+        self.rejected.extend(rejected);
+        drop(part.writer);
+        fs::rename(&part.temp, &part.path)?;
+        let progress = match Arc::try_unwrap(part.progress).map(Mutex::into_inner) {
+            Ok(Ok(progress)) => progress,
+            _ => bail!(ErrorKind::Msg(format!("{} is still being written", part.path.display()))),
+        };
+        self.parts.push(json!({
+            "file": part.path.file_name().map(|name| name.to_string_lossy().into_owned()),
+            "records": part.records,
+            "bytes": progress.bytes,
+            "sha256": hex(&progress.sha256.finalize()),
+        }));
+        Ok(())
+    }
+
+    fn is_full(&self) -> bool {
+        let part = match self.current {
+            Some(ref part) => part,
+            None => return false,
+        };
+        let bytes = part.progress.lock().map_or(0, |progress| progress.bytes);
+        self.rotation.records.is_some_and(|records| part.records >= records)
+            || self.rotation.bytes.is_some_and(|max| bytes >= max)
+    }
+
+    fn write_manifest(&self) -> Result<()> {
+        let directory = match self.pattern.path(0).parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent.to_owned(),
+            _ => PathBuf::from("."),
+        };
+        let manifest = json!({
+            "parts": self.parts,
+            "records": self.parts.iter().filter_map(|part| part["records"].as_u64()).sum::<u64>(),
+            "bytes": self.parts.iter().filter_map(|part| part["bytes"].as_u64()).sum::<u64>(),
+        });
+        let temp = directory.join(format!(".{}.tmp", MANIFEST));
+        fs::write(&temp, serde_json::to_string_pretty(&manifest)? + "\n")?;
+        fs::rename(&temp, directory.join(MANIFEST))?;
+        Ok(())
+    }
+}
+
+impl RecordWriter for Rotating<'_> {
+    fn set_source(&mut self, source: &Source) {
+        self.source.clone_from(source);
+    }
+
+    fn write_record(&mut self, record: &str) -> Result<()> {
+        if self.is_full() {
+            self.close_part()?;
+            self.open_part()?;
+        }
+        if let Some(ref mut part) = self.current {
+            part.writer.set_source(&self.source);
+            let written = part.writer.write_record(record);
+            part.records += written.is_ok() as u64;
+            let rejected = part.writer.take_rejected();
+            part.records -= rejected.len() as u64;
+            self.rejected.extend(rejected);
+            written?;
+        }
+        Ok(())
+    }
+
+    fn take_rejected(&mut self) -> Vec<Rejected> {
+        std::mem::take(&mut self.rejected)
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.close_part()?;
+        self.write_manifest()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::JsonWriter;
+    use ::assert_matches::assert_matches;
+
+    fn json_parts<'a>() -> OpenPart<'a> {
+        Box::new(|file| Ok(Box::new(JsonWriter::new(file, false, false, false)) as Box<dyn RecordWriter>))
+    }
+
+    #[test]
+    fn test_part_pattern() {
+        assert_eq!(PartPattern::new("out/part-%05d.jsonl").unwrap().path(42), PathBuf::from("out/part-00042.jsonl"));
+        assert_eq!(PartPattern::new("p%3d-100%%").unwrap().path(7), PathBuf::from("p  7-100%"));
+        assert_eq!(PartPattern::new("%d").unwrap().path(12), PathBuf::from("12"));
+        assert_matches!(PartPattern::new("part.jsonl"), Err(Error(ErrorKind::InvalidArguments(_), _)));
+        assert_matches!(PartPattern::new("%d-%d"), Err(Error(ErrorKind::InvalidArguments(_), _)));
+        assert_matches!(PartPattern::new("%s"), Err(Error(ErrorKind::InvalidArguments(_), _)));
+    }
+
+    #[test]
+    fn test_rotate_records() {
+        let directory = ::tempfile::tempdir().unwrap();
+        let rotation = Rotation { bytes: None, records: Some(2) };
+        let mut rotating = Rotating::new(&directory.path().join("part-%02d.jsonl"), rotation, json_parts()).unwrap();
+        for record in &["1", "2", "3"] {
+            rotating.write_record(record).unwrap();
+        }
+        assert!(directory.path().join(".part-01.jsonl.tmp").exists());
+        rotating.finish().unwrap();
+
+        assert_eq!(fs::read_to_string(directory.path().join("part-00.jsonl")).unwrap(), "1\n2\n");
+        assert_eq!(fs::read_to_string(directory.path().join("part-01.jsonl")).unwrap(), "3\n");
+        assert!(!directory.path().join(".part-01.jsonl.tmp").exists());
+        let manifest: Value = serde_json::from_str(&fs::read_to_string(directory.path().join(MANIFEST)).unwrap()).unwrap();
+        assert_eq!(manifest["records"], json!(3));
+        assert_eq!(manifest["bytes"], json!(6));
+        assert_eq!(manifest["parts"][1], json!({
+            "file": "part-01.jsonl",
+            "records": 1,
+            "bytes": 2,
+            "sha256": hex(&Sha256::digest(b"3\n")),
+        }));
+    }
+
+    #[test]
+    fn test_rotate_bytes() {
+        let directory = ::tempfile::tempdir().unwrap();
+        let rotation = Rotation { bytes: Some(5), records: None };
+        let mut rotating = Rotating::new(&directory.path().join("%d.jsonl"), rotation, json_parts()).unwrap();
+        for record in &["12", "34", "56", "7"] {
+            rotating.write_record(record).unwrap();
+        }
+        rotating.finish().unwrap();
+        assert_eq!(fs::read_to_string(directory.path().join("0.jsonl")).unwrap(), "12\n34\n");
+        assert_eq!(fs::read_to_string(directory.path().join("1.jsonl")).unwrap(), "56\n7\n");
+    }
+
+    #[test]
+    fn test_no_records() {
+        let directory = ::tempfile::tempdir().unwrap();
+        let rotation = Rotation { bytes: None, records: Some(1) };
+        let mut rotating = Rotating::new(&directory.path().join("%d.jsonl"), rotation, json_parts()).unwrap();
+        rotating.finish().unwrap();
+        assert_eq!(fs::read_to_string(directory.path().join("0.jsonl")).unwrap(), "");
+        assert!(!directory.path().join("1.jsonl").exists());
+    }
+}
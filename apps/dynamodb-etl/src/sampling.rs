@@ -0,0 +1,179 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+
+use ::sha2::{Digest, Sha256};
+
+use crate::errors::*;
+use crate::json_queries::Queries;
+
+/// Selects input lines by a hash of their key, so that the same records
+/// are selected on every run.
+///
+/// A sample rate keeps lines whose hash falls under that fraction of all
+/// hashes. A sample size keeps the lines with the smallest hashes, which is
+/// a uniform sample of that size no matter the order of the input, but can
+/// only be known once all input has been read.
+#[derive(Debug)]
+pub(crate) struct Sampler {
+    key: Option<Queries>,
+    rate: Option<f64>,
+    size: Option<usize>,
+    reservoir: BinaryHeap<Held>,
+}
+
+/// A line held in the reservoir, ordered by hash
+#[derive(Debug)]
+struct Held {
+    hash: u64,
+    index: usize,
+    offset: u64,
+    line: String,
+}
+
+impl PartialEq for Held {
+    fn eq(&self, other: &Held) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Held {}
+
+impl PartialOrd for Held {
+    fn partial_cmp(&self, other: &Held) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Held {
+    fn cmp(&self, other: &Held) -> Ordering {
+        (self.hash, self.index).cmp(&(other.hash, other.index))
+    }
+}
+
+impl Sampler {
+    /// Without a key path, or when it's missing, the whole line is hashed
+    pub(crate) fn new(key: Option<&str>, rate: Option<f64>, size: Option<usize>) -> Result<Sampler> {
+        let key = match key {
+            Some(path) => Some(Queries::new(path)?),
+            None => None,
+        };
+        Ok(Sampler { key, rate, size, reservoir: BinaryHeap::new() })
+    }
+
+    /// Returns the line if it should be processed now. Lines kept for the
+    /// reservoir are returned by `drain`.
+    pub(crate) fn sample(&mut self, index: usize, offset: u64, line: String) -> Result<Option<String>> {
+        let hash = self.hash(&line)?;
+        if let Some(rate) = self.rate {
+            if hash as f64 >= rate * u64::MAX as f64 {
+                return Ok(None);
+            }
+        }
+        let size = match self.size {
+            Some(size) => size,
+            None => return Ok(Some(line)),
+        };
+        let held = Held { hash, index, offset, line };
+        if self.reservoir.len() < size {
+            self.reservoir.push(held);
+        } else if self.reservoir.peek().is_some_and(|largest| held < *largest) {
+            let _ = self.reservoir.pop();
+            self.reservoir.push(held);
+        }
+        Ok(None)
+    }
+
+    /// The lines in the reservoir, as index, offset and line, in input order
+    pub(crate) fn drain(&mut self) -> Vec<(usize, u64, String)> {
+        let mut held = std::mem::take(&mut self.reservoir).into_vec();
+        held.sort_by_key(|held| held.index);
+        held.into_iter().map(|held| (held.index, held.offset, held.line)).collect()
+    }
+
+    fn hash(&mut self, line: &str) -> Result<u64> {
+        let key = match self.key {
+            Some(ref mut queries) => queries.get(line)?,
+            None => String::new(),
+        };
+        let digest = Sha256::digest(if key.is_empty() { line } else { &key }.as_bytes());
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&digest[..8]);
+        Ok(u64::from_be_bytes(bytes))
+    }
+}
+
+/// Parses a sample rate, which must be a fraction greater than 0 and up to 1
+pub(crate) fn parse_sample_rate(rate: &str) -> std::result::Result<f64, String> {
+    match rate.parse::<f64>() {
+        Ok(rate) if rate > 0.0 && rate <= 1.0 => Ok(rate),
+        _ => Err(format!("sample rate must be greater than 0 and at most 1, got '{}'", rate)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::assert_matches::assert_matches;
+
+    fn lines() -> Vec<String> {
+        (0..1000).map(|i| format!(r#"{{"pk":{{"S":"item-{}"}},"n":{}}}"#, i, i)).collect()
+    }
+
+    fn sample(sampler: &mut Sampler, lines: &[String]) -> Vec<usize> {
+        let mut selected = Vec::new();
+        for (index, line) in lines.iter().enumerate() {
+            if sampler.sample(index, 0, line.clone()).unwrap().is_some() {
+                selected.push(index);
+            }
+        }
+        selected.extend(sampler.drain().into_iter().map(|(index, _, _)| index));
+        selected
+    }
+
+    #[test]
+    fn test_sample_rate() {
+        let lines = lines();
+        let first = sample(&mut Sampler::new(Some(".pk.S"), Some(0.1), None).unwrap(), &lines);
+        let second = sample(&mut Sampler::new(Some(".pk.S"), Some(0.1), None).unwrap(), &lines);
+        assert_eq!(first, second);
+        assert!(first.len() > 50 && first.len() < 150, "Sampled {} out of 1000", first.len());
+    }
+
+    #[test]
+    fn test_sample_size_is_order_independent() {
+        let lines = lines();
+        let mut reversed = lines.clone();
+        reversed.reverse();
+        let forward = sample(&mut Sampler::new(Some(".pk.S"), None, Some(10)).unwrap(), &lines);
+        let backward = sample(&mut Sampler::new(Some(".pk.S"), None, Some(10)).unwrap(), &reversed);
+        assert_eq!(forward.len(), 10);
+        let mut backward: Vec<usize> = backward.into_iter().map(|index| 999 - index).collect();
+        backward.sort();
+        assert_eq!(forward, backward);
+    }
+
+    #[test]
+    fn test_sample_rate_and_size() {
+        let lines = lines();
+        let by_rate = sample(&mut Sampler::new(None, Some(0.1), None).unwrap(), &lines);
+        let by_both = sample(&mut Sampler::new(None, Some(0.1), Some(5)).unwrap(), &lines);
+        assert_eq!(by_both.len(), 5);
+        assert!(by_both.iter().all(|index| by_rate.contains(index)));
+    }
+
+    #[test]
+    fn test_sample_invalid_json() {
+        let mut sampler = Sampler::new(Some(".pk.S"), Some(0.5), None).unwrap();
+        let result = sampler.sample(0, 0, "not json".to_owned());
+        assert_matches!(result, Err(ref error) if !error.is_fatal())
+    }
+
+    #[test]
+    fn test_parse_sample_rate() {
+        assert_eq!(parse_sample_rate("0.01"), Ok(0.01));
+        assert_eq!(parse_sample_rate("1"), Ok(1.0));
+        assert!(parse_sample_rate("0").is_err());
+        assert!(parse_sample_rate("1.5").is_err());
+        assert!(parse_sample_rate("some").is_err());
+    }
+}
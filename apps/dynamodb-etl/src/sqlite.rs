@@ -0,0 +1,310 @@
+use std::path::Path;
+use std::str::FromStr;
+
+use ::error_chain::bail;
+use ::rusqlite::types::Value as SqlValue;
+use ::rusqlite::{params_from_iter, Connection};
+use ::serde_json::{self, json, Value};
+
+use crate::errors::*;
+use crate::json_queries::{type_name, JsonPath};
+use crate::output::RecordWriter;
+use crate::tabular::Column;
+
+/// How records are laid out in the table
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub(crate) enum SqliteLayout {
+    /// A column per top-level field
+    Fields,
+    /// A json column per path
+    Paths,
+}
+
+impl FromStr for SqliteLayout {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "fields" => Ok(SqliteLayout::Fields),
+            "paths" => Ok(SqliteLayout::Paths),
+            _ => Err(format!("unknown sqlite layout '{}', expected fields or paths", s))
+        }
+    }
+}
+
+/// Inserts records into a SQLite table, in transactions of `batch_size`
+/// records, and indexes the key paths once all records are in.
+///
+/// With the fields layout, columns are added as new top-level fields show
+/// up. Scalars are stored as SQLite integers, reals and text, and objects
+/// and arrays as json text, which SQLite's json functions can query. With
+/// the paths layout, each column holds the json at its path.
+///
+/// SQLite column names ignore ASCII case, so fields that only differ in
+/// case go to the same column, and records with more than one of them are
+/// errors.
+#[derive(Debug)]
+pub(crate) struct Sqlite {
+    connection: Connection,
+    table: String,
+    layout: SqliteLayout,
+    /// Columns in the table, for the paths layout along with their paths
+    columns: Vec<String>,
+    paths: Vec<Column>,
+    indexes: Vec<JsonPath>,
+    batch_size: usize,
+    pending: usize,
+}
+
+impl Sqlite {
+    pub(crate) fn new(path: &Path, table: &str, layout: SqliteLayout, paths: Vec<Column>,
+                      indexes: Vec<JsonPath>, batch_size: usize) -> Result<Sqlite> {
+        let connection = Connection::open(path)?;
+        let mut sqlite = Sqlite {
+            connection,
+            table: table.to_owned(),
+            layout,
+            columns: Vec::new(),
+            paths: Vec::new(),
+            indexes,
+            batch_size: batch_size.max(1),
+            pending: 0,
+        };
+        if layout == SqliteLayout::Paths {
+            let names: Vec<String> = paths.iter().map(|column| column.name.clone()).collect();
+            sqlite.paths = paths;
+            sqlite.add_columns(&names)?;
+            for index in &sqlite.indexes {
+                let _ = sqlite.index_expression(index)?;
+            }
+        }
+        Ok(sqlite)
+    }
+
+    /// Creates the table, or adds the columns it doesn't have yet
+    fn add_columns(&mut self, names: &[String]) -> Result<()> {
+        let new: Vec<&String> = names.iter().filter(|name| !self.has_column(name)).collect();
+        if new.is_empty() {
+            return Ok(());
+        }
+        if self.columns.is_empty() {
+            let existing = self.existing_columns()?;
+            if existing.is_empty() {
+                let columns: Vec<String> = new.iter().map(|name| quote(name)).collect();
+                self.connection.execute_batch(&format!("CREATE TABLE {} ({})", quote(&self.table), columns.join(", ")))?;
+                self.columns.extend(new.into_iter().cloned());
+                return Ok(());
+            }
+            self.columns = existing;
+            return self.add_columns(names);
+        }
+        for name in new {
+            self.connection.execute_batch(&format!("ALTER TABLE {} ADD COLUMN {}", quote(&self.table), quote(name)))?;
+            self.columns.push(name.clone());
+        }
+        Ok(())
+    }
+
+    fn has_column(&self, name: &str) -> bool {
+        self.columns.iter().any(|column| column.eq_ignore_ascii_case(name))
+    }
+
+    /// Columns of the table, if it's already in the database
+    fn existing_columns(&self) -> Result<Vec<String>> {
+        let mut statement = self.connection.prepare(&format!("PRAGMA table_info({})", quote(&self.table)))?;
+        let columns = statement.query_map([], |row| row.get::<_, String>(1))?
+            .collect::<std::result::Result<Vec<String>, _>>()?;
+        Ok(columns)
+    }
+
+    /// The column, or json_extract expression on a column, for a key path
+    fn index_expression(&self, path: &JsonPath) -> Result<Option<String>> {
+        let segments = path.segments();
+        let (column, rest) = match self.layout {
+            SqliteLayout::Fields => match segments.split_first() {
+                Some((Value::String(column), rest)) => (column.clone(), rest),
+                _ => bail!(ErrorKind::InvalidPath(path.to_string(), "must start with a field name".to_owned())),
+            },
+            SqliteLayout::Paths => match self.paths.iter().find(|column| segments.starts_with(column.path.segments())) {
+                Some(column) => (column.name.clone(), &segments[column.path.segments().len()..]),
+                None => bail!(ErrorKind::InvalidPath(path.to_string(), "is not inside any column".to_owned())),
+            },
+        };
+        if !self.has_column(&column) {
+            return Ok(None);
+        }
+        if rest.is_empty() {
+            return Ok(Some(quote(&column)));
+        }
+        let mut json_path = "$".to_owned();
+        for segment in rest {
+            match segment {
+                Value::String(key) => json_path.push_str(&format!(".{}", quote(key))),
+                Value::Number(index) if index.as_u64().is_some() => json_path.push_str(&format!("[{}]", index)),
+                _ => bail!(ErrorKind::InvalidPath(path.to_string(), "negative indexes can't be indexed".to_owned())),
+            }
+        }
+        Ok(Some(format!("json_extract({}, '{}')", quote(&column), json_path.replace('\'', "''"))))
+    }
+
+    fn insert(&mut self, names: &[String], values: Vec<SqlValue>) -> Result<()> {
+        if self.pending == 0 {
+            self.connection.execute_batch("BEGIN")?;
+        }
+        let columns: Vec<String> = names.iter().map(|name| quote(name)).collect();
+        let placeholders = vec!["?"; names.len()].join(", ");
+        let sql = format!("INSERT INTO {} ({}) VALUES ({})", quote(&self.table), columns.join(", "), placeholders);
+        let _ = self.connection.prepare_cached(&sql)?.execute(params_from_iter(values))?;
+        self.pending += 1;
+        if self.pending >= self.batch_size {
+            self.connection.execute_batch("COMMIT")?;
+            self.pending = 0;
+        }
+        Ok(())
+    }
+}
+
+impl RecordWriter for Sqlite {
+    fn write_record(&mut self, record: &str) -> Result<()> {
+        let record: Value = serde_json::from_str(record)?;
+        let (names, values) = match (self.layout, record) {
+            (SqliteLayout::Fields, Value::Object(map)) => {
+                let names: Vec<String> = map.keys().cloned().collect();
+                if let Some((first, second)) = case_collision(&names) {
+                    bail!(ErrorKind::SchemaMismatch(format!("fields {} and {} would both go to the same column",
+                                                            first, second)));
+                }
+                self.add_columns(&names)?;
+                (names, map.into_iter().map(|(_, value)| sql_value(value)).collect())
+            },
+            (SqliteLayout::Fields, other) =>
+                bail!(ErrorKind::SchemaMismatch(format!("record is {}, expected an object", type_name(&other)))),
+            (SqliteLayout::Paths, record) => {
+                let values = self.paths.iter()
+                    .map(|column| match column.path.get(&record) {
+                        Some(value) => SqlValue::Text(value.to_string()),
+                        None => SqlValue::Null,
+                    })
+                    .collect();
+                (self.paths.iter().map(|column| column.name.clone()).collect(), values)
+            },
+        };
+        if names.is_empty() {
+            return Ok(());
+        }
+        self.insert(&names, values)
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        if self.pending > 0 {
+            self.connection.execute_batch("COMMIT")?;
+            self.pending = 0;
+        }
+        for (number, path) in self.indexes.iter().enumerate() {
+            match self.index_expression(path)? {
+                Some(expression) => {
+                    let name = format!("{}_index_{}", self.table, number + 1);
+                    self.connection.execute_batch(&format!("CREATE INDEX IF NOT EXISTS {} ON {} ({})",
+                                                           quote(&name), quote(&self.table), expression))?;
+                },
+                None => report_notice(Notice::Warning, "NotIndexed", &format!("no records have {}, not indexing it", path),
+                                      json!({"path": path.to_string()})),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Two names that only differ in ASCII case, if there are any
+fn case_collision(names: &[String]) -> Option<(&String, &String)> {
+    names.iter().enumerate().find_map(|(i, first)| {
+        names[i + 1..].iter().find(|second| first.eq_ignore_ascii_case(second)).map(|second| (first, second))
+    })
+}
+
+fn sql_value(value: Value) -> SqlValue {
+    match value {
+        Value::Null => SqlValue::Null,
+        Value::Bool(b) => SqlValue::Integer(b as i64),
+        Value::Number(ref n) if n.is_i64() => SqlValue::Integer(n.as_i64().unwrap_or(0)),
+        Value::Number(n) => SqlValue::Real(n.as_f64().unwrap_or(0.0)),
+        Value::String(s) => SqlValue::Text(s),
+        other => SqlValue::Text(other.to_string()),
+    }
+}
+
+/// Quotes an identifier
+fn quote(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json_queries::parse_path_list;
+    use crate::tabular::parse_columns;
+    use ::assert_matches::assert_matches;
+
+    fn query(path: &Path, sql: &str) -> Vec<String> {
+        let connection = Connection::open(path).unwrap();
+        let mut statement = connection.prepare(sql).unwrap();
+        let rows = statement.query_map([], |row| row.get::<_, String>(0)).unwrap();
+        rows.map(|row| row.unwrap()).collect()
+    }
+
+    #[test]
+    fn test_sqlite_fields() {
+        let file = ::tempfile::NamedTempFile::new().unwrap();
+        let indexes = parse_path_list(".pk.S, .missing").unwrap();
+        let mut sqlite = Sqlite::new(file.path(), "export", SqliteLayout::Fields, Vec::new(), indexes, 2).unwrap();
+        sqlite.write_record(r#"{"pk":{"S":"a"},"n":1}"#).unwrap();
+        sqlite.write_record(r#"{"pk":{"S":"b"},"n":2.5,"ok":true}"#).unwrap();
+        assert_matches!(sqlite.write_record("[1]"), Err(Error(ErrorKind::SchemaMismatch(_), _)));
+        sqlite.write_record(r#"{"pk":{"S":"c"},"name":"x\"y"}"#).unwrap();
+        sqlite.finish().unwrap();
+
+        let rows = query(file.path(), "SELECT json_extract(pk, '$.S') || '|' || typeof(n) || '|' || \
+                                       coalesce(ok, '') || '|' || coalesce(name, '') FROM export ORDER BY rowid");
+        assert_eq!(rows, vec!["a|integer||", "b|real|1|", "c|null||x\"y"]);
+        let indexes = query(file.path(), "SELECT sql FROM sqlite_master WHERE type = 'index'");
+        assert_eq!(indexes, vec![r#"CREATE INDEX "export_index_1" ON "export" (json_extract("pk", '$."S"'))"#]);
+    }
+
+    #[test]
+    fn test_sqlite_column_case() {
+        let file = ::tempfile::NamedTempFile::new().unwrap();
+        let indexes = parse_path_list(".Id").unwrap();
+        let mut sqlite = Sqlite::new(file.path(), "export", SqliteLayout::Fields, Vec::new(), indexes, 100).unwrap();
+        sqlite.write_record(r#"{"id":"a"}"#).unwrap();
+        sqlite.write_record(r#"{"ID":"b"}"#).unwrap();
+        let result = sqlite.write_record(r#"{"id":"c","Id":"d"}"#);
+        assert_matches!(result, Err(Error(ErrorKind::SchemaMismatch(_), _)));
+        assert_matches!(result, Err(ref error) if !error.is_fatal());
+        sqlite.finish().unwrap();
+
+        assert_eq!(query(file.path(), "SELECT id FROM export ORDER BY rowid"), vec!["a", "b"]);
+        assert_eq!(query(file.path(), "SELECT name FROM pragma_table_info('export')"), vec!["id"]);
+        let indexes = query(file.path(), "SELECT sql FROM sqlite_master WHERE type = 'index'");
+        assert_eq!(indexes, vec![r#"CREATE INDEX "export_index_1" ON "export" ("Id")"#]);
+    }
+
+    #[test]
+    fn test_sqlite_paths() {
+        let file = ::tempfile::NamedTempFile::new().unwrap();
+        let columns = parse_columns("id=.pk.S, data=.projectData.S").unwrap();
+        let indexes = parse_path_list(".projectData.S.name").unwrap();
+        let mut sqlite = Sqlite::new(file.path(), "t", SqliteLayout::Paths, columns, indexes, 100).unwrap();
+        sqlite.write_record(r#"{"pk":{"S":"a"},"projectData":{"S":{"name":"x","tags":[1]}}}"#).unwrap();
+        sqlite.write_record(r#"{"pk":{"S":"b"}}"#).unwrap();
+        sqlite.finish().unwrap();
+
+        let rows = query(file.path(), "SELECT id || '|' || coalesce(data, 'null') FROM t ORDER BY rowid");
+        assert_eq!(rows, vec![r#""a"|{"name":"x","tags":[1]}"#, r#""b"|null"#]);
+        let indexes = query(file.path(), "SELECT sql FROM sqlite_master WHERE type = 'index'");
+        assert_eq!(indexes, vec![r#"CREATE INDEX "t_index_1" ON "t" (json_extract("data", '$."name"'))"#]);
+
+        let outside = parse_path_list(".other").unwrap();
+        let result = Sqlite::new(file.path(), "t", SqliteLayout::Paths, parse_columns("id=.pk.S").unwrap(), outside, 1);
+        assert_matches!(result, Err(Error(ErrorKind::InvalidPath(_, _), _)));
+    }
+}
@@ -0,0 +1,317 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, BufRead, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use ::serde_json::{self, json, Value};
+
+use crate::annotate::Decoders;
+use crate::errors::*;
+use crate::output::{RecordWriter, Rejected, Source};
+
+/// Parts of the processing that are timed separately
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub(crate) enum Stage {
+    /// Waiting for input
+    Read,
+    /// Running jq and decoding the data paths
+    Decode,
+    /// Applying the transforms, such as flattening and exploding
+    Transform,
+    /// Writing to the output
+    Write,
+}
+
+const STAGES: [(Stage, &str); 4] = [
+    (Stage::Read, "read"),
+    (Stage::Decode, "decode"),
+    (Stage::Transform, "transform"),
+    (Stage::Write, "write"),
+];
+
+/// What happened during a run, reported at the end of it
+#[derive(Debug)]
+pub(crate) struct Stats {
+    /// Whether anyone asks for the statistics. Which data paths a record
+    /// has takes extra jq queries to find out, so it's only counted then.
+    enabled: bool,
+    started: Instant,
+    pub(crate) records_read: u64,
+    pub(crate) records_written: u64,
+    /// Records that failed, by kind of error
+    pub(crate) skipped: BTreeMap<&'static str, u64>,
+    pub(crate) bytes_in: u64,
+    /// Shared with the writers, which can run on other threads
+    pub(crate) bytes_out: Arc<AtomicU64>,
+    pub(crate) decompressed_bytes: u64,
+    binary: u64,
+    text: u64,
+    neither: u64,
+    timings: [Duration; 4],
+}
+
+impl Default for Stats {
+    fn default() -> Stats {
+        Stats::new(false)
+    }
+}
+
+impl Stats {
+    pub(crate) fn new(enabled: bool) -> Stats {
+        Stats {
+            enabled,
+            started: Instant::now(),
+            records_read: 0,
+            records_written: 0,
+            skipped: BTreeMap::new(),
+            bytes_in: 0,
+            bytes_out: Arc::new(AtomicU64::new(0)),
+            decompressed_bytes: 0,
+            binary: 0,
+            text: 0,
+            neither: 0,
+            timings: [Duration::default(); 4],
+        }
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Counts the data paths of a record. Records with both paths count
+    /// as binary and as text.
+    pub(crate) fn decoded(&mut self, decoders: Decoders) {
+        self.binary += decoders.binary as u64;
+        self.text += decoders.text as u64;
+        self.neither += !(decoders.binary || decoders.text) as u64;
+    }
+
+    pub(crate) fn add_time(&mut self, stage: Stage, time: Duration) {
+        if let Some(index) = STAGES.iter().position(|(s, _)| *s == stage) {
+            self.timings[index] += time;
+        }
+    }
+
+    pub(crate) fn to_json(&self) -> Value {
+        let seconds: serde_json::Map<String, Value> = STAGES.iter().zip(&self.timings)
+            .map(|((_, name), time)| (name.to_string(), json!(time.as_secs_f64())))
+            .collect();
+        json!({
+            "records": {
+                "read": self.records_read,
+                "written": self.records_written,
+                "skipped": self.skipped,
+            },
+            "bytes": {
+                "in": self.bytes_in,
+                "out": self.bytes_out.load(Ordering::Relaxed),
+                "decompressed": self.decompressed_bytes,
+            },
+            "data_paths": {
+                "binary": self.binary,
+                "text": self.text,
+                "neither": self.neither,
+            },
+            "seconds": {
+                "total": self.started.elapsed().as_secs_f64(),
+                "stages": seconds,
+            },
+        })
+    }
+
+    /// A few lines for people, such as
+    ///
+    /// ```text
+    /// Read 1000 records (523100 bytes), wrote 990 (480020 bytes), skipped 10: GzipError 8, Base64Error 2
+    /// Data paths: 700 binary (1203000 bytes decompressed), 250 text, 50 neither
+    /// Took 3.21s: read 0.20s, decode 2.50s, transform 0.30s, write 0.21s
+    /// ```
+    pub(crate) fn summary(&self) -> String {
+        let mut skipped: Vec<(&str, u64)> = self.skipped.iter().map(|(kind, count)| (*kind, *count)).collect();
+        skipped.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+        let skipped_kinds: Vec<String> = skipped.iter().map(|(kind, count)| format!("{} {}", kind, count)).collect();
+        let skipped_count: u64 = self.skipped.values().sum();
+        let skipped = match skipped_count {
+            0 => "skipped 0".to_owned(),
+            count => format!("skipped {}: {}", count, skipped_kinds.join(", ")),
+        };
+        let stages: Vec<String> = STAGES.iter().zip(&self.timings)
+            .map(|((_, name), time)| format!("{} {:.2}s", name, time.as_secs_f64()))
+            .collect();
+        // Data paths are only counted when the statistics were asked for
+        let data_paths = match self.enabled {
+            true => format!("Data paths: {} binary ({} bytes decompressed), {} text, {} neither\n",
+                            self.binary, self.decompressed_bytes, self.text, self.neither),
+            false => String::new(),
+        };
+        format!("Read {} records ({} bytes), wrote {} ({} bytes), {}\n{}Took {:.2}s: {}\n",
+                self.records_read, self.bytes_in, self.records_written, self.bytes_out.load(Ordering::Relaxed), skipped,
+                data_paths, self.started.elapsed().as_secs_f64(), stages.join(", "))
+    }
+
+    pub(crate) fn write(&self, path: &Path) -> Result<()> {
+        fs::write(path, serde_json::to_string_pretty(&self.to_json())? + "\n")?;
+        Ok(())
+    }
+}
+
+/// Counts the bytes read through it, and the time spent waiting for them
+#[derive(Debug)]
+pub(crate) struct MeasuredInput<R> {
+    inner: R,
+    pub(crate) bytes: u64,
+    pub(crate) elapsed: Duration,
+}
+
+impl<R> MeasuredInput<R> {
+    pub(crate) fn new(inner: R) -> MeasuredInput<R> {
+        MeasuredInput { inner, bytes: 0, elapsed: Duration::default() }
+    }
+}
+
+impl<R: Read> Read for MeasuredInput<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let started = Instant::now();
+        let read = self.inner.read(buf);
+        self.elapsed += started.elapsed();
+        self.bytes += *read.as_ref().unwrap_or(&0) as u64;
+        read
+    }
+}
+
+impl<R: BufRead> BufRead for MeasuredInput<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        let started = Instant::now();
+        let buffer = self.inner.fill_buf();
+        self.elapsed += started.elapsed();
+        buffer
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.bytes += amt as u64;
+        self.inner.consume(amt)
+    }
+}
+
+/// Counts the bytes written through it
+#[derive(Debug)]
+pub(crate) struct CountedOutput<W: Write> {
+    inner: W,
+    bytes: Arc<AtomicU64>,
+}
+
+impl<W: Write> CountedOutput<W> {
+    pub(crate) fn new(inner: W, bytes: Arc<AtomicU64>) -> CountedOutput<W> {
+        CountedOutput { inner, bytes }
+    }
+}
+
+impl<W: Write> Write for CountedOutput<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        let _ = self.bytes.fetch_add(written as u64, Ordering::Relaxed);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Counts the records written through it, and the time it takes. Records
+/// the output rejects later are taken off the count.
+pub(crate) struct MeasuredOutput<'a> {
+    inner: &'a mut dyn RecordWriter,
+    pub(crate) records: u64,
+    pub(crate) elapsed: Duration,
+    finished: bool,
+}
+
+impl<'a> MeasuredOutput<'a> {
+    pub(crate) fn new(inner: &'a mut dyn RecordWriter) -> MeasuredOutput<'a> {
+        MeasuredOutput { inner, records: 0, elapsed: Duration::default(), finished: false }
+    }
+}
+
+impl RecordWriter for MeasuredOutput<'_> {
+    fn set_source(&mut self, source: &Source) {
+        self.inner.set_source(source);
+    }
+
+    fn write_record(&mut self, record: &str) -> Result<()> {
+        let started = Instant::now();
+        let written = self.inner.write_record(record);
+        self.elapsed += started.elapsed();
+        self.records += written.is_ok() as u64;
+        written
+    }
+
+    fn take_rejected(&mut self) -> Vec<Rejected> {
+        let rejected = self.inner.take_rejected();
+        self.records -= rejected.len() as u64;
+        rejected
+    }
+
+    /// Only finishes the output once, even when a failure after finishing
+    /// it has the caller finish it again
+    fn finish(&mut self) -> Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+        let started = Instant::now();
+        let finished = self.inner.finish();
+        self.elapsed += started.elapsed();
+        finished
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_measured_input() {
+        let mut input = MeasuredInput::new(Cursor::new("one\ntwo\n"));
+        let lines: Vec<String> = (&mut input).lines().map(|line| line.unwrap()).collect();
+        assert_eq!(lines, vec!["one", "two"]);
+        assert_eq!(input.bytes, 8);
+    }
+
+    #[test]
+    fn test_measured_output() {
+        let mut buffer = Vec::new();
+        let bytes = Arc::new(AtomicU64::new(0));
+        let mut counted = CountedOutput::new(&mut buffer, bytes.clone());
+        let mut output = MeasuredOutput::new(&mut counted);
+        output.write_record("{}").unwrap();
+        output.write_record("[]").unwrap();
+        output.finish().unwrap();
+        assert_eq!(output.records, 2);
+        assert_eq!(bytes.load(Ordering::Relaxed), 6);
+    }
+
+    #[test]
+    fn test_report() {
+        let mut stats = Stats::new(true);
+        stats.records_read = 4;
+        stats.records_written = 2;
+        let _ = stats.skipped.insert("Base64Error", 1);
+        let _ = stats.skipped.insert("GzipError", 1);
+        stats.decoded(Decoders { binary: true, text: true });
+        stats.decoded(Decoders { binary: false, text: false });
+        stats.add_time(Stage::Decode, Duration::from_millis(1500));
+
+        let json = stats.to_json();
+        assert_eq!(json["records"], json!({"read": 4, "written": 2, "skipped": {"Base64Error": 1, "GzipError": 1}}));
+        assert_eq!(json["data_paths"], json!({"binary": 1, "text": 1, "neither": 1}));
+        assert_eq!(json["seconds"]["stages"]["decode"], json!(1.5));
+        let summary = stats.summary();
+        assert!(summary.starts_with("Read 4 records (0 bytes), wrote 2 (0 bytes), skipped 2: Base64Error 1, GzipError 1\n"));
+        assert!(summary.contains("decode 1.50s"));
+    }
+}
@@ -0,0 +1,252 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::str::FromStr;
+
+use ::csv::{QuoteStyle, WriterBuilder};
+use ::error_chain::bail;
+use ::serde_json::{self, Value};
+
+use crate::errors::*;
+use crate::json_queries::{split_list, JsonPath};
+use crate::output::RecordWriter;
+
+/// How objects and arrays are written to a single cell
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub(crate) enum NestedValues {
+    Json,
+    Flatten,
+}
+
+impl FromStr for NestedValues {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(NestedValues::Json),
+            "flatten" => Ok(NestedValues::Flatten),
+            _ => Err(format!("unknown nested values mode '{}', expected json or flatten", s))
+        }
+    }
+}
+
+/// An output column and the path of its value
+#[derive(Clone,Debug)]
+pub(crate) struct Column {
+    pub(crate) name: String,
+    pub(crate) path: JsonPath,
+}
+
+/// Parses a comma-separated list of `name=path` columns. Columns without
+/// a name are named after their path.
+pub(crate) fn parse_columns(columns: &str) -> Result<Vec<Column>> {
+    split_list(columns).into_iter()
+        .map(|column| {
+            let (name, path) = match column.find('=') {
+                Some(equals) if !column[..equals].contains(['.', '[', '"']) =>
+                    (column[..equals].trim(), column[equals + 1..].trim()),
+                _ => (column.trim().trim_start_matches('.'), column.trim()),
+            };
+            if name.is_empty() {
+                bail!(ErrorKind::InvalidPath(column.to_owned(), "column has no name".to_owned()));
+            }
+            Ok(Column { name: name.to_owned(), path: JsonPath::new(path)? })
+        })
+        .collect()
+}
+
+/// Writes records as CSV or TSV rows, one cell per column.
+///
+/// Without explicit columns, there's one for each top-level field of the
+/// first record. Flattened columns get one cell per scalar inside them,
+/// named like `column.field.0`, also as found on the first record.
+#[derive(Debug)]
+pub(crate) struct Tabular<W: Write> {
+    writer: ::csv::Writer<W>,
+    columns: Option<Vec<Column>>,
+    nested: NestedValues,
+    header: bool,
+    /// Column index and flattened suffix of each cell
+    cells: Option<Vec<(usize, String)>>,
+}
+
+impl<W: Write> Tabular<W> {
+    pub(crate) fn new(output: W, delimiter: u8, columns: Option<Vec<Column>>,
+                      nested: NestedValues, header: bool) -> Tabular<W> {
+        let writer = WriterBuilder::new()
+            .delimiter(delimiter)
+            .quote_style(QuoteStyle::Necessary)
+            .from_writer(output);
+        Tabular { writer, columns, nested, header, cells: None }
+    }
+
+    /// Fixes the columns and cells from the first record
+    fn resolve(&mut self, record: &Value) -> Result<()> {
+        let columns = match self.columns.take() {
+            Some(columns) => columns,
+            None => match record {
+                Value::Object(map) => map.keys()
+                    .map(|key| Ok(Column {
+                        name: key.clone(),
+                        path: JsonPath::new(&format!(".[{}]", serde_json::to_string(key)?))?,
+                    }))
+                    .collect::<Result<Vec<Column>>>()?,
+                _ => Vec::new(),
+            },
+        };
+        let mut cells = Vec::new();
+        for (index, column) in columns.iter().enumerate() {
+            match (self.nested, column.path.get(record)) {
+                (NestedValues::Flatten, Some(value)) => {
+                    let mut flattened = Vec::new();
+                    flatten(&mut flattened, String::new(), value);
+                    cells.extend(flattened.into_iter().map(|(suffix, _)| (index, suffix)));
+                },
+                _ => cells.push((index, String::new())),
+            }
+        }
+        self.columns = Some(columns);
+        self.cells = Some(cells);
+        if self.header {
+            self.write_header()?;
+        }
+        Ok(())
+    }
+
+    fn write_header(&mut self) -> Result<()> {
+        let (columns, cells) = match (&self.columns, &self.cells) {
+            (Some(columns), Some(cells)) => (columns, cells),
+            _ => return Ok(()),
+        };
+        let names: Vec<String> = cells.iter()
+            .map(|(index, suffix)| format!("{}{}", columns[*index].name, suffix))
+            .collect();
+        self.writer.write_record(&names)?;
+        Ok(())
+    }
+}
+
+impl<W: Write> RecordWriter for Tabular<W> {
+    fn write_record(&mut self, record: &str) -> Result<()> {
+        let record: Value = serde_json::from_str(record)?;
+        if self.cells.is_none() {
+            self.resolve(&record)?;
+        }
+        let (columns, cells) = match (&self.columns, &self.cells) {
+            (Some(columns), Some(cells)) => (columns, cells),
+            _ => return Ok(()),
+        };
+        let values: Vec<Option<&Value>> = columns.iter().map(|column| column.path.get(&record)).collect();
+        let flattened: Vec<HashMap<String, String>> = match self.nested {
+            NestedValues::Json => Vec::new(),
+            NestedValues::Flatten => values.iter()
+                .map(|value| {
+                    let mut flattened = Vec::new();
+                    if let Some(value) = value {
+                        flatten(&mut flattened, String::new(), value);
+                    }
+                    flattened.into_iter().map(|(suffix, value)| (suffix, cell(value))).collect()
+                })
+                .collect(),
+        };
+        let row: Vec<String> = cells.iter()
+            .map(|(index, suffix)| match self.nested {
+                NestedValues::Json => values[*index].map(cell).unwrap_or_default(),
+                NestedValues::Flatten => flattened[*index].get(suffix).cloned().unwrap_or_default(),
+            })
+            .collect();
+        self.writer.write_record(&row)?;
+        Ok(())
+    }
+
+    /// Explicit columns get a header even when there were no records
+    fn finish(&mut self) -> Result<()> {
+        if self.cells.is_none() && self.header && self.columns.is_some() {
+            let columns = self.columns.as_ref().map_or(0, Vec::len);
+            self.cells = Some((0..columns).map(|index| (index, String::new())).collect());
+            self.write_header()?;
+        }
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Text of a cell: strings without quotes, null as empty, and anything
+/// else as json
+fn cell(value: &Value) -> String {
+    match value {
+        Value::Null => String::new(),
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+/// Scalars inside a value, with their path as `.field.0` suffixes. Empty
+/// objects and arrays count as scalars.
+fn flatten<'a>(flattened: &mut Vec<(String, &'a Value)>, prefix: String, value: &'a Value) {
+    match value {
+        Value::Object(map) if !map.is_empty() => for (key, value) in map {
+            flatten(flattened, format!("{}.{}", prefix, key), value);
+        },
+        Value::Array(vec) if !vec.is_empty() => for (index, value) in vec.iter().enumerate() {
+            flatten(flattened, format!("{}.{}", prefix, index), value);
+        },
+        _ => flattened.push((prefix, value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::assert_matches::assert_matches;
+
+    fn write(delimiter: u8, columns: Option<&str>, nested: NestedValues, header: bool, records: &[&str]) -> String {
+        let columns = columns.map(|columns| parse_columns(columns).unwrap());
+        let mut tabular = Tabular::new(Vec::new(), delimiter, columns, nested, header);
+        for record in records {
+            tabular.write_record(record).unwrap();
+        }
+        tabular.finish().unwrap();
+        String::from_utf8(tabular.writer.into_inner().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_csv_columns() {
+        let records = &[
+            r#"{"pk":{"S":"a,1"},"data":{"name":"say \"hi\"","tags":["x"]}}"#,
+            r#"{"pk":{"S":"b"},"data":{"name":null}}"#,
+        ];
+        let csv = write(b',', Some("id=.pk.S, name=.data.name, .data.tags"), NestedValues::Json, true, records);
+        assert_eq!(csv, "id,name,data.tags\n\"a,1\",\"say \"\"hi\"\"\",\"[\"\"x\"\"]\"\nb,,\n");
+    }
+
+    #[test]
+    fn test_tsv_top_level_fields() {
+        let records = &[r#"{"a":1,"b":"two\tcells","c":true}"#, r#"{"c":false,"d":4}"#];
+        let tsv = write(b'\t', None, NestedValues::Json, false, records);
+        assert_eq!(tsv, "1\t\"two\tcells\"\ttrue\n\t\tfalse\n");
+    }
+
+    #[test]
+    fn test_flatten() {
+        let records = &[
+            r#"{"id":1,"data":{"name":"x","tags":["a","b"],"empty":{}}}"#,
+            r#"{"id":2,"data":{"tags":["c"],"extra":1}}"#,
+        ];
+        let csv = write(b',', None, NestedValues::Flatten, true, records);
+        assert_eq!(csv, "id,data.name,data.tags.0,data.tags.1,data.empty\n1,x,a,b,{}\n2,,c,,\n");
+    }
+
+    #[test]
+    fn test_header_without_records() {
+        let csv = write(b',', Some("id=.pk.S,.v"), NestedValues::Flatten, true, &[]);
+        assert_eq!(csv, "id,v\n");
+    }
+
+    #[test]
+    fn test_parse_columns() {
+        assert_matches!(parse_columns("=.a"), Err(Error(ErrorKind::InvalidPath(_, _), _)));
+        let columns = parse_columns(r#"a=.x, .["b=c"]"#).unwrap();
+        let names: Vec<&str> = columns.iter().map(|column| column.name.as_str()).collect();
+        assert_eq!(names, vec!["a", r#"["b=c"]"#]);
+    }
+}
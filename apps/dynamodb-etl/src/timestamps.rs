@@ -0,0 +1,337 @@
+use std::str::FromStr;
+
+use ::chrono::{DateTime, NaiveDate, NaiveDateTime, SecondsFormat, TimeZone, Utc};
+use ::error_chain::bail;
+use ::serde_json::Value;
+
+use crate::errors::*;
+use crate::json_queries::{parse_path_list, JsonPath};
+
+/// Numbers below this are taken as epoch seconds, above it as epoch millis.
+/// 1e11 seconds is in the year 5138, 1e11 millis is in 1973.
+const EPOCH_MILLIS_THRESHOLD: f64 = 1e11;
+
+/// Range of epoch seconds auto-detection accepts, 2000-01-01 to 2100-01-01
+const AUTO_EPOCH_RANGE: (f64, f64) = (946_684_800.0, 4_102_444_800.0);
+
+const NAIVE_FORMATS: &[&str] = &[
+    "%Y-%m-%dT%H:%M:%S%.f",
+    "%Y-%m-%d %H:%M:%S%.f",
+    "%Y-%m-%dT%H:%M",
+    "%Y-%m-%d %H:%M",
+    "%Y%m%dT%H%M%S%.f",
+];
+
+const OFFSET_FORMATS: &[&str] = &[
+    "%Y-%m-%dT%H:%M:%S%.f%z",
+    "%Y-%m-%d %H:%M:%S%.f%z",
+    "%Y-%m-%d %H:%M:%S%.f %z",
+    "%Y%m%dT%H%M%S%.f%z",
+];
+
+/// Representation timestamps are normalised to
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub(crate) enum TimestampFormat {
+    Rfc3339,
+    EpochMillis,
+}
+
+impl FromStr for TimestampFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "rfc3339" => Ok(TimestampFormat::Rfc3339),
+            "epoch-millis" => Ok(TimestampFormat::EpochMillis),
+            _ => Err(format!("unknown timestamp format '{}', expected rfc3339 or epoch-millis", s))
+        }
+    }
+}
+
+#[derive(Debug)]
+enum TimestampFields {
+    Auto,
+    Paths(Vec<JsonPath>),
+}
+
+/// Normalises timestamps given as epoch seconds, epoch millis or ISO-8601
+/// strings, either on a list of paths or on every field that looks like one.
+#[derive(Debug)]
+pub(crate) struct Timestamps {
+    fields: TimestampFields,
+    format: TimestampFormat,
+}
+
+impl Timestamps {
+    /// Fields are either "auto" or a comma-separated list of jq paths
+    pub(crate) fn new(fields: &str, format: TimestampFormat) -> Result<Timestamps> {
+        let fields = match fields.trim() {
+            "auto" => TimestampFields::Auto,
+            paths => TimestampFields::Paths(parse_path_list(paths)?),
+        };
+        Ok(Timestamps { fields, format })
+    }
+
+    /// Rewrites timestamps in place. Values on listed paths that are not
+    /// timestamps are errors; missing or null values are left alone.
+    pub(crate) fn normalise(&self, json: &mut Value) -> Result<()> {
+        match self.fields {
+            TimestampFields::Auto => self.normalise_auto(None, json),
+            TimestampFields::Paths(ref paths) => {
+                for path in paths {
+                    if let Some(value) = path.get_mut(json).filter(|v| !v.is_null()) {
+                        match parse_timestamp(value) {
+                            Some(timestamp) => *value = self.format(timestamp),
+                            None => bail!(ErrorKind::TimestampError(path.to_string(), value.to_string())),
+                        }
+                    }
+                }
+            },
+        }
+        Ok(())
+    }
+
+    fn normalise_auto(&self, key: Option<&str>, json: &mut Value) {
+        match json {
+            Value::Object(map) => {
+                // DynamoDB numbers and strings go by the name of their attribute
+                let attribute = map.len() == 1 && map.keys().all(|kind| kind == "N" || kind == "S");
+                for (name, value) in map.iter_mut() {
+                    self.normalise_auto(if attribute { key } else { Some(name) }, value)
+                }
+            },
+            Value::Array(vec) =>
+                for value in vec.iter_mut() {
+                    self.normalise_auto(key, value)
+                },
+            _ => if let Some(timestamp) = detect_timestamp(key, json) {
+                *json = self.format(timestamp)
+            },
+        }
+    }
+
+    fn format(&self, timestamp: DateTime<Utc>) -> Value {
+        match self.format {
+            TimestampFormat::Rfc3339 => Value::String(timestamp.to_rfc3339_opts(SecondsFormat::AutoSi, true)),
+            TimestampFormat::EpochMillis => Value::from(timestamp.timestamp_millis()),
+        }
+    }
+}
+
+/// ISO-8601 strings are always detected; numbers, and strings holding
+/// numbers, only on timestamp-like keys and within a plausible range.
+fn detect_timestamp(key: Option<&str>, value: &Value) -> Option<DateTime<Utc>> {
+    match value {
+        Value::String(s) if looks_like_iso_date(s) => parse_timestamp_str(s.trim()),
+        Value::String(_) | Value::Number(_) if key.is_some_and(is_timestamp_key) => {
+            let number = match value {
+                Value::String(s) => s.trim().parse::<f64>().ok()?,
+                _ => value.as_f64()?,
+            };
+            let seconds = if number.abs() < EPOCH_MILLIS_THRESHOLD { number } else { number / 1000.0 };
+            if seconds >= AUTO_EPOCH_RANGE.0 && seconds < AUTO_EPOCH_RANGE.1 {
+                from_epoch(number)
+            } else {
+                None
+            }
+        },
+        _ => None,
+    }
+}
+
+fn looks_like_iso_date(s: &str) -> bool {
+    let bytes = s.trim().as_bytes();
+    bytes.len() >= 10
+        && bytes[..4].iter().all(u8::is_ascii_digit)
+        && bytes[4] == b'-'
+        && bytes[5..7].iter().all(u8::is_ascii_digit)
+        && bytes[7] == b'-'
+        && bytes[8..10].iter().all(u8::is_ascii_digit)
+}
+
+/// createdAt, created_at, updateTime, eventDate, timestamp, ts... The last
+/// word of the key decides, so updateCount or candidateId aren't taken.
+fn is_timestamp_key(key: &str) -> bool {
+    let words = key_words(key);
+    match words.last() {
+        Some(word) => ["at", "ts", "time", "timestamp", "date", "datetime"].contains(&word.as_str()),
+        None => false,
+    }
+}
+
+/// Lowercase words of a snake_case, kebab-case or camelCase key. Runs of
+/// capitals are a word of their own, as in eventTS or HTTPDate.
+fn key_words(key: &str) -> Vec<String> {
+    let chars: Vec<char> = key.chars().collect();
+    let mut words = vec![String::new()];
+    for (i, &c) in chars.iter().enumerate() {
+        if !c.is_alphanumeric() {
+            words.push(String::new());
+            continue;
+        }
+        let previous = if i > 0 { chars[i - 1] } else { ' ' };
+        let next = chars.get(i + 1).copied().unwrap_or(' ');
+        let starts_word = c.is_uppercase()
+            && (previous.is_lowercase() || previous.is_ascii_digit() || (previous.is_uppercase() && next.is_lowercase()));
+        if starts_word {
+            words.push(String::new());
+        }
+        if let Some(word) = words.last_mut() {
+            word.extend(c.to_lowercase());
+        }
+    }
+    words.retain(|word| !word.is_empty());
+    words
+}
+
+fn parse_timestamp(value: &Value) -> Option<DateTime<Utc>> {
+    match value {
+        Value::Number(n) => n.as_f64().and_then(from_epoch),
+        Value::String(s) => parse_timestamp_str(s.trim()),
+        _ => None,
+    }
+}
+
+pub(crate) fn parse_timestamp_str(s: &str) -> Option<DateTime<Utc>> {
+    if let Ok(number) = s.parse::<f64>() {
+        return from_epoch(number);
+    }
+    if let Ok(timestamp) = DateTime::parse_from_rfc3339(s) {
+        return Some(timestamp.with_timezone(&Utc));
+    }
+    if let Some(timestamp) = OFFSET_FORMATS.iter()
+        .find_map(|format| DateTime::parse_from_str(s, format).ok()) {
+        return Some(timestamp.with_timezone(&Utc));
+    }
+    let naive = s.trim_end_matches(['Z', 'z']);
+    if let Some(timestamp) = NAIVE_FORMATS.iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(naive, format).ok()) {
+        return Some(Utc.from_utc_datetime(&timestamp));
+    }
+    NaiveDate::parse_from_str(s, "%Y-%m-%d").ok()
+        .and_then(|date| date.and_hms_opt(0, 0, 0))
+        .map(|timestamp| Utc.from_utc_datetime(&timestamp))
+}
+
+fn from_epoch(number: f64) -> Option<DateTime<Utc>> {
+    if !number.is_finite() {
+        return None;
+    }
+    let millis = if number.abs() < EPOCH_MILLIS_THRESHOLD { number * 1000.0 } else { number };
+    Utc.timestamp_millis_opt(millis.round() as i64).single()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::assert_matches::assert_matches;
+    use ::serde_json::json;
+
+    fn normalise(fields: &str, format: TimestampFormat, mut json: Value) -> Result<Value> {
+        Timestamps::new(fields, format)?.normalise(&mut json).map(|_| json)
+    }
+
+    #[test]
+    fn test_parse_timestamp() {
+        let expected = Utc.with_ymd_and_hms(2019, 9, 1, 12, 30, 0).single();
+        assert_eq!(parse_timestamp(&json!(1_567_341_000)), expected);
+        assert_eq!(parse_timestamp(&json!(1_567_341_000_000u64)), expected);
+        assert_eq!(parse_timestamp(&json!("1567341000")), expected);
+        assert_eq!(parse_timestamp(&json!("2019-09-01T12:30:00Z")), expected);
+        assert_eq!(parse_timestamp(&json!("2019-09-01T14:30:00+02:00")), expected);
+        assert_eq!(parse_timestamp(&json!("2019-09-01 12:30:00")), expected);
+        assert_eq!(parse_timestamp(&json!("2019-09-01T12:30")), expected);
+        assert_eq!(parse_timestamp(&json!("20190901T123000Z")), expected);
+        assert_eq!(parse_timestamp(&json!("2019-09-01")), Utc.with_ymd_and_hms(2019, 9, 1, 0, 0, 0).single());
+        assert_eq!(parse_timestamp(&json!("yesterday")), None);
+        assert_eq!(parse_timestamp(&json!(true)), None);
+    }
+
+    #[test]
+    fn test_normalise_paths_rfc3339() {
+        let json = json!({"a": 1_567_341_000_123u64, "b": {"N": "1567341000"}, "c": "2019-09-01"});
+        let result = normalise(".a, .b.N, .missing", TimestampFormat::Rfc3339, json);
+        assert_matches!(result, Ok(ref actual) if actual == &json!({
+            "a": "2019-09-01T12:30:00.123Z", "b": {"N": "2019-09-01T12:30:00Z"}, "c": "2019-09-01"
+        }));
+    }
+
+    #[test]
+    fn test_normalise_paths_epoch_millis() {
+        let json = json!({"a": "2019-09-01T12:30:00.123Z"});
+        let result = normalise(".a", TimestampFormat::EpochMillis, json);
+        assert_matches!(result, Ok(ref actual) if actual == &json!({"a": 1_567_341_000_123u64}));
+    }
+
+    #[test]
+    fn test_normalise_paths_fail() {
+        let json = json!({"a": {"b": "not a date"}});
+        let result = normalise(".a.b", TimestampFormat::Rfc3339, json);
+        assert_matches!(result, Err(Error(ErrorKind::TimestampError(ref path, _), _)) if path == ".a.b");
+        assert_matches!(result, Err(ref error) if !error.is_fatal())
+    }
+
+    #[test]
+    fn test_normalise_auto() {
+        let json = json!({
+            "createdAt": 1_567_341_000,
+            "updated_at": "1567341000000",
+            "count": 1_567_341_000,
+            "items": [{"eventTime": "2019-09-01 12:30:00"}],
+            "day": "2019-09-01",
+            "name": "2019 report",
+            "format": 1_567_341_000
+        });
+        let result = normalise("auto", TimestampFormat::Rfc3339, json);
+        assert_matches!(result, Ok(ref actual) if actual == &json!({
+            "createdAt": "2019-09-01T12:30:00Z",
+            "updated_at": "2019-09-01T12:30:00Z",
+            "count": 1_567_341_000,
+            "items": [{"eventTime": "2019-09-01T12:30:00Z"}],
+            "day": "2019-09-01T00:00:00Z",
+            "name": "2019 report",
+            "format": 1_567_341_000
+        }));
+    }
+
+    #[test]
+    fn test_normalise_auto_keys() {
+        let json = json!({
+            "updateCount": 1_567_341_000,
+            "candidateId": 1_567_341_000,
+            "validated": 1_567_341_000,
+            "timeZone": 1_567_341_000,
+            "eventTS": 1_567_341_000,
+            "HTTPDate": 1_567_341_000,
+            "expiry_time": 1_567_341_000
+        });
+        let result = normalise("auto", TimestampFormat::Rfc3339, json);
+        assert_matches!(result, Ok(ref actual) if actual == &json!({
+            "updateCount": 1_567_341_000,
+            "candidateId": 1_567_341_000,
+            "validated": 1_567_341_000,
+            "timeZone": 1_567_341_000,
+            "eventTS": "2019-09-01T12:30:00Z",
+            "HTTPDate": "2019-09-01T12:30:00Z",
+            "expiry_time": "2019-09-01T12:30:00Z"
+        }));
+    }
+
+    #[test]
+    fn test_normalise_auto_dynamodb() {
+        let json = json!({"createdAt": {"N": "1567296000"}, "count": {"N": "1567296000"}, "data": {"M": {"ts": {"S": "1567296000"}}}});
+        let result = normalise("auto", TimestampFormat::Rfc3339, json);
+        assert_matches!(result, Ok(ref actual) if actual == &json!({
+            "createdAt": {"N": "2019-09-01T00:00:00Z"},
+            "count": {"N": "1567296000"},
+            "data": {"M": {"ts": {"S": "2019-09-01T00:00:00Z"}}}
+        }));
+    }
+
+    #[test]
+    fn test_timestamp_format_from_str() {
+        assert_eq!("rfc3339".parse::<TimestampFormat>(), Ok(TimestampFormat::Rfc3339));
+        assert_eq!("epoch-millis".parse::<TimestampFormat>(), Ok(TimestampFormat::EpochMillis));
+        assert!("iso".parse::<TimestampFormat>().is_err());
+    }
+}